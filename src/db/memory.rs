@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+use super::storage::Storage;
+use crate::generics::{errors::ApiError, structs::{Account, Conversation, EncryptedMessage, Session, TwoFactor, UserKey}};
+
+/// [`Storage`] backend that keeps everything in process memory. Nothing survives a restart, so this is meant for local development and CI,
+/// where we don't want to stand up a real database.
+#[derive(Default)]
+pub struct MemoryStore
+{
+    accounts: RwLock<HashMap<String, Account>>,
+    conversations: RwLock<HashMap<String, Conversation>>,
+    messages: RwLock<HashMap<String, Vec<EncryptedMessage>>>,
+    sessions: RwLock<HashMap<String, Session>>,
+    /// Lowercased usernames of deleted accounts.
+    tombstones: RwLock<HashSet<String>>
+}
+
+#[async_trait]
+impl Storage for MemoryStore
+{
+    async fn ping(&self) -> Result<(), ApiError> { Ok(()) }
+
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, ApiError>
+    {
+        Ok(self.accounts.read().await.get(username).cloned())
+    }
+
+    async fn get_account_by_id(&self, id: &str) -> Result<Option<Account>, ApiError>
+    {
+        Ok(self.accounts.read().await.values().find(|a| a.id == id).cloned())
+    }
+
+    async fn create_account(&self, new: &Account) -> Result<(), ApiError>
+    {
+        let mut accounts = self.accounts.write().await;
+        let tombstoned = self.tombstones.read().await.contains(&new.username.to_ascii_lowercase());
+        if tombstoned || accounts.values().any(|a| a.username.eq_ignore_ascii_case(&new.username) || a.id == new.id)
+        { return Err(ApiError::Conflict(String::from("An account with that username already exists."))) }
+        accounts.insert(new.username.clone(), new.clone());
+        Ok(())
+    }
+
+    async fn update_account(&self, new: &Account) -> Result<(), ApiError>
+    {
+        let mut accounts = self.accounts.write().await;
+        let Some(account) = accounts.values_mut().find(|a| a.id == new.id)
+        else { return Err(ApiError::NotFound(String::from("Account not found."))) };
+        *account = Account { username: account.username.clone(), ..new.clone() };
+        Ok(())
+    }
+
+    async fn use_two_factor_code(&self, id: &str, old: &TwoFactor, new: &TwoFactor) -> Result<bool, ApiError>
+    {
+        let mut accounts = self.accounts.write().await;
+        let Some(two_factor) = accounts.values_mut().find(|a| a.id == id).and_then(|a| a.two_factor.as_mut())
+        else { return Ok(false) };
+        if two_factor.last_step != old.last_step || two_factor.recovery_codes != old.recovery_codes { return Ok(false) }
+        (two_factor.last_step, two_factor.recovery_codes) = (new.last_step, new.recovery_codes.clone());
+        Ok(true)
+    }
+
+    async fn delete_account(&self, id: &str) -> Result<(), ApiError>
+    {
+        let mut accounts = self.accounts.write().await;
+        if let Some(username) = accounts.values().find(|a| a.id == id).map(|a| a.username.clone())
+        {
+            accounts.remove(&username);
+            self.tombstones.write().await.insert(username.to_ascii_lowercase());
+        }
+        self.sessions.write().await.retain(|_, s| s.user_id != id);
+        Ok(())
+    }
+
+    async fn rename_account(&self, id: &str, new: &str) -> Result<bool, ApiError>
+    {
+        // both locks are held until the rename is done, so nothing sees it half done
+        let mut accounts = self.accounts.write().await;
+        let mut tombstones = self.tombstones.write().await;
+
+        let Some(old) = accounts.values().find(|a| a.id == id).map(|a| a.username.clone()) else { return Ok(false) };
+        let taken = accounts.keys().any(|username| *username != old && username.eq_ignore_ascii_case(new));
+        if taken || (!old.eq_ignore_ascii_case(new) && tombstones.contains(&new.to_ascii_lowercase()))
+        { return Err(ApiError::Conflict(String::from("An account with that username already exists."))) }
+
+        let mut account = accounts.remove(&old).unwrap();
+        account.username = new.to_string();
+        accounts.insert(new.to_string(), account);
+        if !old.eq_ignore_ascii_case(new) { tombstones.insert(old.to_ascii_lowercase()); }
+        Ok(true)
+    }
+
+    async fn get_accounts_due_for_deletion(&self, now: i64) -> Result<Vec<String>, ApiError>
+    {
+        Ok(self.accounts.read().await.values().filter(|a| a.delete_at.is_some_and(|at| at <= now)).map(|a| a.id.clone()).collect())
+    }
+
+    async fn create_session(&self, session: &Session) -> Result<(), ApiError>
+    {
+        self.sessions.write().await.insert(session.token_hash.clone(), session.clone());
+        Ok(())
+    }
+
+    async fn get_session(&self, token_hash: &str) -> Result<Option<Session>, ApiError>
+    {
+        Ok(self.sessions.read().await.get(token_hash).cloned())
+    }
+
+    async fn get_session_by_refresh(&self, refresh_hash: &str) -> Result<Option<Session>, ApiError>
+    {
+        Ok(self.sessions.read().await.values().find(|s| s.refresh_hash == refresh_hash).cloned())
+    }
+
+    async fn rotate_session(&self, refresh_hash: &str, rotated: &Session) -> Result<bool, ApiError>
+    {
+        let mut sessions = self.sessions.write().await;
+        let Some(token_hash) = sessions.values().find(|s| s.id == rotated.id && s.refresh_hash == refresh_hash).map(|s| s.token_hash.clone())
+        else { return Ok(false) };
+
+        // sessions are keyed by access token hash, so the rotated one moves to its new key
+        let Some(session) = sessions.remove(&token_hash) else { return Ok(false) };
+        sessions.insert(rotated.token_hash.clone(), Session {
+            token_hash: rotated.token_hash.clone(),
+            refresh_hash: rotated.refresh_hash.clone(),
+            expires: rotated.expires,
+            last_seen: rotated.last_seen,
+            ..session
+        });
+        Ok(true)
+    }
+
+    async fn get_sessions(&self, user: &str) -> Result<Vec<Session>, ApiError>
+    {
+        let mut sessions: Vec<Session> = self.sessions.read().await.values().filter(|s| s.user_id == user).cloned().collect();
+        sessions.sort_by_key(|s| s.created);
+        Ok(sessions)
+    }
+
+    async fn touch_session(&self, token_hash: &str, last_seen: i64) -> Result<(), ApiError>
+    {
+        if let Some(session) = self.sessions.write().await.get_mut(token_hash) { session.last_seen = last_seen }
+        Ok(())
+    }
+
+    async fn delete_session(&self, user: &str, id: &str) -> Result<Option<Session>, ApiError>
+    {
+        let mut sessions = self.sessions.write().await;
+        let Some(token_hash) = sessions.values().find(|s| s.user_id == user && s.id == id).map(|s| s.token_hash.clone())
+        else { return Ok(None) };
+        Ok(sessions.remove(&token_hash))
+    }
+
+    async fn delete_sessions(&self, user: &str, keep: Option<&str>) -> Result<Vec<Session>, ApiError>
+    {
+        let mut sessions = self.sessions.write().await;
+        let hashes: Vec<String> = sessions
+            .values()
+            .filter(|s| s.user_id == user && keep != Some(s.id.as_str()))
+            .map(|s| s.token_hash.clone())
+            .collect();
+        Ok(hashes.iter().filter_map(|h| sessions.remove(h)).collect())
+    }
+
+    async fn delete_lapsed_sessions(&self, idle_before: i64, created_before: i64) -> Result<Vec<Session>, ApiError>
+    {
+        let mut sessions = self.sessions.write().await;
+        let hashes: Vec<String> = sessions
+            .values()
+            .filter(|s| s.last_seen < idle_before || s.created < created_before)
+            .map(|s| s.token_hash.clone())
+            .collect();
+        Ok(hashes.iter().filter_map(|h| sessions.remove(h)).collect())
+    }
+
+    async fn get_conversations(&self, user: &str) -> Result<Vec<Conversation>, ApiError>
+    {
+        Ok(self
+            .conversations
+            .read()
+            .await
+            .values()
+            .filter(|c| c.users.iter().any(|u| u == user))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_conversation(&self, id: &str) -> Result<Option<Conversation>, ApiError>
+    {
+        Ok(self.conversations.read().await.get(id).cloned())
+    }
+
+    async fn create_conversation(&self, new: &Conversation) -> Result<(), ApiError>
+    {
+        let mut conversations = self.conversations.write().await;
+        if conversations.contains_key(&new.id)
+        { return Err(ApiError::Conflict(String::from("A conversation with that ID already exists."))) }
+        conversations.insert(new.id.clone(), new.clone());
+        Ok(())
+    }
+
+    async fn change_members(&self, id: &str, from_epoch: u32, users: &[String], keys: &[UserKey]) -> Result<bool, ApiError>
+    {
+        let mut conversations = self.conversations.write().await;
+        let Some(convo) = conversations.get_mut(id).filter(|c| c.epoch == from_epoch)
+        else { return Ok(false) };
+        convo.users = users.to_vec();
+        convo.keys.extend_from_slice(keys);
+        convo.epoch = from_epoch + 1;
+        Ok(true)
+    }
+
+    async fn add_conversation_keys(&self, id: &str, keys: &[UserKey]) -> Result<bool, ApiError>
+    {
+        let mut conversations = self.conversations.write().await;
+        let Some(convo) = conversations.get_mut(id) else { return Ok(false) };
+        convo.keys.extend_from_slice(keys);
+        Ok(true)
+    }
+
+    async fn remove_conversation_keys(&self, id: &str, owner: &str) -> Result<bool, ApiError>
+    {
+        let mut conversations = self.conversations.write().await;
+        let Some(convo) = conversations.get_mut(id) else { return Ok(false) };
+        convo.keys.retain(|k| k.owner != owner);
+        Ok(true)
+    }
+
+    async fn delete_conversation(&self, id: &str) -> Result<(), ApiError>
+    {
+        self.conversations.write().await.remove(id);
+        self.messages.write().await.remove(id);
+        Ok(())
+    }
+
+    async fn append_message(&self, message: &EncryptedMessage) -> Result<Option<EncryptedMessage>, ApiError>
+    {
+        // hold the conversation while appending, so its membership and epoch can't change underneath us
+        let conversations = self.conversations.read().await;
+        let Some(convo) = conversations.get(&message.dest_convo_id)
+        else { return Ok(None) };
+        if !convo.users.contains(&message.sender) || convo.epoch != message.epoch { return Ok(None) }
+
+        let mut messages = self.messages.write().await;
+        let messages = messages.entry(message.dest_convo_id.clone()).or_default();
+        let stored = EncryptedMessage { seq: messages.len() as i64 + 1, ..message.clone() };
+        messages.push(stored.clone());
+        Ok(Some(stored))
+    }
+
+    async fn get_messages(&self, conversation_id: &str, before: Option<i64>, after: Option<i64>, limit: usize) -> Result<Vec<EncryptedMessage>, ApiError>
+    {
+        let messages = self.messages.read().await;
+        let in_range = messages
+            .get(conversation_id)
+            .into_iter()
+            .flatten()
+            .filter(|m| before.is_none_or(|b| m.seq < b) && after.is_none_or(|a| m.seq > a));
+
+        // messages are kept in sequence order, so the page is either the front or the back of the range
+        let mut page: Vec<EncryptedMessage> = match after
+        {
+            Some(_) => in_range.take(limit).cloned().collect(),
+            None => in_range.rev().take(limit).cloned().collect()
+        };
+        if after.is_none() { page.reverse() }
+        Ok(page)
+    }
+}
@@ -1 +1,26 @@
+pub mod memory;
 pub mod mongo;
+pub mod sqlite;
+pub mod storage;
+#[cfg(test)]
+mod tests;
+use std::sync::Arc;
+use storage::Db;
+
+/// Picks the storage backend from the `DB_BACKEND` environment variable (`mongo` by default, `sqlite`, or `memory`) and connects to it.
+pub async fn from_env() -> Result<Db, String>
+{
+    match dotenv::var("DB_BACKEND").unwrap_or_else(|_| String::from("mongo")).to_lowercase().as_str()
+    {
+        "mongo" | "mongodb" =>
+        {
+            let store = mongo::MongoStore::connect(mongo::MongoConfig::from_env()?)
+                .await
+                .map_err(|e| format!("Failed to connect to MongoDB! {e}"))?;
+            Ok(Arc::new(store))
+        }
+        "sqlite" => Ok(Arc::new(sqlite::SqliteStore::open(&dotenv::var("SQLITE_PATH").unwrap_or_else(|_| String::from("crim.db")))?)),
+        "memory" => Ok(Arc::new(memory::MemoryStore::default())),
+        other => Err(format!("Unknown DB_BACKEND `{other}`. Expected `mongo`, `sqlite` or `memory`."))
+    }
+}
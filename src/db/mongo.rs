@@ -1,31 +1,774 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use tracing::{error, info, warn};
 use mongodb::{
-    bson::doc, bson::Document, options::{ServerApi, ServerApiVersion}, Collection, Database
+    bson::{self, doc}, bson::Document, options::{ServerApi, ServerApiVersion}, Collection, Database
 };
-use mongodb::{options::ClientOptions, Client};
-
-async fn init_mongo() -> mongodb::error::Result<Client>
-{
-    let mut client_options = ClientOptions::parse(dotenv::var("MONGO_URI").unwrap().as_str()).await?;
-    // Set the server_api field of the client_options object to set the version of the Stable API on the client
-    let server_api = ServerApi::builder()
-        .version(ServerApiVersion::V1)
-        .build();
-    client_options.server_api = Some(server_api);
-    // Get a handle to the cluster
-    let client = Client::with_options(client_options)?;
-    // Ping the server to see if you can connect to the cluster
+use mongodb::{options::{ClientOptions, Collation, CollationStrength, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReturnDocument, UpdateOptions}, Client, ClientSession, IndexModel};
+use mongodb::error::{ErrorKind, WriteFailure};
+use super::storage::Storage;
+use crate::generics::{errors::ApiError, structs::{Account, Conversation, EncryptedMessage, Session, TwoFactor, UserKey}, utils};
+
+/// Server error code MongoDB reports when an insert violates a unique index.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Byte fields that older versions of the API stored as arrays of i32s instead of BSON Binary, by collection.
+/// Each entry is the top-level field to rewrite, and the path to query for a leftover array.
+const LEGACY_BYTE_FIELDS: [(&str, &[(&str, &str)]); 3] = [
+    ("accounts", &[("public_key", "public_key"), ("priv_key_enc", "priv_key_enc"), ("nonce", "nonce")]),
+    ("conversations", &[("keys", "keys.key")]),
+    ("messages", &[("data", "data"), ("nonce", "nonce")])
+];
+
+/// Logs a document that failed to decode and turns it into a storage error, so a bad document only fails the request that read it.
+fn malformed(what: &'static str) -> impl Fn(bson::de::Error) -> ApiError
+{
+    move |e| { error!("Malformed {what} document: {e}"); ApiError::Storage(format!("A stored {what} is malformed.")) }
+}
+
+/// Logs a value that failed to encode and turns it into a storage error.
+fn unencodable(what: &'static str) -> impl Fn(bson::ser::Error) -> ApiError
+{
+    move |e| { error!("Failed to encode {what}: {e}"); ApiError::Storage(format!("Failed to encode {what} for storage.")) }
+}
+
+/// Connection settings for the MongoDB backend, read from the environment once at startup.
+///
+/// ## Fields
+/// * [`uri`][`std::string::String`] - The connection string (`MONGO_URI`).
+/// * [`db_name`][`std::string::String`] - The database to use (`DB_NAME`).
+/// * [`max_pool_size`][`u32`] - Upper bound on pooled connections (`MONGO_MAX_POOL_SIZE`, default 20).
+/// * [`min_pool_size`][`u32`] - Connections kept open even when idle (`MONGO_MIN_POOL_SIZE`, default 2).
+/// * [`connect_timeout`][`std::time::Duration`] - Timeout for opening a single connection (`MONGO_CONNECT_TIMEOUT_MS`, default 5000).
+/// * [`server_selection_timeout`][`std::time::Duration`] - How long an operation waits for a usable server (`MONGO_SERVER_SELECTION_TIMEOUT_MS`, default 5000).
+/// * [`health_check_interval`][`std::time::Duration`] - How often the background health check pings the cluster (`MONGO_HEALTH_CHECK_INTERVAL_SECS`, default 30).
+#[derive(Debug, Clone)]
+pub struct MongoConfig
+{
+    pub uri: String,
+    pub db_name: String,
+    pub max_pool_size: u32,
+    pub min_pool_size: u32,
+    pub connect_timeout: Duration,
+    pub server_selection_timeout: Duration,
+    pub health_check_interval: Duration
+}
+
+impl MongoConfig
+{
+    pub fn from_env() -> Result<MongoConfig, String>
+    {
+        let Ok(uri) = dotenv::var("MONGO_URI")
+        else { return Err(String::from("MONGO_URI must be set to use the mongo backend.")) };
+        let Ok(db_name) = dotenv::var("DB_NAME")
+        else { return Err(String::from("DB_NAME must be set to use the mongo backend.")) };
+
+        Ok(MongoConfig {
+            uri,
+            db_name,
+            max_pool_size: utils::env_or("MONGO_MAX_POOL_SIZE", 20),
+            min_pool_size: utils::env_or("MONGO_MIN_POOL_SIZE", 2),
+            connect_timeout: Duration::from_millis(utils::env_or("MONGO_CONNECT_TIMEOUT_MS", 5000)),
+            server_selection_timeout: Duration::from_millis(utils::env_or("MONGO_SERVER_SELECTION_TIMEOUT_MS", 5000)),
+            health_check_interval: Duration::from_secs(utils::env_or("MONGO_HEALTH_CHECK_INTERVAL_SECS", 30))
+        })
+    }
+}
+
+/// The collation usernames are compared with: ignoring case, as usernames are unique ignoring case.
+fn ignoring_case() -> Collation
+{
+    Collation::builder().locale("en").strength(CollationStrength::Secondary).build()
+}
+
+async fn ping(client: &Client) -> mongodb::error::Result<()>
+{
     client
         .database("admin")
         .run_command(doc! {"ping": 1}, None)
-        .await?;
-    Ok(client)
+        .await
+        .map(|_| ())
 }
 
-pub async fn ping() -> Result<(), mongodb::error::Error> { init_mongo().await.map(|_| ()).map_err(|e| e) }
-pub async fn get_database(name: &str) -> Database { init_mongo().await.unwrap().database(name) }
-pub async fn get_collection(name: &str) -> Collection<Document>
+/// Whether the deployment has multi-document transactions, i.e. is a replica set or a sharded cluster (as every Atlas cluster is) rather than a standalone server.
+async fn supports_transactions(client: &Client) -> mongodb::error::Result<bool>
 {
-    get_database(dotenv::var("DB_NAME").unwrap().as_str())
-        .await
-        .collection::<Document>(name)
+    let hello = client.database("admin").run_command(doc! {"hello": 1}, None).await?;
+    Ok(hello.contains_key("setName") || hello.get_str("msg").is_ok_and(|m| m == "isdbgrid"))
+}
+
+/// [`Storage`] backend for MongoDB. Accounts live in the `accounts` collection, their logins in `sessions`, conversations in `conversations`, and their messages in `messages`.
+/// Each conversation document keeps a `last_seq` counter, which is atomically incremented to hand out message sequence numbers.
+/// If inserting a message fails after its number was handed out, that number is simply skipped, so sequence numbers always increase but may have gaps.
+///
+/// Holds one long-lived [`Client`], whose internal connection pool is shared by every request.
+///
+/// Renames run in a transaction, so they need a replica set or sharded cluster. On a standalone server they are refused, which is logged on startup.
+pub struct MongoStore
+{
+    client: Client,
+    db: Database,
+    transactions: bool
+}
+
+impl MongoStore
+{
+    /// Builds the pooled client, makes sure the cluster is reachable, and starts the periodic health check.
+    pub async fn connect(config: MongoConfig) -> mongodb::error::Result<MongoStore>
+    {
+        let mut client_options = ClientOptions::parse(config.uri.as_str()).await?;
+        // Set the server_api field of the client_options object to set the version of the Stable API on the client
+        let server_api = ServerApi::builder()
+            .version(ServerApiVersion::V1)
+            .build();
+        client_options.server_api = Some(server_api);
+        client_options.max_pool_size = Some(config.max_pool_size);
+        client_options.min_pool_size = Some(config.min_pool_size);
+        client_options.connect_timeout = Some(config.connect_timeout);
+        client_options.server_selection_timeout = Some(config.server_selection_timeout);
+        // Get a handle to the cluster
+        let client = Client::with_options(client_options)?;
+        // Ping the server to see if you can connect to the cluster
+        ping(&client).await?;
+
+        let transactions = supports_transactions(&client).await?;
+        if !transactions { warn!("MongoDB is running as a standalone server, which has no transactions, so usernames can't be changed. Run it as a replica set to allow renames.") }
+
+        tokio::spawn(health_check(client.clone(), config.health_check_interval));
+
+        let store = MongoStore { db: client.database(&config.db_name), client, transactions };
+        store.end_plaintext_sessions().await?;
+        store.ensure_indexes().await?;
+        store.migrate_embedded_messages().await?;
+        store.migrate_byte_arrays().await?;
+        store.drop_account_sids().await?;
+        store.set_missing_epochs().await?;
+        store.migrate_to_account_ids().await?;
+        store.migrate_session_owners().await?;
+        Ok(store)
+    }
+
+    fn collection(&self, name: &str) -> Collection<Document> { self.db.collection::<Document>(name) }
+
+    async fn find_sessions(&self, filter: Document) -> Result<Vec<Session>, ApiError>
+    {
+        let err = |_| ApiError::Storage(String::from("An error occurred listing sessions."));
+        let options = FindOptions::builder().sort(doc! {"created": 1}).build();
+        let mut cursor = self.collection("sessions").find(filter, options).await.map_err(err)?;
+        let mut sessions: Vec<Session> = Vec::new();
+        while cursor.advance().await.map_err(err)?
+        {
+            sessions.push(Session::from_document(cursor.deserialize_current().map_err(err)?).map_err(malformed("session"))?);
+        }
+        Ok(sessions)
+    }
+
+    async fn ensure_indexes(&self) -> mongodb::error::Result<()>
+    {
+        let unique = || Some(IndexOptions::builder().unique(true).build());
+        // these let inserts report duplicates as conflicts instead of silently creating a second document
+        // usernames are also unique ignoring case. Fails if two existing accounts differ only in case; one has to be renamed first
+        self.collection("accounts")
+            .create_indexes(
+                [
+                    IndexModel::builder().keys(doc! {"username": 1}).options(unique()).build(),
+                    IndexModel::builder()
+                        .keys(doc! {"username": 1})
+                        .options(IndexOptions::builder().unique(true).collation(ignoring_case()).name(String::from("username_nocase")).build())
+                        .build(),
+                    // accounts from before IDs have none until they are migrated, and mustn't clash over it meanwhile
+                    IndexModel::builder()
+                        .keys(doc! {"id": 1})
+                        .options(IndexOptions::builder().unique(true).partial_filter_expression(doc! {"id": {"$type": "string"}}).build())
+                        .build()
+                ],
+                None
+            )
+            .await?;
+        self.collection("tombstones")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"username": 1})
+                    .options(IndexOptions::builder().unique(true).collation(ignoring_case()).name(String::from("username_nocase")).build())
+                    .build(),
+                None
+            )
+            .await?;
+        self.collection("conversations").create_index(IndexModel::builder().keys(doc! {"id": 1}).options(unique()).build(), None).await?;
+        self.collection("sessions")
+            .create_indexes(
+                [
+                    IndexModel::builder().keys(doc! {"token_hash": 1}).options(unique()).build(),
+                    IndexModel::builder().keys(doc! {"id": 1}).options(unique()).build(),
+                    IndexModel::builder().keys(doc! {"refresh_hash": 1}).options(unique()).build(),
+                    IndexModel::builder().keys(doc! {"user_id": 1}).build()
+                ],
+                None
+            )
+            .await?;
+        self.collection("messages")
+            .create_indexes(
+                [
+                    IndexModel::builder().keys(doc! {"dest_convo_id": 1, "seq": 1}).options(unique()).build(),
+                    IndexModel::builder().keys(doc! {"id": 1}).options(unique()).build()
+                ],
+                None
+            )
+            .await
+            .map(|_| ())
+    }
+
+    /// Does the work of [`Storage::rename_account`] inside the transaction of `session`.
+    async fn rename_in(&self, session: &mut ClientSession, id: &str, new: &str) -> mongodb::error::Result<bool>
+    {
+        // the document as it was before the update, for the username to leave a tombstone of
+        let Some(renamed) = self.collection("accounts").find_one_and_update_with_session(doc! {"id": id}, doc! {"$set": {"username": new}}, None, session).await?
+        else { return Ok(false) };
+        let old = renamed.get_str("username").unwrap_or_default();
+        if !old.eq_ignore_ascii_case(new)
+        {
+            // a guard tombstone of the new username, taken out again before committing. Its unique index refuses it if the username belongs to a deleted account,
+            // and a deletion tombstoning it concurrently conflicts with it, where a plain lookup would miss a deletion committed after it
+            let tombstones = self.collection("tombstones");
+            tombstones.insert_one_with_session(doc! {"username": new, "deleted": utils::now()}, None, session).await?;
+            tombstones.delete_one_with_session(doc! {"username": new}, None, session).await?;
+
+            let tombstone = doc! {"username": old, "deleted": utils::now()};
+            if let Err(e) = self.collection("tombstones").insert_one_with_session(tombstone, None, session).await
+            {
+                if !matches!(*e.kind, ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY) { return Err(e) }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Moves messages still embedded in conversation documents (from before messages had their own collection) into the `messages` collection.
+    /// Safe to re-run if interrupted, since a conversation's embedded messages are only removed once they've all been copied.
+    async fn migrate_embedded_messages(&self) -> mongodb::error::Result<()>
+    {
+        let mut cursor = self.collection("conversations").find(doc! {"messages.0": {"$exists": true}}, None).await?;
+        while cursor.advance().await?
+        {
+            let convo = Conversation::from_document(cursor.deserialize_current()?)?;
+            let count = convo.messages.len() as i64;
+            info!("Moving {count} embedded messages out of conversation {}", convo.id);
+
+            let messages = convo.messages.into_iter().enumerate().map(|(i, m)| EncryptedMessage {
+                id: utils::rand_hex(12),
+                dest_convo_id: convo.id.clone(),
+                seq: i as i64 + 1,
+                ..m
+            });
+            self.collection("messages").delete_many(doc! {"dest_convo_id": &convo.id, "seq": {"$lte": count}}, None).await?;
+            self.collection("messages").insert_many(messages.map(|m| m.to_document()).collect::<Result<Vec<Document>, _>>()?, None).await?;
+            self.collection("conversations")
+                .update_one(doc! {"id": &convo.id}, doc! {"$set": {"last_seq": count}, "$unset": {"messages": ""}}, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Gives accounts from before account IDs one, and rewrites every reference to them from their username to it. References to accounts that no longer
+    /// exist keep their username. An account's ID is derived from its document's `_id`, and only set once its references are rewritten,
+    /// so this is safe to re-run if interrupted.
+    async fn migrate_to_account_ids(&self) -> mongodb::error::Result<()>
+    {
+        let matching = |filter: Document| Some(UpdateOptions::builder().array_filters(vec![filter]).build());
+        let (accounts, conversations) = (self.collection("accounts"), self.collection("conversations"));
+        let mut cursor = accounts.find(doc! {"id": {"$exists": false}}, None).await?;
+        let mut migrated = 0;
+        while cursor.advance().await?
+        {
+            let raw = cursor.deserialize_current()?;
+            let (Ok(oid), Ok(username)) = (raw.get_object_id("_id"), raw.get_str("username"))
+            else { warn!("Skipping an account document without an ObjectId or username while migrating to account IDs"); continue };
+            // ObjectIds are 24 hex characters; padded, they have the shape of an account ID
+            let id = format!("{:0>width$}", oid.to_hex(), width = Account::ID_LENGTH);
+
+            accounts.update_many(doc! {"friends": username}, doc! {"$set": {"friends.$[f]": &id}}, matching(doc! {"f": username})).await?;
+            for field in ["sender", "receiver"]
+            {
+                accounts
+                    .update_many(
+                        doc! {format!("friend_requests.{field}"): username},
+                        doc! {"$set": {format!("friend_requests.$[r].{field}"): &id}},
+                        matching(doc! {format!("r.{field}"): username})
+                    )
+                    .await?;
+            }
+            conversations.update_many(doc! {"users": username}, doc! {"$set": {"users.$[u]": &id}}, matching(doc! {"u": username})).await?;
+            conversations.update_many(doc! {"keys.owner": username}, doc! {"$set": {"keys.$[k].owner": &id}}, matching(doc! {"k.owner": username})).await?;
+            self.collection("messages").update_many(doc! {"sender": username}, doc! {"$set": {"sender": &id}}, None).await?;
+            accounts.update_one(doc! {"_id": oid}, doc! {"$set": {"id": &id}}, None).await?;
+            migrated += 1;
+        }
+        if migrated > 0 { info!("Gave {migrated} accounts IDs, and rewrote the references to them") }
+        Ok(())
+    }
+
+    /// Moves sessions from before sessions were tied to their account's ID over to it. Sessions of accounts that no longer exist are ended.
+    /// Safe to re-run if interrupted, as each session is moved on its own.
+    async fn migrate_session_owners(&self) -> mongodb::error::Result<()>
+    {
+        let sessions = self.collection("sessions");
+        let mut cursor = sessions.find(doc! {"user_id": {"$exists": false}}, None).await?;
+        let (mut moved, mut ended) = (0, 0);
+        while cursor.advance().await?
+        {
+            let raw = cursor.deserialize_current()?;
+            let (Ok(oid), username) = (raw.get_object_id("_id"), raw.get_str("username").unwrap_or_default())
+            else { warn!("Skipping a session document without an ObjectId while tying sessions to account IDs"); continue };
+            match self.collection("accounts").find_one(doc! {"username": username}, None).await?.as_ref().and_then(|a| a.get_str("id").ok())
+            {
+                Some(id) => { sessions.update_one(doc! {"_id": oid}, doc! {"$set": {"user_id": id}, "$unset": {"username": ""}}, None).await?; moved += 1 }
+                None => { sessions.delete_one(doc! {"_id": oid}, None).await?; ended += 1 }
+            }
+        }
+        if moved + ended > 0 { info!("Tied {moved} sessions to their account's ID, and ended {ended} of accounts that no longer exist") }
+        Ok(())
+    }
+
+    /// Puts conversations from before key epochs at the first one, so their epoch can be matched on like everyone else's.
+    async fn set_missing_epochs(&self) -> mongodb::error::Result<()>
+    {
+        self.collection("conversations")
+            .update_many(doc! {"epoch": {"$exists": false}}, doc! {"$set": {"epoch": 0_i64}}, None)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes the single `session_id` accounts had before sessions got their own collection. This logs out everyone who was logged in under the old scheme.
+    async fn drop_account_sids(&self) -> mongodb::error::Result<()>
+    {
+        let result = self.collection("accounts").update_many(doc! {"session_id": {"$exists": true}}, doc! {"$unset": {"session_id": ""}}, None).await?;
+        if result.modified_count > 0 { info!("Dropped the legacy session IDs of {} accounts", result.modified_count) }
+        Ok(())
+    }
+
+    /// Ends sessions from before only hashes of their tokens were stored, and drops the indexes on their plaintext tokens.
+    /// Their tokens can't be hashed after the fact without keeping them around, so everyone logged in under the old scheme is logged out.
+    async fn end_plaintext_sessions(&self) -> mongodb::error::Result<()>
+    {
+        let sessions = self.collection("sessions");
+        let result = sessions.delete_many(doc! {"token": {"$exists": true}}, None).await?;
+        if result.deleted_count > 0 { info!("Ended {} sessions with plaintext tokens", result.deleted_count) }
+
+        for index in sessions.list_index_names().await.unwrap_or_default()
+        {
+            if index == "token_1" || index == "refresh_token_1" { sessions.drop_index(index, None).await?; }
+        }
+        Ok(())
+    }
+
+    /// Rewrites byte fields still stored as arrays of i32s (see [`LEGACY_BYTE_FIELDS`]) as BSON Binary.
+    /// Decoding accepts both forms, so this only saves space and keeps documents uniform; it is safe to re-run if interrupted.
+    async fn migrate_byte_arrays(&self) -> mongodb::error::Result<()>
+    {
+        for (collection, fields) in LEGACY_BYTE_FIELDS
+        {
+            let legacy = fields.iter().map(|(_, path)| doc! {format!("{path}.0"): {"$exists": true}}).collect::<Vec<Document>>();
+            let mut cursor = self.collection(collection).find(doc! {"$or": legacy}, None).await?;
+            let mut migrated = 0;
+            while cursor.advance().await?
+            {
+                let raw = cursor.deserialize_current()?;
+                let id = raw.get("_id").cloned().unwrap_or_default();
+                // a round trip through the model re-encodes every byte field as Binary
+                let encoded = match collection
+                {
+                    "accounts" => Account::from_document(raw)?.to_document()?,
+                    "conversations" => Conversation::from_document(raw)?.to_document()?,
+                    _ => EncryptedMessage::from_document(raw)?.to_document()?
+                };
+                let mut set = Document::new();
+                for (field, _) in fields.iter()
+                {
+                    if let Some(value) = encoded.get(*field) { set.insert(*field, value.clone()); }
+                }
+                self.collection(collection).update_one(doc! {"_id": id}, doc! {"$set": set}, None).await?;
+                migrated += 1;
+            }
+            if migrated > 0 { info!("Converted byte arrays to BSON Binary in {migrated} {collection} documents") }
+        }
+        Ok(())
+    }
+}
+
+/// Pings the cluster every `interval`, logging whenever it becomes unreachable or recovers.
+async fn health_check(client: Client, interval: Duration)
+{
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await; // the first tick completes immediately, and we've only just pinged in `connect`.
+    let mut healthy = true;
+    loop
+    {
+        ticker.tick().await;
+        match ping(&client).await
+        {
+            Ok(_) if !healthy => { info!("MongoDB health check recovered."); healthy = true; }
+            Ok(_) => (),
+            Err(e) => { warn!("MongoDB health check failed: {e}"); healthy = false; }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MongoStore
+{
+    async fn ping(&self) -> Result<(), ApiError>
+    {
+        ping(&self.client).await.map_err(|e| ApiError::Storage(format!("Failed to connect to MongoDB: {e}")))
+    }
+
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, ApiError>
+    {
+        let Ok(doc) = self
+            .collection("accounts")
+            .find_one(doc! { "username": username }, None)
+            .await
+        else { return Err(ApiError::Storage(String::from("An error occurred querying the database for an account by username."))) };
+
+        doc.map(Account::from_document).transpose().map_err(malformed("account"))
+    }
+
+    async fn get_account_by_id(&self, id: &str) -> Result<Option<Account>, ApiError>
+    {
+        let Ok(doc) = self.collection("accounts").find_one(doc! {"id": id}, None).await
+        else { return Err(ApiError::Storage(String::from("An error occurred querying the database for an account by ID."))) };
+
+        doc.map(Account::from_document).transpose().map_err(malformed("account"))
+    }
+
+    async fn create_account(&self, new: &Account) -> Result<(), ApiError>
+    {
+        // a tombstone is always written before its account is deleted, so the unique index covers the name until the tombstone does
+        let tombstone = self
+            .collection("tombstones")
+            .find_one(doc! {"username": &new.username}, FindOneOptions::builder().collation(ignoring_case()).build())
+            .await
+            .map_err(|_| ApiError::Storage(String::from("An error occurred creating an account in the database.")))?;
+        if tombstone.is_some() { return Err(ApiError::Conflict(String::from("An account with that username already exists."))) }
+
+        self
+            .collection("accounts")
+            .insert_one(new.to_document().map_err(unencodable("account"))?, None)
+            .await
+            .map(|_| ())
+            .map_err(|e| match *e.kind
+            {
+                ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY => ApiError::Conflict(String::from("An account with that username already exists.")),
+                _ => { error!("Failed to insert account: {e}"); ApiError::Storage(String::from("An error occurred creating an account in the database.")) }
+            })
+    }
+
+    async fn update_account(&self, new: &Account) -> Result<(), ApiError>
+    {
+        let mut fields = new.to_document().map_err(unencodable("account"))?;
+        // only renames change the username, so a copy read before one doesn't undo it
+        fields.remove("username");
+        let updated = self
+            .collection("accounts")
+            .update_one(doc! { "id": &new.id }, doc! { "$set": fields }, None)
+            .await
+            .map_err(|_| ApiError::Storage(String::from("An error occurred updating an account in the database.")))?;
+        if updated.matched_count == 0 { return Err(ApiError::NotFound(String::from("Account not found."))) }
+        Ok(())
+    }
+
+    async fn use_two_factor_code(&self, id: &str, old: &TwoFactor, new: &TwoFactor) -> Result<bool, ApiError>
+    {
+        let mut filter = doc! { "id": id, "two_factor.last_step": old.last_step, "two_factor.recovery_codes": &old.recovery_codes };
+        // settings from before recovery codes have none stored
+        if old.recovery_codes.is_empty()
+        {
+            filter.remove("two_factor.recovery_codes");
+            filter.insert("$or", vec![doc! { "two_factor.recovery_codes": [] }, doc! { "two_factor.recovery_codes": { "$exists": false } }]);
+        }
+        let used = self
+            .collection("accounts")
+            .update_one(
+                filter,
+                doc! { "$set": { "two_factor.last_step": new.last_step, "two_factor.recovery_codes": &new.recovery_codes } },
+                None
+            )
+            .await
+            .map_err(|_| ApiError::Storage(String::from("An error occurred recording a used two-factor code in the database.")))?;
+        Ok(used.matched_count == 1)
+    }
+
+    async fn delete_account(&self, id: &str) -> Result<(), ApiError>
+    {
+        let err = |_| ApiError::Storage(String::from("An error occurred deleting an account from the database."));
+        if let Some(account) = self.collection("accounts").find_one(doc! {"id": id}, None).await.map_err(err)?
+        {
+            let username = account.get_str("username").unwrap_or_default();
+            let tombstone = self.collection("tombstones").insert_one(doc! {"username": username, "deleted": utils::now()}, None).await;
+            if let Err(e) = tombstone
+            {
+                if !matches!(*e.kind, ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY) { return Err(err(e)) }
+            }
+        }
+        self
+            .collection("accounts")
+            .delete_one(doc! { "id": id }, None)
+            .await
+            .map_err(|_| ApiError::Storage(String::from("An error occurred deleting an account from the database.")))?;
+        self.delete_sessions(id, None).await.map(|_| ())
+    }
+
+    async fn rename_account(&self, id: &str, new: &str) -> Result<bool, ApiError>
+    {
+        let err = |e: mongodb::error::Error| match *e.kind
+        {
+            ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY => ApiError::Conflict(String::from("An account with that username already exists.")),
+            _ => { error!("Failed to rename account: {e}"); ApiError::Storage(String::from("An error occurred renaming an account.")) }
+        };
+        if !self.transactions
+        { return Err(ApiError::Storage(String::from("Usernames can't be changed while MongoDB runs as a standalone server, as renames need a replica set."))) }
+
+        let mut session = self.client.start_session(None).await.map_err(err)?;
+        session.start_transaction(None).await.map_err(err)?;
+        if !self.rename_in(&mut session, id, new).await.map_err(err)?
+        {
+            session.abort_transaction().await.map_err(err)?;
+            return Ok(false)
+        }
+        session.commit_transaction().await.map_err(err)?;
+        Ok(true)
+    }
+
+    async fn get_accounts_due_for_deletion(&self, now: i64) -> Result<Vec<String>, ApiError>
+    {
+        let err = |_| ApiError::Storage(String::from("An error occurred looking up accounts due for deletion."));
+        let mut cursor = self.collection("accounts").find(doc! {"delete_at": {"$lte": now}}, None).await.map_err(err)?;
+        let mut ids: Vec<String> = Vec::new();
+        while cursor.advance().await.map_err(err)?
+        {
+            ids.push(Account::from_document(cursor.deserialize_current().map_err(err)?).map_err(malformed("account"))?.id);
+        }
+        Ok(ids)
+    }
+
+    async fn create_session(&self, session: &Session) -> Result<(), ApiError>
+    {
+        self
+            .collection("sessions")
+            .insert_one(session.to_document().map_err(unencodable("session"))?, None)
+            .await
+            .map(|_| ())
+            .map_err(|_| ApiError::Storage(String::from("An error occurred creating a session.")))
+    }
+
+    async fn get_session(&self, token_hash: &str) -> Result<Option<Session>, ApiError>
+    {
+        let Ok(doc) = self.collection("sessions").find_one(doc! {"token_hash": token_hash}, None).await
+        else { return Err(ApiError::Storage(String::from("An error occurred looking up a session."))) };
+
+        doc.map(Session::from_document).transpose().map_err(malformed("session"))
+    }
+
+    async fn get_session_by_refresh(&self, refresh_hash: &str) -> Result<Option<Session>, ApiError>
+    {
+        let Ok(doc) = self.collection("sessions").find_one(doc! {"refresh_hash": refresh_hash}, None).await
+        else { return Err(ApiError::Storage(String::from("An error occurred looking up a session."))) };
+
+        doc.map(Session::from_document).transpose().map_err(malformed("session"))
+    }
+
+    async fn rotate_session(&self, refresh_hash: &str, rotated: &Session) -> Result<bool, ApiError>
+    {
+        let update = doc! {"$set": {
+            "token_hash": &rotated.token_hash,
+            "refresh_hash": &rotated.refresh_hash,
+            "expires": rotated.expires,
+            "last_seen": rotated.last_seen
+        }};
+        self
+            .collection("sessions")
+            .update_one(doc! {"id": &rotated.id, "refresh_hash": refresh_hash}, update, None)
+            .await
+            .map(|result| result.modified_count == 1)
+            .map_err(|_| ApiError::Storage(String::from("An error occurred refreshing a session.")))
+    }
+
+    async fn get_sessions(&self, user: &str) -> Result<Vec<Session>, ApiError>
+    {
+        self.find_sessions(doc! {"user_id": user}).await
+    }
+
+    async fn touch_session(&self, token_hash: &str, last_seen: i64) -> Result<(), ApiError>
+    {
+        self
+            .collection("sessions")
+            .update_one(doc! {"token_hash": token_hash}, doc! {"$set": {"last_seen": last_seen}}, None)
+            .await
+            .map(|_| ())
+            .map_err(|_| ApiError::Storage(String::from("An error occurred updating a session.")))
+    }
+
+    async fn delete_session(&self, user: &str, id: &str) -> Result<Option<Session>, ApiError>
+    {
+        let Ok(doc) = self.collection("sessions").find_one_and_delete(doc! {"user_id": user, "id": id}, None).await
+        else { return Err(ApiError::Storage(String::from("An error occurred revoking a session."))) };
+
+        doc.map(Session::from_document).transpose().map_err(malformed("session"))
+    }
+
+    async fn delete_sessions(&self, user: &str, keep: Option<&str>) -> Result<Vec<Session>, ApiError>
+    {
+        let mut filter = doc! {"user_id": user};
+        if let Some(keep) = keep { filter.insert("id", doc! {"$ne": keep}); }
+
+        // read first, so the caller learns which sessions went; any created in between are simply left alone
+        let sessions = self.find_sessions(filter).await?;
+        let hashes: Vec<&str> = sessions.iter().map(|s| s.token_hash.as_str()).collect();
+        self
+            .collection("sessions")
+            .delete_many(doc! {"token_hash": {"$in": hashes}}, None)
+            .await
+            .map_err(|_| ApiError::Storage(String::from("An error occurred revoking sessions.")))?;
+        Ok(sessions)
+    }
+
+    async fn delete_lapsed_sessions(&self, idle_before: i64, created_before: i64) -> Result<Vec<Session>, ApiError>
+    {
+        // same read-then-delete as delete_sessions; a session used in between the two still goes, as it had lapsed when it was read
+        let sessions = self.find_sessions(doc! {"$or": [{"last_seen": {"$lt": idle_before}}, {"created": {"$lt": created_before}}]}).await?;
+        let hashes: Vec<&str> = sessions.iter().map(|s| s.token_hash.as_str()).collect();
+        self
+            .collection("sessions")
+            .delete_many(doc! {"token_hash": {"$in": hashes}}, None)
+            .await
+            .map_err(|_| ApiError::Storage(String::from("An error occurred cleaning up sessions.")))?;
+        Ok(sessions)
+    }
+
+    async fn get_conversations(&self, user: &str) -> Result<Vec<Conversation>, ApiError>
+    {
+        let mut convos: Vec<Conversation> = Vec::new();
+        let Ok(mut cursor) = self
+            .collection("conversations")
+            .find(Some(doc! {"users": user}), None)
+            .await
+        else { return Err(ApiError::Storage(String::from("Failed to retrieve conversations from database."))) };
+
+        let err = |_| ApiError::Storage(String::from("Failed to retrieve conversations from database."));
+        while cursor.advance().await.map_err(err)?
+        {
+            convos.push(Conversation::from_document(cursor.deserialize_current().map_err(err)?).map_err(malformed("conversation"))?);
+        }
+
+        Ok(convos)
+    }
+
+    async fn get_conversation(&self, id: &str) -> Result<Option<Conversation>, ApiError>
+    {
+        let Ok(doc) = self
+            .collection("conversations")
+            .find_one(Some(doc! {"id": id}), None)
+            .await
+        else { return Err(ApiError::Storage(String::from("There was an error trying to retrieve a conversation."))) };
+
+        doc.map(Conversation::from_document).transpose().map_err(malformed("conversation"))
+    }
+
+    async fn create_conversation(&self, new: &Conversation) -> Result<(), ApiError>
+    {
+        self
+            .collection("conversations")
+            .insert_one(new.to_document().map_err(unencodable("conversation"))?, None)
+            .await
+            .map(|_| ())
+            .map_err(|e| match *e.kind
+            {
+                ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY => ApiError::Conflict(String::from("A conversation with that ID already exists.")),
+                _ => { error!("Failed to insert conversation: {e}"); ApiError::Storage(String::from("An error occurred generating a conversation.")) }
+            })
+    }
+
+    async fn change_members(&self, id: &str, from_epoch: u32, users: &[String], keys: &[UserKey]) -> Result<bool, ApiError>
+    {
+        let keys = bson::to_bson(keys).map_err(unencodable("conversation key"))?;
+        self
+            .collection("conversations")
+            .update_one(
+                doc! {"id": id, "epoch": from_epoch as i64},
+                doc! {"$set": {"users": users, "epoch": from_epoch as i64 + 1}, "$push": {"keys": {"$each": keys}}},
+                None
+            )
+            .await
+            .map(|result| result.modified_count == 1)
+            .map_err(|_| ApiError::Storage(String::from("An error occurred changing the members of a conversation.")))
+    }
+
+    async fn add_conversation_keys(&self, id: &str, keys: &[UserKey]) -> Result<bool, ApiError>
+    {
+        let keys = bson::to_bson(keys).map_err(unencodable("conversation key"))?;
+        self
+            .collection("conversations")
+            .update_one(doc! {"id": id}, doc! {"$push": {"keys": {"$each": keys}}}, None)
+            .await
+            .map(|result| result.matched_count == 1)
+            .map_err(|_| ApiError::Storage(String::from("An error occurred adding keys to a conversation.")))
+    }
+
+    async fn remove_conversation_keys(&self, id: &str, owner: &str) -> Result<bool, ApiError>
+    {
+        self
+            .collection("conversations")
+            .update_one(doc! {"id": id}, doc! {"$pull": {"keys": {"owner": owner}}}, None)
+            .await
+            .map(|result| result.matched_count == 1)
+            .map_err(|_| ApiError::Storage(String::from("An error occurred removing keys from a conversation.")))
+    }
+
+    async fn delete_conversation(&self, id: &str) -> Result<(), ApiError>
+    {
+        let err = |_| ApiError::Storage(String::from("An error occurred deleting a conversation."));
+        self.collection("conversations").delete_one(doc! {"id": id}, None).await.map_err(err)?;
+        self.collection("messages").delete_many(doc! {"dest_convo_id": id}, None).await.map_err(err)?;
+        Ok(())
+    }
+
+    async fn append_message(&self, message: &EncryptedMessage) -> Result<Option<EncryptedMessage>, ApiError>
+    {
+        let err = |_| ApiError::Storage(String::from("An error occurred pushing a new message to a conversation."));
+        let Some(convo) = self
+            .collection("conversations")
+            .find_one_and_update(
+                doc! {"id": &message.dest_convo_id, "users": &message.sender, "epoch": message.epoch as i64},
+                doc! {"$inc": {"last_seq": 1_i64}},
+                FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build()
+            )
+            .await
+            .map_err(err)?
+        else { return Ok(None) };
+
+        let stored = EncryptedMessage { seq: convo.get_i64("last_seq").map_err(|_| ApiError::Storage(String::from("Conversation has a malformed sequence counter.")))?, ..message.clone() };
+        self.collection("messages").insert_one(stored.to_document().map_err(unencodable("message"))?, None).await.map_err(err)?;
+        Ok(Some(stored))
+    }
+
+    async fn get_messages(&self, conversation_id: &str, before: Option<i64>, after: Option<i64>, limit: usize) -> Result<Vec<EncryptedMessage>, ApiError>
+    {
+        let err = |_| ApiError::Storage(String::from("Failed to retrieve messages from database."));
+        let mut seq = Document::new();
+        if let Some(before) = before { seq.insert("$lt", before); }
+        if let Some(after) = after { seq.insert("$gt", after); }
+        let mut filter = doc! {"dest_convo_id": conversation_id};
+        if !seq.is_empty() { filter.insert("seq", seq); }
+
+        // newest-first when paging backwards, so the limit keeps the messages closest to the cursor
+        let direction = if after.is_some() { 1 } else { -1 };
+        let options = FindOptions::builder().sort(doc! {"seq": direction}).limit(limit as i64).build();
+
+        let mut messages: Vec<EncryptedMessage> = Vec::new();
+        let mut cursor = self.collection("messages").find(filter, options).await.map_err(err)?;
+        while cursor.advance().await.map_err(err)?
+        {
+            messages.push(EncryptedMessage::from_document(cursor.deserialize_current().map_err(err)?).map_err(malformed("message"))?);
+        }
+        if direction == -1 { messages.reverse() }
+        Ok(messages)
+    }
 }
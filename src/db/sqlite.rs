@@ -0,0 +1,903 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use super::storage::Storage;
+use tracing::error;
+use crate::generics::{errors::ApiError, structs::{Account, Conversation, Device, EncryptedMessage, FriendRequest, KeyChange, KeyType, KeyWrap, RecoveryKey, Session, TwoFactor, UserKey}, utils};
+
+//----------------------------------------------//
+//                                              //
+//                 Schema Migrations            //
+//                                              //
+//----------------------------------------------//
+
+/// Every schema change, in order. The index of a migration + 1 is the `user_version` the database is at once it has been applied,
+/// so migrations must only ever be appended to this list, never edited or reordered.
+pub const MIGRATIONS: &[&str] = &[
+    // 1 - initial schema
+    "CREATE TABLE accounts (
+        username TEXT PRIMARY KEY NOT NULL,
+        hash TEXT NOT NULL,
+        public_key BLOB NOT NULL,
+        priv_key_enc BLOB NOT NULL,
+        nonce BLOB NOT NULL,
+        session_id TEXT NOT NULL DEFAULT ''
+    );
+    CREATE INDEX accounts_session_id ON accounts (session_id);
+    CREATE TABLE friends (
+        username TEXT NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        friend TEXT NOT NULL,
+        PRIMARY KEY (username, position)
+    );
+    CREATE TABLE friend_requests (
+        username TEXT NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        sender TEXT NOT NULL,
+        receiver TEXT NOT NULL,
+        status TEXT NOT NULL,
+        PRIMARY KEY (username, position)
+    );
+    CREATE TABLE conversations (
+        id TEXT PRIMARY KEY NOT NULL
+    );
+    CREATE TABLE conversation_users (
+        conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        username TEXT NOT NULL,
+        PRIMARY KEY (conversation_id, position)
+    );
+    CREATE INDEX conversation_users_username ON conversation_users (username);
+    CREATE TABLE conversation_keys (
+        conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        owner TEXT NOT NULL,
+        key BLOB NOT NULL,
+        PRIMARY KEY (conversation_id, position)
+    );
+    CREATE TABLE messages (
+        conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+        seq INTEGER NOT NULL,
+        sender TEXT NOT NULL,
+        data BLOB NOT NULL,
+        nonce BLOB NOT NULL,
+        PRIMARY KEY (conversation_id, seq)
+    );",
+    // 2 - message IDs and server timestamps
+    "ALTER TABLE messages ADD COLUMN id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE messages ADD COLUMN timestamp INTEGER NOT NULL DEFAULT 0;
+    UPDATE messages SET id = lower(hex(randomblob(12))) WHERE id = '';
+    CREATE UNIQUE INDEX messages_id ON messages (id);",
+    // 3 - one row per login instead of a single SID per account
+    "DROP INDEX accounts_session_id;
+    ALTER TABLE accounts DROP COLUMN session_id;
+    CREATE TABLE sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        token TEXT NOT NULL UNIQUE,
+        username TEXT NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        device TEXT NOT NULL,
+        ip TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_username ON sessions (username);",
+    // 4 - expiring access tokens and refresh tokens. Existing sessions have neither, so they are ended
+    "DELETE FROM sessions;
+    ALTER TABLE sessions ADD COLUMN refresh_token TEXT NOT NULL DEFAULT '';
+    ALTER TABLE sessions ADD COLUMN expires INTEGER NOT NULL DEFAULT 0;
+    CREATE UNIQUE INDEX sessions_refresh_token ON sessions (refresh_token);",
+    // 5 - only keyed hashes of session tokens are stored. The plaintext tokens can't be hashed after the fact without keeping them around, so their sessions are ended
+    "DELETE FROM sessions;
+    ALTER TABLE sessions RENAME COLUMN token TO token_hash;
+    ALTER TABLE sessions RENAME COLUMN refresh_token TO refresh_hash;
+    DROP INDEX sessions_refresh_token;
+    CREATE UNIQUE INDEX sessions_refresh_hash ON sessions (refresh_hash);",
+    // 6 - TOTP two-factor authentication and its recovery codes (hashed)
+    "CREATE TABLE two_factor (
+        username TEXT PRIMARY KEY NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        secret BLOB NOT NULL,
+        confirmed INTEGER NOT NULL,
+        last_step INTEGER NOT NULL
+    );
+    CREATE TABLE recovery_codes (
+        username TEXT NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (username, position)
+    );",
+    // 7 - usernames are unique ignoring case. Fails if two existing accounts differ only in case; one has to be renamed first
+    "CREATE UNIQUE INDEX accounts_username_nocase ON accounts (username COLLATE NOCASE);",
+    // 8 - per-account key derivation parameters for the private key. Accounts without a row still use the legacy fixed salt
+    "CREATE TABLE key_wraps (
+        username TEXT PRIMARY KEY NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        algorithm TEXT NOT NULL,
+        salt BLOB NOT NULL,
+        mem_cost INTEGER NOT NULL,
+        time_cost INTEGER NOT NULL,
+        lanes INTEGER NOT NULL
+    );",
+    // 9 - accounts whose key pair was generated by the client
+    "ALTER TABLE accounts ADD COLUMN client_keys INTEGER NOT NULL DEFAULT 0;",
+    // 10 - kinds of key pair. Everything from before is RSA
+    "ALTER TABLE accounts ADD COLUMN key_type TEXT NOT NULL DEFAULT 'rsa';
+    ALTER TABLE conversation_keys ADD COLUMN key_type TEXT NOT NULL DEFAULT 'rsa';",
+    // 11 - conversation key epochs. Everything from before belongs to the first one
+    "ALTER TABLE conversations ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE conversation_keys ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE messages ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;",
+    // 12 - history of every account's public keys
+    "CREATE TABLE key_changes (
+        username TEXT NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        key_type TEXT NOT NULL,
+        fingerprint TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (username, position)
+    );",
+    // 13 - per-device keys. Existing conversation keys and key changes are for the account's own key
+    "CREATE TABLE devices (
+        username TEXT NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        key_type TEXT NOT NULL,
+        public_key BLOB NOT NULL,
+        added INTEGER NOT NULL,
+        approved INTEGER NOT NULL,
+        session TEXT NOT NULL,
+        PRIMARY KEY (username, position)
+    );
+    ALTER TABLE conversation_keys ADD COLUMN device TEXT NOT NULL DEFAULT '';
+    ALTER TABLE key_changes ADD COLUMN device TEXT NOT NULL DEFAULT '';",
+    // 14 - private keys encrypted under a recovery key, for resetting forgotten passwords
+    "CREATE TABLE recovery_keys (
+        username TEXT PRIMARY KEY NOT NULL REFERENCES accounts (username) ON DELETE CASCADE,
+        priv_key_enc BLOB NOT NULL,
+        nonce BLOB NOT NULL,
+        algorithm TEXT NOT NULL,
+        salt BLOB NOT NULL,
+        mem_cost INTEGER NOT NULL,
+        time_cost INTEGER NOT NULL,
+        lanes INTEGER NOT NULL,
+        auth_hash TEXT NOT NULL,
+        created INTEGER NOT NULL
+    );",
+    // 15 - accounts scheduled for deletion, and the usernames of deleted accounts, which can't be registered again
+    "ALTER TABLE accounts ADD COLUMN delete_at INTEGER;
+    CREATE TABLE tombstones (
+        username TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+        deleted INTEGER NOT NULL
+    );
+    CREATE TRIGGER accounts_not_tombstoned BEFORE INSERT ON accounts
+    WHEN EXISTS (SELECT 1 FROM tombstones WHERE username = NEW.username)
+    BEGIN
+        SELECT RAISE(ABORT, 'That username belonged to a deleted account.');
+    END;",
+    // 16 - renamed accounts can't take a deleted account's username either, unless only its case changes
+    "CREATE TRIGGER accounts_not_renamed_to_tombstone BEFORE UPDATE OF username ON accounts
+    WHEN NEW.username <> OLD.username COLLATE NOCASE AND EXISTS (SELECT 1 FROM tombstones WHERE username = NEW.username)
+    BEGIN
+        SELECT RAISE(ABORT, 'That username belonged to a deleted account.');
+    END;",
+    // 17 - stable account IDs, which every reference to an account is rewritten to. References to accounts that no longer exist keep their username
+    "ALTER TABLE accounts ADD COLUMN id TEXT NOT NULL DEFAULT '';
+    UPDATE accounts SET id = lower(hex(randomblob(16)));
+    CREATE UNIQUE INDEX accounts_id ON accounts (id);
+    UPDATE friends SET friend = (SELECT a.id FROM accounts a WHERE a.username = friends.friend)
+    WHERE friend IN (SELECT username FROM accounts);
+    UPDATE friend_requests SET sender = (SELECT a.id FROM accounts a WHERE a.username = friend_requests.sender)
+    WHERE sender IN (SELECT username FROM accounts);
+    UPDATE friend_requests SET receiver = (SELECT a.id FROM accounts a WHERE a.username = friend_requests.receiver)
+    WHERE receiver IN (SELECT username FROM accounts);
+    DROP INDEX conversation_users_username;
+    ALTER TABLE conversation_users RENAME COLUMN username TO user_id;
+    CREATE INDEX conversation_users_user_id ON conversation_users (user_id);
+    UPDATE conversation_users SET user_id = (SELECT a.id FROM accounts a WHERE a.username = conversation_users.user_id)
+    WHERE user_id IN (SELECT username FROM accounts);
+    UPDATE conversation_keys SET owner = (SELECT a.id FROM accounts a WHERE a.username = conversation_keys.owner)
+    WHERE owner IN (SELECT username FROM accounts);
+    UPDATE messages SET sender = (SELECT a.id FROM accounts a WHERE a.username = messages.sender)
+    WHERE sender IN (SELECT username FROM accounts);",
+    // 18 - sessions belong to their account by ID, so renames leave them alone. SQLite can't change a foreign key in place, so the table is rebuilt
+    "CREATE TABLE sessions_by_id (
+        id TEXT PRIMARY KEY NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        user_id TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+        device TEXT NOT NULL,
+        ip TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        refresh_hash TEXT NOT NULL DEFAULT '',
+        expires INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO sessions_by_id (id, token_hash, user_id, device, ip, created, last_seen, refresh_hash, expires)
+    SELECT s.id, s.token_hash, a.id, s.device, s.ip, s.created, s.last_seen, s.refresh_hash, s.expires FROM sessions s JOIN accounts a ON a.username = s.username;
+    DROP TABLE sessions;
+    ALTER TABLE sessions_by_id RENAME TO sessions;
+    CREATE INDEX sessions_user_id ON sessions (user_id);
+    CREATE UNIQUE INDEX sessions_refresh_hash ON sessions (refresh_hash);"
+];
+
+/// Brings the database schema up to date, applying every migration past the database's current `user_version` inside one transaction.
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()>
+{
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= MIGRATIONS.len() { return Ok(()) }
+
+    let tx = conn.transaction()?;
+    for migration in &MIGRATIONS[version..] { tx.execute_batch(migration)?; }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()
+}
+
+//----------------------------------------------//
+//                                              //
+//                    Backend                   //
+//                                              //
+//----------------------------------------------//
+
+/// [`Storage`] backend for a single SQLite file, for self-hosted deployments that don't want to run MongoDB.
+///
+/// rusqlite is blocking, so every query runs on tokio's blocking pool against one shared connection.
+pub struct SqliteStore
+{
+    conn: Arc<Mutex<Connection>>
+}
+
+impl SqliteStore
+{
+    /// Opens (or creates) the database at `path` and runs any pending migrations. `:memory:` gives a throwaway database.
+    pub fn open(path: &str) -> Result<SqliteStore, String>
+    {
+        let mut conn = Connection::open(path).map_err(|e| format!("Failed to open SQLite database at `{path}`: {e}"))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")
+            .map_err(|e| format!("Failed to configure SQLite database: {e}"))?;
+        migrate(&mut conn).map_err(|e| format!("Failed to migrate SQLite database: {e}"))?;
+        Ok(SqliteStore { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Runs `f` against the connection on the blocking pool, mapping any error to `err`.
+    async fn with_conn<T, F>(&self, err: &'static str, f: F) -> Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static
+    {
+        self.with_conn_or_conflict(err, None, f).await
+    }
+
+    /// Like [`SqliteStore::with_conn`], but a violated constraint is reported as an [`ApiError::Conflict`] carrying `conflict`.
+    async fn with_conn_or_conflict<T, F>(&self, err: &'static str, conflict: Option<&'static str>, f: F) -> Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static
+    {
+        let conn = Arc::clone(&self.conn);
+        // a panic in an earlier closure poisons the lock, but any transaction it had open was rolled back as it unwound, so the connection is still fine to use
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap_or_else(PoisonError::into_inner)))
+            .await
+            .map_err(|_| ApiError::Storage(err.to_string()))?
+            .map_err(|e| match (e.sqlite_error_code(), conflict)
+            {
+                (Some(rusqlite::ErrorCode::ConstraintViolation), Some(conflict)) => ApiError::Conflict(conflict.to_string()),
+                _ => { error!("{err} ({e})"); ApiError::Storage(err.to_string()) }
+            })
+    }
+}
+
+fn read_key_type(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<KeyType>
+{
+    row.get::<_, String>(idx)?
+        .parse()
+        .map_err(|e: String| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, e.into()))
+}
+
+fn read_account(conn: &Connection, username: &str) -> rusqlite::Result<Option<Account>>
+{
+    let Some(mut account) = conn
+        .query_row(
+            "SELECT username, hash, public_key, priv_key_enc, nonce, client_keys, key_type, delete_at, id FROM accounts WHERE username = ?1",
+            params![username],
+            |row| Ok(Account {
+                username: row.get(0)?,
+                id: row.get(8)?,
+                hash: row.get(1)?,
+                public_key: row.get(2)?,
+                priv_key_enc: row.get(3)?,
+                nonce: row.get(4)?,
+                friends: Vec::new(),
+                friend_requests: Vec::new(),
+                two_factor: None,
+                key_wrap: None,
+                client_keys: row.get(5)?,
+                key_type: read_key_type(row, 6)?,
+                key_changes: Vec::new(),
+                devices: Vec::new(),
+                recovery_key: None,
+                delete_at: row.get(7)?
+            })
+        )
+        .optional()?
+    else { return Ok(None) };
+
+    account.friends = conn
+        .prepare("SELECT friend FROM friends WHERE username = ?1 ORDER BY position")?
+        .query_map(params![username], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    account.friend_requests = conn
+        .prepare("SELECT sender, receiver, status FROM friend_requests WHERE username = ?1 ORDER BY position")?
+        .query_map(params![username], |row| Ok(FriendRequest { sender: row.get(0)?, receiver: row.get(1)?, status: row.get(2)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+    account.two_factor = conn
+        .query_row(
+            "SELECT secret, confirmed, last_step FROM two_factor WHERE username = ?1",
+            params![username],
+            |row| Ok(TwoFactor { secret: row.get(0)?, confirmed: row.get(1)?, last_step: row.get(2)?, recovery_codes: Vec::new() })
+        )
+        .optional()?;
+    account.key_wrap = conn
+        .query_row(
+            "SELECT algorithm, salt, mem_cost, time_cost, lanes FROM key_wraps WHERE username = ?1",
+            params![username],
+            |row| Ok(KeyWrap { algorithm: row.get(0)?, salt: row.get(1)?, mem_cost: row.get(2)?, time_cost: row.get(3)?, lanes: row.get(4)? })
+        )
+        .optional()?;
+    account.key_changes = conn
+        .prepare("SELECT key_type, fingerprint, timestamp, device FROM key_changes WHERE username = ?1 ORDER BY position")?
+        .query_map(params![username], |row| Ok(KeyChange { key_type: read_key_type(row, 0)?, fingerprint: row.get(1)?, timestamp: row.get(2)?, device: row.get(3)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+    account.devices = conn
+        .prepare("SELECT id, name, key_type, public_key, added, approved, session FROM devices WHERE username = ?1 ORDER BY position")?
+        .query_map(params![username], |row| Ok(Device {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            key_type: read_key_type(row, 2)?,
+            public_key: row.get(3)?,
+            added: row.get(4)?,
+            approved: row.get(5)?,
+            session: row.get(6)?
+        }))?
+        .collect::<rusqlite::Result<_>>()?;
+    account.recovery_key = conn
+        .query_row(
+            "SELECT priv_key_enc, nonce, algorithm, salt, mem_cost, time_cost, lanes, auth_hash, created FROM recovery_keys WHERE username = ?1",
+            params![username],
+            |row| Ok(RecoveryKey {
+                priv_key_enc: row.get(0)?,
+                nonce: row.get(1)?,
+                key_wrap: KeyWrap { algorithm: row.get(2)?, salt: row.get(3)?, mem_cost: row.get(4)?, time_cost: row.get(5)?, lanes: row.get(6)? },
+                auth_hash: row.get(7)?,
+                created: row.get(8)?
+            })
+        )
+        .optional()?;
+    if let Some(two_factor) = &mut account.two_factor
+    {
+        two_factor.recovery_codes = conn
+            .prepare("SELECT hash FROM recovery_codes WHERE username = ?1 ORDER BY position")?
+            .query_map(params![username], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(Some(account))
+}
+
+/// Replaces the friends, friend requests, two-factor settings, key wrapping parameters, key history, devices and recovery key of an account with the ones on `account`.
+fn write_account_lists(tx: &Transaction, account: &Account) -> rusqlite::Result<()>
+{
+    tx.execute("DELETE FROM friends WHERE username = ?1", params![account.username])?;
+    tx.execute("DELETE FROM friend_requests WHERE username = ?1", params![account.username])?;
+    for (i, friend) in account.friends.iter().enumerate()
+    {
+        tx.execute("INSERT INTO friends (username, position, friend) VALUES (?1, ?2, ?3)", params![account.username, i, friend])?;
+    }
+    for (i, req) in account.friend_requests.iter().enumerate()
+    {
+        tx.execute(
+            "INSERT INTO friend_requests (username, position, sender, receiver, status) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![account.username, i, req.sender, req.receiver, req.status]
+        )?;
+    }
+
+    tx.execute("DELETE FROM two_factor WHERE username = ?1", params![account.username])?;
+    tx.execute("DELETE FROM recovery_codes WHERE username = ?1", params![account.username])?;
+    if let Some(two_factor) = &account.two_factor
+    {
+        tx.execute(
+            "INSERT INTO two_factor (username, secret, confirmed, last_step) VALUES (?1, ?2, ?3, ?4)",
+            params![account.username, two_factor.secret, two_factor.confirmed, two_factor.last_step]
+        )?;
+        for (i, hash) in two_factor.recovery_codes.iter().enumerate()
+        {
+            tx.execute("INSERT INTO recovery_codes (username, position, hash) VALUES (?1, ?2, ?3)", params![account.username, i, hash])?;
+        }
+    }
+
+    tx.execute("DELETE FROM key_wraps WHERE username = ?1", params![account.username])?;
+    if let Some(key_wrap) = &account.key_wrap
+    {
+        tx.execute(
+            "INSERT INTO key_wraps (username, algorithm, salt, mem_cost, time_cost, lanes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![account.username, key_wrap.algorithm, key_wrap.salt, key_wrap.mem_cost, key_wrap.time_cost, key_wrap.lanes]
+        )?;
+    }
+
+    tx.execute("DELETE FROM key_changes WHERE username = ?1", params![account.username])?;
+    for (i, change) in account.key_changes.iter().enumerate()
+    {
+        tx.execute(
+            "INSERT INTO key_changes (username, position, key_type, fingerprint, timestamp, device) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![account.username, i, change.key_type.as_str(), change.fingerprint, change.timestamp, change.device]
+        )?;
+    }
+
+    tx.execute("DELETE FROM devices WHERE username = ?1", params![account.username])?;
+    for (i, device) in account.devices.iter().enumerate()
+    {
+        tx.execute(
+            "INSERT INTO devices (username, position, id, name, key_type, public_key, added, approved, session) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![account.username, i, device.id, device.name, device.key_type.as_str(), device.public_key, device.added, device.approved, device.session]
+        )?;
+    }
+
+    tx.execute("DELETE FROM recovery_keys WHERE username = ?1", params![account.username])?;
+    if let Some(recovery) = &account.recovery_key
+    {
+        let wrap = &recovery.key_wrap;
+        tx.execute(
+            "INSERT INTO recovery_keys (username, priv_key_enc, nonce, algorithm, salt, mem_cost, time_cost, lanes, auth_hash, created)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![account.username, recovery.priv_key_enc, recovery.nonce, wrap.algorithm, wrap.salt, wrap.mem_cost, wrap.time_cost, wrap.lanes, recovery.auth_hash, recovery.created]
+        )?;
+    }
+    Ok(())
+}
+
+fn read_conversation(conn: &Connection, id: &str) -> rusqlite::Result<Option<Conversation>>
+{
+    let Some(epoch) = conn
+        .query_row("SELECT epoch FROM conversations WHERE id = ?1", params![id], |row| row.get(0))
+        .optional()?
+    else { return Ok(None) };
+
+    let users = conn
+        .prepare("SELECT user_id FROM conversation_users WHERE conversation_id = ?1 ORDER BY position")?
+        .query_map(params![id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let keys = conn
+        .prepare("SELECT owner, key, key_type, epoch, device FROM conversation_keys WHERE conversation_id = ?1 ORDER BY position")?
+        .query_map(params![id], |row| Ok(UserKey { owner: row.get(0)?, key: row.get(1)?, key_type: read_key_type(row, 2)?, epoch: row.get(3)?, device: row.get(4)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Some(Conversation { id: id.to_string(), users, keys, messages: Vec::new(), epoch }))
+}
+
+/// Inserts keys into a conversation, starting at `position`.
+fn write_conversation_keys(tx: &Transaction, id: &str, position: usize, keys: &[UserKey]) -> rusqlite::Result<()>
+{
+    for (i, key) in keys.iter().enumerate()
+    {
+        tx.execute(
+            "INSERT INTO conversation_keys (conversation_id, position, owner, key, key_type, epoch, device) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![id, position + i, key.owner, key.key, key.key_type.as_str(), key.epoch, key.device]
+        )?;
+    }
+    Ok(())
+}
+
+const SESSION_COLUMNS: &str = "id, token_hash, user_id, device, ip, created, last_seen, refresh_hash, expires";
+
+fn read_session(row: &rusqlite::Row) -> rusqlite::Result<Session>
+{
+    Ok(Session {
+        id: row.get(0)?,
+        token_hash: row.get(1)?,
+        user_id: row.get(2)?,
+        device: row.get(3)?,
+        ip: row.get(4)?,
+        created: row.get(5)?,
+        last_seen: row.get(6)?,
+        refresh_hash: row.get(7)?,
+        expires: row.get(8)?
+    })
+}
+
+fn read_message(row: &rusqlite::Row) -> rusqlite::Result<EncryptedMessage>
+{
+    Ok(EncryptedMessage {
+        dest_convo_id: row.get(0)?,
+        seq: row.get(1)?,
+        id: row.get(2)?,
+        timestamp: row.get(3)?,
+        sender: row.get(4)?,
+        data: row.get(5)?,
+        nonce: row.get(6)?,
+        epoch: row.get(7)?,
+        sender_sid: String::new()
+    })
+}
+
+#[async_trait]
+impl Storage for SqliteStore
+{
+    async fn ping(&self) -> Result<(), ApiError>
+    {
+        self.with_conn("Failed to reach the SQLite database.", |conn| conn.query_row("SELECT 1", [], |_| Ok(()))).await
+    }
+
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, ApiError>
+    {
+        let username = username.to_string();
+        self.with_conn("An error occurred querying the database for an account by username.", move |conn| read_account(conn, &username)).await
+    }
+
+    async fn get_account_by_id(&self, id: &str) -> Result<Option<Account>, ApiError>
+    {
+        let id = id.to_string();
+        self.with_conn("An error occurred querying the database for an account by ID.", move |conn| {
+            let username: Option<String> = conn.query_row("SELECT username FROM accounts WHERE id = ?1", params![id], |row| row.get(0)).optional()?;
+            username.map(|username| read_account(conn, &username)).transpose().map(Option::flatten)
+        })
+        .await
+    }
+
+    async fn create_account(&self, new: &Account) -> Result<(), ApiError>
+    {
+        let new = new.clone();
+        let conflict = Some("An account with that username already exists.");
+        self.with_conn_or_conflict("An error occurred creating an account in the database.", conflict, move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO accounts (username, hash, public_key, priv_key_enc, nonce, client_keys, key_type, delete_at, id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![new.username, new.hash, new.public_key, new.priv_key_enc, new.nonce, new.client_keys, new.key_type.as_str(), new.delete_at, new.id]
+            )?;
+            write_account_lists(&tx, &new)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn update_account(&self, new: &Account) -> Result<(), ApiError>
+    {
+        let new = new.clone();
+        let updated = self.with_conn("An error occurred updating an account in the database.", move |conn| {
+            let tx = conn.transaction()?;
+            // the account may have been renamed since `new` was read, so its rows are written under the username it has now
+            let Some(username) = tx.query_row("SELECT username FROM accounts WHERE id = ?1", params![new.id], |row| row.get(0)).optional()?
+            else { return Ok(false) };
+            tx.execute(
+                "UPDATE accounts SET hash = ?2, public_key = ?3, priv_key_enc = ?4, nonce = ?5, client_keys = ?6, key_type = ?7, delete_at = ?8 WHERE id = ?1",
+                params![new.id, new.hash, new.public_key, new.priv_key_enc, new.nonce, new.client_keys, new.key_type.as_str(), new.delete_at]
+            )?;
+            write_account_lists(&tx, &Account { username, ..new })?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await?;
+        if !updated { return Err(ApiError::NotFound(String::from("Account not found."))) }
+        Ok(())
+    }
+
+    async fn use_two_factor_code(&self, id: &str, old: &TwoFactor, new: &TwoFactor) -> Result<bool, ApiError>
+    {
+        let (id, old, new) = (id.to_string(), old.clone(), new.clone());
+        self.with_conn("An error occurred recording a used two-factor code in the database.", move |conn| {
+            let tx = conn.transaction()?;
+            let Some(username): Option<String> = tx.query_row("SELECT username FROM accounts WHERE id = ?1", params![id], |row| row.get(0)).optional()?
+            else { return Ok(false) };
+            let Some(last_step): Option<i64> = tx.query_row("SELECT last_step FROM two_factor WHERE username = ?1", params![username], |row| row.get(0)).optional()?
+            else { return Ok(false) };
+            let recovery_codes: Vec<String> = tx
+                .prepare("SELECT hash FROM recovery_codes WHERE username = ?1 ORDER BY position")?
+                .query_map(params![username], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            if last_step != old.last_step || recovery_codes != old.recovery_codes { return Ok(false) }
+
+            tx.execute("UPDATE two_factor SET last_step = ?2 WHERE username = ?1", params![username, new.last_step])?;
+            tx.execute("DELETE FROM recovery_codes WHERE username = ?1", params![username])?;
+            for (i, hash) in new.recovery_codes.iter().enumerate()
+            {
+                tx.execute("INSERT INTO recovery_codes (username, position, hash) VALUES (?1, ?2, ?3)", params![username, i, hash])?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn delete_account(&self, id: &str) -> Result<(), ApiError>
+    {
+        let id = id.to_string();
+        self.with_conn("An error occurred deleting an account from the database.", move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO tombstones (username, deleted) SELECT username, ?2 FROM accounts WHERE id = ?1",
+                params![id, utils::now()]
+            )?;
+            // sessions go with it, through their foreign key
+            tx.execute("DELETE FROM accounts WHERE id = ?1", params![id])?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn rename_account(&self, id: &str, new: &str) -> Result<bool, ApiError>
+    {
+        let (id, new) = (id.to_string(), new.to_string());
+        let conflict = Some("An account with that username already exists.");
+        self.with_conn_or_conflict("An error occurred renaming an account.", conflict, move |conn| {
+            let tx = conn.transaction()?;
+            let Some(old) = tx.query_row("SELECT username FROM accounts WHERE id = ?1", params![id], |row| row.get::<_, String>(0)).optional()?
+            else { return Ok(false) };
+            // the account's own rows are only consistent again once they have all been renamed
+            tx.pragma_update(None, "defer_foreign_keys", true)?;
+            tx.execute("UPDATE accounts SET username = ?2 WHERE id = ?1", params![id, new])?;
+            for table in ["friends", "friend_requests", "two_factor", "recovery_codes", "key_wraps", "key_changes", "devices", "recovery_keys"]
+            {
+                tx.execute(&format!("UPDATE {table} SET username = ?2 WHERE username = ?1"), params![old, new])?;
+            }
+            if !old.eq_ignore_ascii_case(&new)
+            {
+                tx.execute("INSERT OR IGNORE INTO tombstones (username, deleted) VALUES (?1, ?2)", params![old, utils::now()])?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn get_accounts_due_for_deletion(&self, now: i64) -> Result<Vec<String>, ApiError>
+    {
+        self.with_conn("An error occurred looking up accounts due for deletion.", move |conn| {
+            conn.prepare("SELECT id FROM accounts WHERE delete_at <= ?1")?
+                .query_map(params![now], |row| row.get(0))?
+                .collect()
+        })
+        .await
+    }
+
+    async fn create_session(&self, session: &Session) -> Result<(), ApiError>
+    {
+        let s = session.clone();
+        self.with_conn("An error occurred creating a session.", move |conn| {
+            conn.execute(
+                &format!("INSERT INTO sessions ({SESSION_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"),
+                params![s.id, s.token_hash, s.user_id, s.device, s.ip, s.created, s.last_seen, s.refresh_hash, s.expires]
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_session(&self, token_hash: &str) -> Result<Option<Session>, ApiError>
+    {
+        let token_hash = token_hash.to_string();
+        self.with_conn("An error occurred looking up a session.", move |conn| {
+            conn.query_row(&format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE token_hash = ?1"), params![token_hash], read_session).optional()
+        })
+        .await
+    }
+
+    async fn get_session_by_refresh(&self, refresh_hash: &str) -> Result<Option<Session>, ApiError>
+    {
+        let refresh_hash = refresh_hash.to_string();
+        self.with_conn("An error occurred looking up a session.", move |conn| {
+            conn.query_row(&format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE refresh_hash = ?1"), params![refresh_hash], read_session).optional()
+        })
+        .await
+    }
+
+    async fn rotate_session(&self, refresh_hash: &str, rotated: &Session) -> Result<bool, ApiError>
+    {
+        let (refresh_hash, s) = (refresh_hash.to_string(), rotated.clone());
+        self.with_conn("An error occurred refreshing a session.", move |conn| {
+            conn.execute(
+                "UPDATE sessions SET token_hash = ?3, refresh_hash = ?4, expires = ?5, last_seen = ?6 WHERE id = ?1 AND refresh_hash = ?2",
+                params![s.id, refresh_hash, s.token_hash, s.refresh_hash, s.expires, s.last_seen]
+            )
+            .map(|changed| changed == 1)
+        })
+        .await
+    }
+
+    async fn get_sessions(&self, user: &str) -> Result<Vec<Session>, ApiError>
+    {
+        let user = user.to_string();
+        self.with_conn("An error occurred listing sessions.", move |conn| {
+            conn.prepare(&format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE user_id = ?1 ORDER BY created"))?
+                .query_map(params![user], read_session)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn touch_session(&self, token_hash: &str, last_seen: i64) -> Result<(), ApiError>
+    {
+        let token_hash = token_hash.to_string();
+        self.with_conn("An error occurred updating a session.", move |conn| {
+            conn.execute("UPDATE sessions SET last_seen = ?2 WHERE token_hash = ?1", params![token_hash, last_seen]).map(|_| ())
+        })
+        .await
+    }
+
+    async fn delete_session(&self, user: &str, id: &str) -> Result<Option<Session>, ApiError>
+    {
+        let (user, id) = (user.to_string(), id.to_string());
+        self.with_conn("An error occurred revoking a session.", move |conn| {
+            conn.query_row(&format!("DELETE FROM sessions WHERE user_id = ?1 AND id = ?2 RETURNING {SESSION_COLUMNS}"), params![user, id], read_session)
+                .optional()
+        })
+        .await
+    }
+
+    async fn delete_sessions(&self, user: &str, keep: Option<&str>) -> Result<Vec<Session>, ApiError>
+    {
+        let (user, keep) = (user.to_string(), keep.map(str::to_string));
+        self.with_conn("An error occurred revoking sessions.", move |conn| {
+            conn.prepare(&format!("DELETE FROM sessions WHERE user_id = ?1 AND id IS NOT ?2 RETURNING {SESSION_COLUMNS}"))?
+                .query_map(params![user, keep], read_session)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn delete_lapsed_sessions(&self, idle_before: i64, created_before: i64) -> Result<Vec<Session>, ApiError>
+    {
+        self.with_conn("An error occurred cleaning up sessions.", move |conn| {
+            conn.prepare(&format!("DELETE FROM sessions WHERE last_seen < ?1 OR created < ?2 RETURNING {SESSION_COLUMNS}"))?
+                .query_map(params![idle_before, created_before], read_session)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn get_conversations(&self, user: &str) -> Result<Vec<Conversation>, ApiError>
+    {
+        let user = user.to_string();
+        self.with_conn("Failed to retrieve conversations from database.", move |conn| {
+            let ids: Vec<String> = conn
+                .prepare("SELECT DISTINCT conversation_id FROM conversation_users WHERE user_id = ?1")?
+                .query_map(params![user], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            let mut convos: Vec<Conversation> = Vec::new();
+            for id in ids
+            {
+                if let Some(convo) = read_conversation(conn, &id)? { convos.push(convo) }
+            }
+            Ok(convos)
+        })
+        .await
+    }
+
+    async fn get_conversation(&self, id: &str) -> Result<Option<Conversation>, ApiError>
+    {
+        let id = id.to_string();
+        self.with_conn("There was an error trying to retrieve a conversation.", move |conn| read_conversation(conn, &id)).await
+    }
+
+    async fn create_conversation(&self, new: &Conversation) -> Result<(), ApiError>
+    {
+        let new = new.clone();
+        let conflict = Some("A conversation with that ID already exists.");
+        self.with_conn_or_conflict("An error occurred generating a conversation.", conflict, move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("INSERT INTO conversations (id, epoch) VALUES (?1, ?2)", params![new.id, new.epoch])?;
+            for (i, user) in new.users.iter().enumerate()
+            {
+                tx.execute("INSERT INTO conversation_users (conversation_id, position, user_id) VALUES (?1, ?2, ?3)", params![new.id, i, user])?;
+            }
+            write_conversation_keys(&tx, &new.id, 0, &new.keys)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn change_members(&self, id: &str, from_epoch: u32, users: &[String], keys: &[UserKey]) -> Result<bool, ApiError>
+    {
+        let (id, users, keys) = (id.to_string(), users.to_vec(), keys.to_vec());
+        self.with_conn("An error occurred changing the members of a conversation.", move |conn| {
+            let tx = conn.transaction()?;
+            if tx.execute("UPDATE conversations SET epoch = epoch + 1 WHERE id = ?1 AND epoch = ?2", params![id, from_epoch])? == 0
+            { return Ok(false) }
+
+            tx.execute("DELETE FROM conversation_users WHERE conversation_id = ?1", params![id])?;
+            for (i, user) in users.iter().enumerate()
+            {
+                tx.execute("INSERT INTO conversation_users (conversation_id, position, user_id) VALUES (?1, ?2, ?3)", params![id, i, user])?;
+            }
+            // keys of earlier epochs stay, the new ones go after them
+            let position: usize = tx.query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM conversation_keys WHERE conversation_id = ?1",
+                params![id],
+                |row| row.get(0)
+            )?;
+            write_conversation_keys(&tx, &id, position, &keys)?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn add_conversation_keys(&self, id: &str, keys: &[UserKey]) -> Result<bool, ApiError>
+    {
+        let (id, keys) = (id.to_string(), keys.to_vec());
+        self.with_conn("An error occurred adding keys to a conversation.", move |conn| {
+            let tx = conn.transaction()?;
+            let exists: Option<String> = tx.query_row("SELECT id FROM conversations WHERE id = ?1", params![id], |row| row.get(0)).optional()?;
+            if exists.is_none() { return Ok(false) }
+
+            let position: usize = tx.query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM conversation_keys WHERE conversation_id = ?1",
+                params![id],
+                |row| row.get(0)
+            )?;
+            write_conversation_keys(&tx, &id, position, &keys)?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn remove_conversation_keys(&self, id: &str, owner: &str) -> Result<bool, ApiError>
+    {
+        let (id, owner) = (id.to_string(), owner.to_string());
+        self.with_conn("An error occurred removing keys from a conversation.", move |conn| {
+            let tx = conn.transaction()?;
+            let exists: Option<String> = tx.query_row("SELECT id FROM conversations WHERE id = ?1", params![id], |row| row.get(0)).optional()?;
+            if exists.is_none() { return Ok(false) }
+            tx.execute("DELETE FROM conversation_keys WHERE conversation_id = ?1 AND owner = ?2", params![id, owner])?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn delete_conversation(&self, id: &str) -> Result<(), ApiError>
+    {
+        let id = id.to_string();
+        self.with_conn("An error occurred deleting a conversation.", move |conn| {
+            // members, keys and messages go with it, through their foreign keys
+            conn.execute("DELETE FROM conversations WHERE id = ?1", params![id]).map(|_| ())
+        })
+        .await
+    }
+
+    async fn append_message(&self, message: &EncryptedMessage) -> Result<Option<EncryptedMessage>, ApiError>
+    {
+        let message = message.clone();
+        self.with_conn("An error occurred pushing a new message to a conversation.", move |conn| {
+            // the sequence number is picked inside the insert itself, so it can't race with another append
+            let seq: Option<i64> = conn
+                .query_row(
+                    "INSERT INTO messages (conversation_id, seq, id, timestamp, sender, data, nonce, epoch)
+                     SELECT id, (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE conversation_id = ?1), ?2, ?3, ?4, ?5, ?6, epoch
+                     FROM conversations
+                     WHERE id = ?1 AND epoch = ?7 AND EXISTS (SELECT 1 FROM conversation_users WHERE conversation_id = ?1 AND user_id = ?4)
+                     RETURNING seq",
+                    params![message.dest_convo_id, message.id, message.timestamp, message.sender, message.data, message.nonce, message.epoch],
+                    |row| row.get(0)
+                )
+                .optional()?;
+            Ok(seq.map(|seq| EncryptedMessage { seq, ..message }))
+        })
+        .await
+    }
+
+    async fn get_messages(&self, conversation_id: &str, before: Option<i64>, after: Option<i64>, limit: usize) -> Result<Vec<EncryptedMessage>, ApiError>
+    {
+        let conversation_id = conversation_id.to_string();
+        self.with_conn("Failed to retrieve messages from database.", move |conn| {
+            // newest-first when paging backwards, so the limit keeps the messages closest to the cursor
+            let direction = if after.is_some() { "ASC" } else { "DESC" };
+            let mut messages: Vec<EncryptedMessage> = conn
+                .prepare(&format!(
+                    "SELECT conversation_id, seq, id, timestamp, sender, data, nonce, epoch FROM messages
+                     WHERE conversation_id = ?1 AND seq < ?2 AND seq > ?3
+                     ORDER BY seq {direction} LIMIT ?4"
+                ))?
+                .query_map(params![conversation_id, before.unwrap_or(i64::MAX), after.unwrap_or(0), limit as i64], read_message)?
+                .collect::<rusqlite::Result<_>>()?;
+            if after.is_none() { messages.reverse() }
+            Ok(messages)
+        })
+        .await
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::generics::{errors::ApiError, structs::{Account, Conversation, EncryptedMessage, Session, TwoFactor, UserKey}};
+
+//----------------------------------------------//
+//                                              //
+//        Backend-agnostic storage interface    //
+//                                              //
+//----------------------------------------------//
+
+/// A shared handle to whichever [`Storage`] backend was chosen at startup. This is what lives in the app state.
+pub type Db = Arc<dyn Storage>;
+
+/// Everything the API needs to persist. Each backend (see [`super::mongo::MongoStore`] and [`super::memory::MemoryStore`]) implements this,
+/// and route handlers only ever talk to the backend through it. Failures of the backend itself surface as [`ApiError::Storage`].
+#[async_trait]
+pub trait Storage: Send + Sync
+{
+    /// Checks that the backend is reachable.
+    async fn ping(&self) -> Result<(), ApiError>;
+
+    /// Retrieves an account value by username.
+    ///
+    /// ## Returns
+    /// * [`Result<Option<Account>, ApiError>`][`std::result::Result`] - A result containing an account option (None if no account is found) or an [`ApiError::Storage`], if an internal error occurred.
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, ApiError>;
+
+    /// Retrieves an account value by its [`Account::id`]. Returns `None` if no account has that ID.
+    async fn get_account_by_id(&self, id: &str) -> Result<Option<Account>, ApiError>;
+
+    /// Retrieves an account value by a handle a client named it with: its ID or its username. Handles that look like an ID (see [`Account::looks_like_id`])
+    /// are tried as one first; usernames can't look like one, so nobody can take over lookups of someone else's ID.
+    async fn find_account(&self, handle: &str) -> Result<Option<Account>, ApiError>
+    {
+        if Account::looks_like_id(handle)
+        {
+            if let Some(account) = self.get_account_by_id(handle).await? { return Ok(Some(account)) }
+        }
+        self.get_account(handle).await
+    }
+
+    /// Creates a new account entry from a given account value. Fails with [`ApiError::Conflict`] if the username is taken, or belonged to a deleted account
+    /// (see [`Storage::delete_account`]), ignoring ASCII case. This must be enforced by the backend itself (e.g. with a unique index),
+    /// so two concurrent signups can't both get through.
+    async fn create_account(&self, new: &Account) -> Result<(), ApiError>;
+
+    /// "Updates" an account value. This is done by replacing the old account value (matched by [`Account::id`]) with the new one, all but its username,
+    /// which only [`Storage::rename_account`] changes. That way a write made from a copy read before a rename still lands, and doesn't undo the rename.
+    /// Fails with [`ApiError::NotFound`] if no account has that ID, e.g. because it was deleted in the meantime.
+    async fn update_account(&self, new: &Account) -> Result<(), ApiError>;
+
+    /// Records that a two-factor code of the account with the ID `id` was used, by storing the [`TwoFactor::last_step`] and [`TwoFactor::recovery_codes`] of `new`,
+    /// but only if the stored ones are still those of `old`. Of two requests that accepted the same code at once, only one gets through.
+    ///
+    /// ## Returns
+    /// * [`Result<bool, ApiError>`][`std::result::Result`] - Whether the code was recorded. False if another code was used since `old` was read,
+    ///   or the account no longer has two-factor authentication (or no longer exists).
+    async fn use_two_factor_code(&self, id: &str, old: &TwoFactor, new: &TwoFactor) -> Result<bool, ApiError>;
+
+    /// Deletes the account with the ID `id`, along with all of its sessions. A tombstone of its username is left behind, so it can never be registered again
+    /// (ignoring ASCII case) by someone else, who could then pass as the deleted user in conversations the old account was a part of.
+    async fn delete_account(&self, id: &str) -> Result<(), ApiError>;
+
+    /// Renames the account with the ID `id` to `new`. Everything else, sessions included, refers to the account by its ID, so it is left as it is.
+    /// The old username is left as a tombstone, like a deleted account's.
+    /// Fails with [`ApiError::Conflict`] if `new` is taken or belonged to a deleted account, ignoring ASCII case (changing only the case of the username is fine).
+    /// This must be a single atomic operation, so the account is never left half renamed.
+    ///
+    /// ## Returns
+    /// * [`Result<bool, ApiError>`][`std::result::Result`] - Whether the account was renamed; false if no account has the ID `id`.
+    async fn rename_account(&self, id: &str, new: &str) -> Result<bool, ApiError>;
+
+    /// Lists the IDs of accounts scheduled for deletion (see [`Account::delete_at`]) at or before `now`.
+    async fn get_accounts_due_for_deletion(&self, now: i64) -> Result<Vec<String>, ApiError>;
+
+    /// Stores a newly created session. Sessions are only ever handled by the hashes of their tokens (see [`crate::generics::utils::hash_token`]),
+    /// so the tokens themselves are never stored.
+    async fn create_session(&self, session: &Session) -> Result<(), ApiError>;
+
+    /// Looks a session up by the hash of its access token. Returns `None` if there is no such session (e.g. it was revoked).
+    async fn get_session(&self, token_hash: &str) -> Result<Option<Session>, ApiError>;
+
+    /// Looks a session up by the hash of its current refresh token. Returns `None` if there is no such session, or the token was already used.
+    async fn get_session_by_refresh(&self, refresh_hash: &str) -> Result<Option<Session>, ApiError>;
+
+    /// Replaces a session's token hashes, expiry and `last_seen` with those of `rotated`, but only if its refresh token hash is still `refresh_hash`.
+    /// This must be a single atomic operation, so a refresh token can never be traded in twice.
+    ///
+    /// ## Returns
+    /// * [`Result<bool, ApiError>`][`std::result::Result`] - Whether the session was rotated; false if the refresh token was already used or the session is gone.
+    async fn rotate_session(&self, refresh_hash: &str, rotated: &Session) -> Result<bool, ApiError>;
+
+    /// Lists every session of the account with the ID `user`, oldest first.
+    async fn get_sessions(&self, user: &str) -> Result<Vec<Session>, ApiError>;
+
+    /// Records that the session with the given access token hash was just used.
+    async fn touch_session(&self, token_hash: &str, last_seen: i64) -> Result<(), ApiError>;
+
+    /// Deletes one session of the account with the ID `user`, by the session's public ID.
+    ///
+    /// ## Returns
+    /// * [`Result<Option<Session>, ApiError>`][`std::result::Result`] - The deleted session, or None if the account has no session with that ID.
+    async fn delete_session(&self, user: &str, id: &str) -> Result<Option<Session>, ApiError>;
+
+    /// Deletes every session of the account with the ID `user`, except the one with the public ID `keep`, if given.
+    ///
+    /// ## Returns
+    /// * [`Result<Vec<Session>, ApiError>`][`std::result::Result`] - The deleted sessions.
+    async fn delete_sessions(&self, user: &str, keep: Option<&str>) -> Result<Vec<Session>, ApiError>;
+
+    /// Deletes every session last used before `idle_before` or created before `created_before`, across all accounts.
+    ///
+    /// ## Returns
+    /// * [`Result<Vec<Session>, ApiError>`][`std::result::Result`] - The deleted sessions.
+    async fn delete_lapsed_sessions(&self, idle_before: i64, created_before: i64) -> Result<Vec<Session>, ApiError>;
+
+    /// Gets all conversations that the user with the ID `user` is a part of. Messages are stored separately, so the conversations' `messages` are left empty.
+    async fn get_conversations(&self, user: &str) -> Result<Vec<Conversation>, ApiError>;
+
+    /// Gets one conversation with the specified ID, without its messages. Returns `None` if no conversation is found.
+    async fn get_conversation(&self, id: &str) -> Result<Option<Conversation>, ApiError>;
+
+    /// Creates a new conversation entry. Fails with [`ApiError::Conflict`] if the ID is taken.
+    async fn create_conversation(&self, new: &Conversation) -> Result<(), ApiError>;
+
+    /// Replaces the members of a conversation with `users` (their IDs) and moves it to the next key epoch, adding `keys` (the new epoch's keys) to its keys,
+    /// but only if the conversation is still at epoch `from_epoch`. Keys of earlier epochs are kept.
+    /// This must be a single atomic operation, so two concurrent changes can't both build on the same membership and one be lost.
+    ///
+    /// ## Returns
+    /// * [`Result<bool, ApiError>`][`std::result::Result`] - Whether the conversation was changed; false if it doesn't exist or has already moved past `from_epoch`.
+    async fn change_members(&self, id: &str, from_epoch: u32, users: &[String], keys: &[UserKey]) -> Result<bool, ApiError>;
+
+    /// Adds `keys` to the keys of a conversation, e.g. keys of earlier epochs encrypted for a newly added device. Members and epoch are left as they are.
+    ///
+    /// ## Returns
+    /// * [`Result<bool, ApiError>`][`std::result::Result`] - Whether the keys were added; false if the conversation doesn't exist.
+    async fn add_conversation_keys(&self, id: &str, keys: &[UserKey]) -> Result<bool, ApiError>;
+
+    /// Removes every key of a conversation encrypted for the account with the ID `owner` (or any of its devices), of every epoch. Members and epoch are left as they are.
+    ///
+    /// ## Returns
+    /// * [`Result<bool, ApiError>`][`std::result::Result`] - Whether the conversation exists.
+    async fn remove_conversation_keys(&self, id: &str, owner: &str) -> Result<bool, ApiError>;
+
+    /// Deletes a conversation, along with all of its messages.
+    async fn delete_conversation(&self, id: &str) -> Result<(), ApiError>;
+
+    /// Appends a message to the end of the conversation named by its `dest_convo_id`, assigning it the conversation's next sequence number.
+    ///
+    /// This must be a single atomic operation: concurrent appends to one conversation all succeed and each get a distinct, increasing sequence number,
+    /// and the message is only stored if its `sender` is a member of the conversation and its `epoch` is the conversation's, at the moment it is appended.
+    ///
+    /// ## Returns
+    /// * [`Result<Option<EncryptedMessage>, ApiError>`][`std::result::Result`] - The message as stored (sequence number included), or None if the conversation doesn't exist,
+    ///   the sender isn't a part of it or the message was encrypted under another epoch.
+    async fn append_message(&self, message: &EncryptedMessage) -> Result<Option<EncryptedMessage>, ApiError>;
+
+    /// Reads up to `limit` messages of a conversation, in ascending sequence order.
+    ///
+    /// With `after` set, these are the oldest messages following it (and preceding `before`, if that is set too).
+    /// Otherwise they are the newest messages preceding `before`, or the newest in the conversation if neither is set.
+    async fn get_messages(&self, conversation_id: &str, before: Option<i64>, after: Option<i64>, limit: usize) -> Result<Vec<EncryptedMessage>, ApiError>;
+}
@@ -0,0 +1,549 @@
+//! Behaviour every [`Storage`] backend must share. Each test body is written once against `&dyn Storage`
+//! and run against every backend, so the backends can't quietly drift apart.
+
+use std::sync::Arc;
+use super::{memory::MemoryStore, mongo::{MongoConfig, MongoStore}, sqlite::SqliteStore, storage::{Db, Storage}};
+use crate::generics::{errors::ApiError, structs::{Account, Conversation, Device, EncryptedMessage, FriendRequest, KeyType, KeyWrap, RecoveryKey, Session, TwoFactor, UserKey}, utils};
+
+fn account(username: &str) -> Account
+{
+    Account {
+        username: username.to_string(),
+        id: Account::new_id(),
+        hash: String::from("hash"),
+        public_key: vec![1, 2, 3],
+        priv_key_enc: vec![4, 5, 6],
+        nonce: vec![7, 8, 9],
+        friends: Vec::new(),
+        friend_requests: Vec::new(),
+        two_factor: None,
+        key_wrap: None,
+        client_keys: false,
+        key_type: KeyType::Rsa,
+        key_changes: Vec::new(),
+        devices: Vec::new(),
+        recovery_key: None,
+        delete_at: None
+    }
+}
+
+fn session(user_id: &str, created: i64) -> Session
+{
+    Session::new(user_id, "test-agent", "127.0.0.1", created).0
+}
+
+fn message(convo: &str, sender: &str, data: u8) -> EncryptedMessage
+{
+    EncryptedMessage {
+        data: vec![data],
+        nonce: vec![0; 12],
+        sender: sender.to_string(),
+        dest_convo_id: convo.to_string(),
+        id: utils::rand_hex(12),
+        timestamp: utils::now(),
+        ..Default::default()
+    }
+}
+
+async fn accounts_round_trip(db: &dyn Storage)
+{
+    let mut alice = account(&format!("alice-{}", utils::rand_hex(4)));
+    db.create_account(&alice).await.unwrap();
+    assert!(matches!(db.create_account(&alice).await, Err(ApiError::Conflict(_))), "usernames must be unique");
+    assert!(matches!(db.create_account(&account(&alice.username.to_uppercase())).await, Err(ApiError::Conflict(_))), "usernames must be unique ignoring case");
+
+    assert!(matches!(db.create_account(&Account { username: format!("other-{}", utils::rand_hex(4)), ..alice.clone() }).await, Err(ApiError::Conflict(_))), "IDs must be unique");
+
+    let fetched = db.get_account(&alice.username).await.unwrap().expect("account should exist after creation");
+    assert_eq!(fetched.id, alice.id);
+    assert_eq!(fetched.hash, alice.hash);
+    assert_eq!(db.get_account_by_id(&alice.id).await.unwrap().map(|a| a.username), Some(alice.username.clone()));
+    assert!(db.get_account_by_id(&Account::new_id()).await.unwrap().is_none());
+    assert!(matches!(db.update_account(&account("nobody")).await, Err(ApiError::NotFound(_))), "updating an account that doesn't exist is an error");
+    for handle in [&alice.id, &alice.username]
+    { assert_eq!(db.find_account(handle).await.unwrap().map(|a| a.id), Some(alice.id.clone()), "accounts can be found by ID or username") }
+    assert_eq!(fetched.public_key, alice.public_key);
+    assert_eq!(fetched.priv_key_enc, alice.priv_key_enc);
+    assert_eq!(fetched.nonce, alice.nonce);
+
+    alice.friends = vec![String::from("bob"), String::from("carol")];
+    alice.friend_requests = vec![FriendRequest { sender: alice.username.clone(), receiver: String::from("dave"), status: String::from("PENDING") }];
+    db.update_account(&alice).await.unwrap();
+
+    let fetched = db.get_account(&alice.username).await.unwrap().unwrap();
+    assert_eq!(fetched.friends, alice.friends);
+    assert_eq!(fetched.friend_requests, alice.friend_requests);
+    assert!(fetched.two_factor.is_none());
+
+    let mut two_factor = TwoFactor::generate();
+    two_factor.regenerate_recovery_codes();
+    alice.two_factor = Some(two_factor.clone());
+    alice.key_wrap = Some(KeyWrap::generate());
+    db.update_account(&alice).await.unwrap();
+    assert_eq!(db.get_account(&alice.username).await.unwrap().unwrap().key_wrap, alice.key_wrap);
+
+    alice.recovery_key = Some(RecoveryKey { priv_key_enc: vec![1; 48], nonce: vec![2; 12], key_wrap: KeyWrap::generate(), auth_hash: String::from("hash"), created: 1000 });
+    db.update_account(&alice).await.unwrap();
+    assert_eq!(db.get_account(&alice.username).await.unwrap().unwrap().recovery_key, alice.recovery_key);
+    alice.recovery_key = None;
+    db.update_account(&alice).await.unwrap();
+    assert!(db.get_account(&alice.username).await.unwrap().unwrap().recovery_key.is_none(), "removed recovery keys should stay removed");
+
+    let mut bob = account(&format!("bob-{}", utils::rand_hex(4)));
+    bob.client_keys = true;
+    bob.key_type = KeyType::X25519;
+    db.create_account(&bob).await.unwrap();
+    let fetched = db.get_account(&bob.username).await.unwrap().unwrap();
+    assert!(fetched.client_keys);
+    assert_eq!(fetched.key_type, KeyType::X25519);
+    assert!(fetched.key_changes.is_empty());
+
+    bob.replace_public_key(KeyType::X25519, vec![9; 32], 1000);
+    db.update_account(&bob).await.unwrap();
+    let fetched = db.get_account(&bob.username).await.unwrap().unwrap();
+    assert_eq!(fetched.public_key, vec![9; 32]);
+    assert_eq!(fetched.key_changes, bob.key_changes, "the key history should be stored in order");
+    assert_eq!(fetched.key_changes.iter().map(|c| c.timestamp).collect::<Vec<i64>>(), vec![0, 1000]);
+
+    let mut phone = Device::new("phone", KeyType::X25519, vec![3; 32], "session", 2000);
+    phone.approved = true;
+    bob.record_device_key(&phone, 2000);
+    bob.devices = vec![phone, Device::new("laptop", KeyType::Rsa, vec![4; 8], "other session", 3000)];
+    db.update_account(&bob).await.unwrap();
+    let fetched = db.get_account(&bob.username).await.unwrap().unwrap();
+    assert_eq!(fetched.devices, bob.devices, "devices should be stored in order");
+    assert_eq!(fetched.key_changes.last().unwrap().device, bob.devices[0].id);
+    db.delete_account(&bob.id).await.unwrap();
+
+    let fetched = db.get_account(&alice.username).await.unwrap().unwrap().two_factor.expect("two-factor settings should be stored");
+    assert_eq!(fetched.secret, two_factor.secret);
+    assert_eq!(fetched.recovery_codes, two_factor.recovery_codes);
+    assert!(!fetched.confirmed);
+
+    // of two logins that used a code from the same copy, only the first is recorded
+    let used = TwoFactor { last_step: 7, recovery_codes: two_factor.recovery_codes[1..].to_vec(), ..two_factor.clone() };
+    assert!(db.use_two_factor_code(&alice.id, &two_factor, &used).await.unwrap());
+    assert!(!db.use_two_factor_code(&alice.id, &two_factor, &TwoFactor { last_step: 8, ..two_factor.clone() }).await.unwrap());
+    assert!(!db.use_two_factor_code(&bob.id, &two_factor, &used).await.unwrap(), "deleted accounts have no codes to use");
+    let fetched = db.get_account(&alice.username).await.unwrap().unwrap().two_factor.unwrap();
+    assert_eq!((fetched.last_step, fetched.recovery_codes, fetched.secret), (7, used.recovery_codes, two_factor.secret));
+
+    db.delete_account(&alice.id).await.unwrap();
+    assert!(db.get_account(&alice.username).await.unwrap().is_none());
+    assert!(db.get_account("nobody-by-this-name").await.unwrap().is_none());
+}
+
+async fn sessions_round_trip(db: &dyn Storage)
+{
+    let (alice, bob) = (account(&format!("alice-{}", utils::rand_hex(4))), account(&format!("bob-{}", utils::rand_hex(4))));
+    db.create_account(&alice).await.unwrap();
+    db.create_account(&bob).await.unwrap();
+
+    let (phone, desktop, laptop) = (session(&alice.id, 1), session(&alice.id, 2), session(&alice.id, 3));
+    let bobs = session(&bob.id, 1);
+    for s in [&laptop, &phone, &desktop, &bobs] { db.create_session(s).await.unwrap(); }
+
+    // logging in again doesn't replace earlier sessions
+    let ids = |sessions: Vec<Session>| sessions.into_iter().map(|s| s.id).collect::<Vec<String>>();
+    assert_eq!(ids(db.get_sessions(&alice.id).await.unwrap()), vec![phone.id.clone(), desktop.id.clone(), laptop.id.clone()]);
+    assert_eq!(db.get_session(&desktop.token_hash).await.unwrap().expect("session should exist").user_id, alice.id);
+    assert!(db.get_session("no-such-token").await.unwrap().is_none());
+
+    db.touch_session(&desktop.token_hash, 42).await.unwrap();
+    assert_eq!(db.get_session(&desktop.token_hash).await.unwrap().unwrap().last_seen, 42);
+
+    // a refresh token works exactly once, and takes the old access token with it
+    let found = db.get_session_by_refresh(&desktop.refresh_hash).await.unwrap().expect("session should be found by refresh token");
+    assert_eq!(found.id, desktop.id);
+    let (rotated, _) = found.rotated(50);
+    assert!(db.rotate_session(&desktop.refresh_hash, &rotated).await.unwrap());
+    assert!(!db.rotate_session(&desktop.refresh_hash, &found.rotated(60).0).await.unwrap(), "a used refresh token must not rotate again");
+    assert!(db.get_session(&desktop.token_hash).await.unwrap().is_none());
+    assert!(db.get_session_by_refresh(&desktop.refresh_hash).await.unwrap().is_none());
+    let fetched = db.get_session(&rotated.token_hash).await.unwrap().expect("session should be found by its new token");
+    assert_eq!((fetched.id, fetched.refresh_hash, fetched.expires, fetched.last_seen, fetched.created), (desktop.id.clone(), rotated.refresh_hash.clone(), rotated.expires, 50, 2));
+    let desktop = rotated;
+
+    // sessions can only be revoked by their owner
+    assert!(db.delete_session(&bob.id, &phone.id).await.unwrap().is_none());
+    assert_eq!(db.delete_session(&alice.id, &phone.id).await.unwrap().map(|s| s.token_hash), Some(phone.token_hash.clone()));
+    assert!(db.get_session(&phone.token_hash).await.unwrap().is_none());
+
+    assert_eq!(ids(db.delete_sessions(&alice.id, Some(&desktop.id)).await.unwrap()), vec![laptop.id.clone()]);
+    assert_eq!(ids(db.get_sessions(&alice.id).await.unwrap()), vec![desktop.id.clone()]);
+
+    // deleting an account takes its sessions with it, and leaves everyone else's alone
+    db.delete_account(&alice.id).await.unwrap();
+    assert!(db.get_session(&desktop.token_hash).await.unwrap().is_none());
+    assert!(db.get_session(&bobs.token_hash).await.unwrap().is_some());
+
+    // only the keyed hash of a token is stored, so the token itself finds nothing
+    let (carol, tokens) = Session::new(&bob.id, "test-agent", "127.0.0.1", 100);
+    db.create_session(&carol).await.unwrap();
+    assert_eq!(db.get_session(&utils::hash_token(&tokens.token)).await.unwrap().map(|s| s.id), Some(carol.id.clone()));
+    assert!(db.get_session(&tokens.token).await.unwrap().is_none());
+    assert!(db.get_session_by_refresh(&tokens.refresh_token).await.unwrap().is_none());
+    db.delete_session(&bob.id, &carol.id).await.unwrap();
+
+    // sweeping takes sessions that went unused or are too old, whoever they belong to
+    let idle = Session { last_seen: 5, ..session(&bob.id, 100) };
+    let (old, fresh) = (Session { last_seen: 100, ..session(&bob.id, 20) }, session(&bob.id, 100));
+    for s in [&idle, &old, &fresh] { db.create_session(s).await.unwrap(); }
+    let mut swept = ids(db.delete_lapsed_sessions(10, 0).await.unwrap());
+    swept.sort();
+    let mut expected = vec![bobs.id.clone(), idle.id.clone()];
+    expected.sort();
+    assert_eq!(swept, expected);
+    assert_eq!(ids(db.delete_lapsed_sessions(0, 50).await.unwrap()), vec![old.id.clone()]);
+    assert_eq!(ids(db.delete_sessions(&bob.id, None).await.unwrap()), vec![fresh.id.clone()]);
+}
+
+async fn conversations_round_trip(db: &dyn Storage)
+{
+    let (alice, bob) = (format!("alice-{}", utils::rand_hex(4)), format!("bob-{}", utils::rand_hex(4)));
+    let convo = Conversation {
+        id: utils::rand_hex(8),
+        users: vec![alice.clone(), bob.clone()],
+        keys: vec![UserKey { owner: alice.clone(), key: vec![1; 4], key_type: KeyType::Rsa, epoch: 0, device: String::new() }, UserKey { owner: bob.clone(), key: vec![2; 4], key_type: KeyType::X25519, epoch: 0, device: String::from("phone") }],
+        messages: Vec::new(),
+        epoch: 0
+    };
+    db.create_conversation(&convo).await.unwrap();
+    assert!(matches!(db.create_conversation(&convo).await, Err(ApiError::Conflict(_))), "conversation IDs must be unique");
+
+    let fetched = db.get_conversation(&convo.id).await.unwrap().expect("conversation should exist after creation");
+    assert_eq!(fetched.users, convo.users);
+    let keys = |keys: &[UserKey]| keys.iter().map(|k| (k.owner.clone(), k.key.clone(), k.key_type, k.device.clone())).collect::<Vec<_>>();
+    assert_eq!(keys(&fetched.keys), keys(&convo.keys));
+    assert!(fetched.messages.is_empty());
+
+    let for_bob = db.get_conversations(&bob).await.unwrap();
+    assert_eq!(for_bob.len(), 1);
+    assert_eq!(for_bob[0].id, convo.id);
+    assert!(db.get_conversations("nobody-by-this-name").await.unwrap().is_empty());
+    assert!(db.get_conversation("no-such-convo").await.unwrap().is_none());
+
+    let first = db.append_message(&message(&convo.id, &alice, 1)).await.unwrap().expect("conversation exists");
+    let second = db.append_message(&message(&convo.id, &bob, 2)).await.unwrap().expect("conversation exists");
+    assert_eq!((first.seq, second.seq), (1, 2));
+
+    let messages = db.get_messages(&convo.id, None, None, 10).await.unwrap();
+    assert_eq!(messages.iter().map(|m| (m.sender.as_str(), m.data[0], m.seq)).collect::<Vec<_>>(), vec![(alice.as_str(), 1, 1), (bob.as_str(), 2, 2)]);
+    assert_eq!(messages[0].id, first.id);
+    assert_eq!(messages[0].timestamp, first.timestamp);
+    assert_eq!(messages[0].dest_convo_id, convo.id);
+
+    // messages aren't embedded in the conversation itself
+    assert!(db.get_conversation(&convo.id).await.unwrap().unwrap().messages.is_empty());
+
+    assert!(db.append_message(&message("no-such-convo", &alice, 3)).await.unwrap().is_none());
+    assert!(db.append_message(&message(&convo.id, "not-a-member", 3)).await.unwrap().is_none());
+    assert!(db.get_messages("no-such-convo", None, None, 10).await.unwrap().is_empty());
+}
+
+async fn key_epochs(db: &dyn Storage)
+{
+    let key = |owner: &str, epoch: u32| UserKey { owner: owner.to_string(), key: vec![epoch as u8; 4], key_type: KeyType::X25519, epoch, device: String::new() };
+    let users = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<String>>();
+    let convo = Conversation { id: utils::rand_hex(8), users: users(&["alice", "bob"]), keys: vec![key("alice", 0), key("bob", 0)], ..Default::default() };
+    db.create_conversation(&convo).await.unwrap();
+    db.append_message(&message(&convo.id, "bob", 1)).await.unwrap().expect("bob is a member at epoch 0");
+
+    // bob leaves, carol joins
+    assert!(db.change_members(&convo.id, 0, &users(&["alice", "carol"]), &[key("alice", 1), key("carol", 1)]).await.unwrap());
+    assert!(!db.change_members(&convo.id, 0, &users(&["alice"]), &[key("alice", 1)]).await.unwrap(), "changes must build on the current epoch");
+    assert!(!db.change_members("no-such-convo", 0, &users(&["alice"]), &[]).await.unwrap());
+
+    let fetched = db.get_conversation(&convo.id).await.unwrap().unwrap();
+    assert_eq!(fetched.epoch, 1);
+    assert_eq!(fetched.users, users(&["alice", "carol"]));
+    let keys = fetched.keys.iter().map(|k| (k.owner.as_str(), k.epoch, k.key[0])).collect::<Vec<_>>();
+    assert_eq!(keys, vec![("alice", 0, 0), ("bob", 0, 0), ("alice", 1, 1), ("carol", 1, 1)], "keys of earlier epochs are kept");
+    assert!(db.get_conversations("bob").await.unwrap().iter().all(|c| c.id != convo.id));
+
+    assert!(db.append_message(&message(&convo.id, "bob", 2)).await.unwrap().is_none(), "removed members can't send");
+    assert!(db.append_message(&message(&convo.id, "carol", 2)).await.unwrap().is_none(), "messages under an old epoch are refused");
+    let sent = db.append_message(&EncryptedMessage { epoch: 1, ..message(&convo.id, "carol", 2) }).await.unwrap().expect("carol is a member at epoch 1");
+    assert_eq!(sent.seq, 2);
+
+    let epochs = db.get_messages(&convo.id, None, None, 10).await.unwrap().iter().map(|m| m.epoch).collect::<Vec<u32>>();
+    assert_eq!(epochs, vec![0, 1]);
+
+    let for_phone = UserKey { device: String::from("phone"), ..key("alice", 0) };
+    assert!(db.add_conversation_keys(&convo.id, &[for_phone]).await.unwrap());
+    assert!(!db.add_conversation_keys("no-such-convo", &[]).await.unwrap());
+    let fetched = db.get_conversation(&convo.id).await.unwrap().unwrap();
+    assert_eq!(fetched.epoch, 1, "adding keys doesn't change the epoch");
+    let devices = fetched.keys.iter().map(|k| (k.owner.as_str(), k.epoch, k.device.as_str())).collect::<Vec<_>>();
+    assert_eq!(devices.last(), Some(&("alice", 0, "phone")));
+}
+
+async fn deletions(db: &dyn Storage)
+{
+    let mut alice = account(&format!("alice-{}", utils::rand_hex(4)));
+    let bob = account(&format!("bob-{}", utils::rand_hex(4)));
+    db.create_account(&alice).await.unwrap();
+    db.create_account(&bob).await.unwrap();
+
+    alice.delete_at = Some(1000);
+    db.update_account(&alice).await.unwrap();
+    assert_eq!(db.get_account(&alice.username).await.unwrap().unwrap().delete_at, Some(1000));
+    assert!(db.get_accounts_due_for_deletion(999).await.unwrap().iter().all(|id| *id != alice.id));
+    let due = db.get_accounts_due_for_deletion(1000).await.unwrap();
+    assert!(due.contains(&alice.id) && !due.contains(&bob.id));
+
+    db.delete_account(&alice.id).await.unwrap();
+    assert!(matches!(db.create_account(&alice).await, Err(ApiError::Conflict(_))), "deleted usernames can't be registered again");
+    assert!(matches!(db.create_account(&account(&alice.username.to_uppercase())).await, Err(ApiError::Conflict(_))), "not even in another case");
+    db.delete_account(&alice.id).await.unwrap();
+
+    let key = |owner: &str| UserKey { owner: owner.to_string(), key: vec![1; 4], ..UserKey::default() };
+    let convo = Conversation { id: utils::rand_hex(8), users: vec![alice.id.clone(), bob.id.clone()], keys: vec![key(&alice.id), key(&bob.id)], ..Default::default() };
+    db.create_conversation(&convo).await.unwrap();
+    db.append_message(&message(&convo.id, &bob.id, 1)).await.unwrap();
+    assert!(db.remove_conversation_keys(&convo.id, &alice.id).await.unwrap());
+    assert!(!db.remove_conversation_keys("no-such-convo", &alice.id).await.unwrap());
+    let owners = db.get_conversation(&convo.id).await.unwrap().unwrap().keys.into_iter().map(|k| k.owner).collect::<Vec<String>>();
+    assert_eq!(owners, vec![bob.id.clone()]);
+
+    db.delete_conversation(&convo.id).await.unwrap();
+    assert!(db.get_conversation(&convo.id).await.unwrap().is_none());
+    assert!(db.get_messages(&convo.id, None, None, 10).await.unwrap().is_empty(), "messages go with their conversation");
+    db.delete_account(&bob.id).await.unwrap();
+}
+
+async fn renames(db: &dyn Storage)
+{
+    let mut alice = account(&format!("alice-{}", utils::rand_hex(4)));
+    let mut bob = account(&format!("bob-{}", utils::rand_hex(4)));
+    let mut carol = account(&format!("carol-{}", utils::rand_hex(4)));
+    let request = FriendRequest { sender: alice.id.clone(), receiver: carol.id.clone(), status: String::from("PENDING") };
+    (alice.friends, bob.friends) = (vec![bob.id.clone()], vec![alice.id.clone()]);
+    (alice.friend_requests, carol.friend_requests) = (vec![request.clone()], vec![request]);
+    for account in [&alice, &bob, &carol] { db.create_account(account).await.unwrap(); }
+    let alices = session(&alice.id, utils::now());
+    db.create_session(&alices).await.unwrap();
+
+    let key = |owner: &str| UserKey { owner: owner.to_string(), key: vec![1; 4], ..UserKey::default() };
+    let convo = Conversation { id: utils::rand_hex(8), users: vec![alice.id.clone(), bob.id.clone()], keys: vec![key(&alice.id), key(&bob.id)], ..Default::default() };
+    db.create_conversation(&convo).await.unwrap();
+    db.append_message(&message(&convo.id, &alice.id, 1)).await.unwrap();
+
+    let new = format!("alicia-{}", utils::rand_hex(4));
+    assert!(matches!(db.rename_account(&alice.id, &bob.username).await, Err(ApiError::Conflict(_))), "taken usernames can't be renamed to");
+    assert!(matches!(db.rename_account(&alice.id, &bob.username.to_uppercase()).await, Err(ApiError::Conflict(_))), "not even in another case");
+    assert!(!db.rename_account(&Account::new_id(), &new).await.unwrap());
+    assert!(db.rename_account(&alice.id, &new).await.unwrap());
+
+    assert!(db.get_account(&alice.username).await.unwrap().is_none());
+    let renamed = db.get_account(&new).await.unwrap().unwrap();
+    assert_eq!(renamed.id, alice.id, "the ID stays the same");
+    assert_eq!(db.get_account_by_id(&alice.id).await.unwrap().map(|a| a.username), Some(new.clone()));
+    // everything else refers to the account by ID, so is left as it was
+    assert_eq!((renamed.friends, renamed.friend_requests), (alice.friends.clone(), alice.friend_requests.clone()));
+    assert_eq!(db.get_account(&bob.username).await.unwrap().unwrap().friends, vec![alice.id.clone()]);
+    assert_eq!(db.get_account(&carol.username).await.unwrap().unwrap().friend_requests[0].sender, alice.id);
+    let convo = db.get_conversation(&convo.id).await.unwrap().unwrap();
+    assert_eq!((convo.users.clone(), convo.keys.len()), (vec![alice.id.clone(), bob.id.clone()], 2));
+    assert_eq!(db.get_messages(&convo.id, None, None, 10).await.unwrap()[0].sender, alice.id);
+    assert_eq!(db.get_conversations(&alice.id).await.unwrap().len(), 1);
+    assert_eq!(db.get_session(&alices.token_hash).await.unwrap().map(|s| s.user_id), Some(alice.id.clone()));
+    assert_eq!(db.get_sessions(&alice.id).await.unwrap().into_iter().map(|s| s.id).collect::<Vec<String>>(), vec![alices.id]);
+
+    // a write from a copy read before the rename still lands, and doesn't undo the rename
+    let stale = Account { hash: String::from("new hash"), friends: Vec::new(), ..alice.clone() };
+    db.update_account(&stale).await.unwrap();
+    assert!(db.get_account(&alice.username).await.unwrap().is_none());
+    let renamed = db.get_account(&new).await.unwrap().unwrap();
+    assert_eq!((renamed.username, renamed.hash, renamed.friends), (new.clone(), stale.hash, Vec::new()));
+
+    assert!(matches!(db.create_account(&account(&alice.username)).await, Err(ApiError::Conflict(_))), "the old username is left as a tombstone");
+    assert!(matches!(db.rename_account(&bob.id, &alice.username).await, Err(ApiError::Conflict(_))), "and can't be renamed to either");
+    assert!(db.rename_account(&alice.id, &new.to_uppercase()).await.unwrap(), "changing only the case is fine");
+    assert!(db.get_account(&new.to_uppercase()).await.unwrap().is_some());
+
+    for id in [alice.id, bob.id, carol.id] { db.delete_account(&id).await.unwrap(); }
+}
+
+async fn message_history_pages(db: &dyn Storage)
+{
+    let convo = Conversation { id: utils::rand_hex(8), users: vec![String::from("alice")], keys: Vec::new(), messages: Vec::new(), epoch: 0 };
+    db.create_conversation(&convo).await.unwrap();
+    for i in 1..=10 { db.append_message(&message(&convo.id, "alice", i)).await.unwrap(); }
+
+    let seqs = |messages: Vec<EncryptedMessage>| messages.iter().map(|m| m.seq).collect::<Vec<i64>>();
+    // newest page first, always returned oldest-first
+    assert_eq!(seqs(db.get_messages(&convo.id, None, None, 3).await.unwrap()), vec![8, 9, 10]);
+    assert_eq!(seqs(db.get_messages(&convo.id, Some(8), None, 3).await.unwrap()), vec![5, 6, 7]);
+    assert_eq!(seqs(db.get_messages(&convo.id, Some(3), None, 3).await.unwrap()), vec![1, 2]);
+    assert_eq!(seqs(db.get_messages(&convo.id, None, Some(2), 3).await.unwrap()), vec![3, 4, 5]);
+    assert_eq!(seqs(db.get_messages(&convo.id, Some(6), Some(2), 10).await.unwrap()), vec![3, 4, 5]);
+    assert!(db.get_messages(&convo.id, None, Some(10), 3).await.unwrap().is_empty());
+}
+
+async fn concurrent_appends(db: Db)
+{
+    let convo = Conversation { id: utils::rand_hex(8), users: vec![String::from("alice"), String::from("bob")], keys: Vec::new(), messages: Vec::new(), epoch: 0 };
+    db.create_conversation(&convo).await.unwrap();
+
+    let senders = (0..20_u8).map(|i| {
+        let (db, msg) = (Arc::clone(&db), message(&convo.id, if i % 2 == 0 { "alice" } else { "bob" }, i));
+        tokio::spawn(async move { db.append_message(&msg).await.unwrap().unwrap().seq })
+    });
+    let mut acked: Vec<i64> = futures::future::join_all(senders).await.into_iter().map(|r| r.unwrap()).collect();
+    acked.sort();
+    acked.dedup();
+    assert_eq!(acked.len(), 20, "every append must get its own sequence number");
+
+    let stored = db.get_messages(&convo.id, None, None, 50).await.unwrap();
+    assert_eq!(stored.len(), 20, "no append may be lost");
+    assert_eq!(stored.iter().map(|m| m.seq).collect::<Vec<i64>>(), acked);
+}
+
+async fn backends() -> Vec<Db>
+{
+    let mut backends: Vec<Db> = vec![Arc::new(MemoryStore::default()), Arc::new(SqliteStore::open(":memory:").unwrap())];
+    // MongoDB needs a live server, so it only joins the suite when one is provided.
+    if let Ok(uri) = std::env::var("TEST_MONGO_URI")
+    {
+        let config = MongoConfig {
+            uri,
+            db_name: format!("crim-test-{}", utils::rand_hex(4)),
+            max_pool_size: 4,
+            min_pool_size: 0,
+            connect_timeout: std::time::Duration::from_secs(5),
+            server_selection_timeout: std::time::Duration::from_secs(5),
+            health_check_interval: std::time::Duration::from_secs(60)
+        };
+        backends.push(Arc::new(MongoStore::connect(config).await.unwrap()));
+    }
+    backends
+}
+
+#[tokio::test]
+async fn accounts_behave_the_same_on_every_backend()
+{
+    for db in backends().await { accounts_round_trip(db.as_ref()).await; }
+}
+
+#[tokio::test]
+async fn sessions_behave_the_same_on_every_backend()
+{
+    for db in backends().await { sessions_round_trip(db.as_ref()).await; }
+}
+
+#[tokio::test]
+async fn conversations_behave_the_same_on_every_backend()
+{
+    for db in backends().await { conversations_round_trip(db.as_ref()).await; }
+}
+
+#[tokio::test]
+async fn key_epochs_behave_the_same_on_every_backend()
+{
+    for db in backends().await { key_epochs(db.as_ref()).await; }
+}
+
+#[tokio::test]
+async fn deletions_behave_the_same_on_every_backend()
+{
+    for db in backends().await { deletions(db.as_ref()).await; }
+}
+
+#[tokio::test]
+async fn renames_behave_the_same_on_every_backend()
+{
+    for db in backends().await { renames(db.as_ref()).await; }
+}
+
+#[tokio::test]
+async fn message_history_pages_the_same_on_every_backend()
+{
+    for db in backends().await { message_history_pages(db.as_ref()).await; }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_appends_are_never_lost_on_any_backend()
+{
+    for db in backends().await { concurrent_appends(db).await; }
+}
+
+#[test]
+fn documents_store_bytes_as_binary_and_still_read_legacy_arrays()
+{
+    use mongodb::bson::{doc, Bson};
+
+    let stored = message("convo", "alice", 7).to_document().unwrap();
+    assert!(matches!(stored.get("data"), Some(Bson::Binary(_))));
+    assert!(stored.get("sender_sid").is_none(), "the sender's SID must never be stored");
+
+    // documents written before byte fields were stored as Binary hold arrays of i32s
+    let legacy = doc! {
+        "username": "legacy", "hash": "hash", "public_key": [1, 2, 3], "priv_key_enc": [4, 5], "nonce": [6],
+        "friends": ["bob"], "friend_requests": [], "session_id": "sid"
+    };
+    let account = Account::from_document(legacy).unwrap();
+    assert_eq!((account.public_key, account.priv_key_enc, account.nonce), (vec![1, 2, 3], vec![4, 5], vec![6]));
+
+    let legacy = doc! { "id": "c", "users": ["alice"], "keys": [{ "owner": "alice", "key": [9, 8] }], "messages": [{ "data": [1], "nonce": [2], "sender": "alice" }] };
+    let convo = Conversation::from_document(legacy).unwrap();
+    assert_eq!(convo.keys[0].key, vec![9, 8]);
+    assert_eq!(convo.messages[0].data, vec![1]);
+    assert!(matches!(convo.to_document().unwrap().get_array("keys").unwrap()[0].as_document().unwrap().get("key"), Some(Bson::Binary(_))));
+
+    // a malformed document is an error, not a panic
+    assert!(Account::from_document(doc! { "username": "broken" }).is_err());
+    assert!(EncryptedMessage::from_document(doc! { "data": 5, "nonce": [], "sender": "alice" }).is_err());
+}
+
+#[tokio::test]
+async fn sqlite_persists_across_reopens()
+{
+    let path = std::env::temp_dir().join(format!("crim-test-{}.db", utils::rand_hex(4)));
+    let path = path.to_str().unwrap();
+    {
+        let db = SqliteStore::open(path).unwrap();
+        db.create_account(&account("persisted")).await.unwrap();
+    }
+    // reopening runs the migrations again, which must be a no-op on an up-to-date database
+    let db = SqliteStore::open(path).unwrap();
+    assert!(db.get_account("persisted").await.unwrap().is_some());
+
+    for suffix in ["", "-wal", "-shm"] { std::fs::remove_file(format!("{path}{suffix}")).ok(); }
+}
+
+#[tokio::test]
+async fn sqlite_migration_refers_to_accounts_by_id()
+{
+    let path = std::env::temp_dir().join(format!("crim-test-{}.db", utils::rand_hex(4)));
+    let path = path.to_str().unwrap();
+    {
+        // a database from before account IDs, where everything refers to accounts by username
+        let conn = rusqlite::Connection::open(path).unwrap();
+        for migration in &super::sqlite::MIGRATIONS[..16] { conn.execute_batch(migration).unwrap(); }
+        conn.pragma_update(None, "user_version", 16).unwrap();
+        conn.execute_batch(
+            "INSERT INTO accounts (username, hash, public_key, priv_key_enc, nonce) VALUES ('alice', 'h', x'01', x'02', x'03'), ('bob', 'h', x'01', x'02', x'03');
+            INSERT INTO friends (username, position, friend) VALUES ('alice', 0, 'bob'), ('bob', 0, 'alice'), ('bob', 1, 'gone');
+            INSERT INTO friend_requests (username, position, sender, receiver, status) VALUES ('alice', 0, 'alice', 'bob', 'PENDING');
+            INSERT INTO conversations (id) VALUES ('c');
+            INSERT INTO conversation_users (conversation_id, position, username) VALUES ('c', 0, 'alice'), ('c', 1, 'bob');
+            INSERT INTO conversation_keys (conversation_id, position, owner, key) VALUES ('c', 0, 'alice', x'09');
+            INSERT INTO messages (conversation_id, seq, sender, data, nonce, id) VALUES ('c', 1, 'bob', x'01', x'02', 'm');
+            INSERT INTO sessions (id, token_hash, username, device, ip, created, last_seen, refresh_hash, expires) VALUES ('s', 't', 'alice', 'd', 'i', 1, 1, 'r', 9);"
+        ).unwrap();
+    }
+    let db = SqliteStore::open(path).unwrap();
+    let (alice, bob) = (db.get_account("alice").await.unwrap().unwrap(), db.get_account("bob").await.unwrap().unwrap());
+    assert!(Account::looks_like_id(&alice.id) && Account::looks_like_id(&bob.id) && alice.id != bob.id);
+    assert_eq!(alice.friends, vec![bob.id.clone()]);
+    assert_eq!(bob.friends, vec![alice.id.clone(), String::from("gone")], "references to accounts that no longer exist are left alone");
+    assert_eq!((alice.friend_requests[0].sender.clone(), alice.friend_requests[0].receiver.clone()), (alice.id.clone(), bob.id.clone()));
+    let convo = db.get_conversations(&bob.id).await.unwrap().remove(0);
+    assert_eq!(convo.users, vec![alice.id.clone(), bob.id.clone()]);
+    assert_eq!(convo.keys[0].owner, alice.id);
+    assert_eq!(db.get_messages("c", None, None, 10).await.unwrap()[0].sender, bob.id);
+    assert_eq!(db.get_session("t").await.unwrap().map(|s| (s.id, s.user_id)), Some((String::from("s"), alice.id.clone())));
+
+    for suffix in ["", "-wal", "-shm"] { std::fs::remove_file(format!("{path}{suffix}")).ok(); }
+}
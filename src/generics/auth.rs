@@ -0,0 +1,82 @@
+use std::net::IpAddr;
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts, RequestPartsExt};
+use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
+use super::{errors::ApiError, rate_limit::{Key, Limit}, structs::{Account, AppState, Session}, utils};
+
+//----------------------------------------------//
+//                                              //
+//          Bearer-token authentication         //
+//                                              //
+//----------------------------------------------//
+
+/// The account a request was authenticated as. Taking this as a handler argument makes the route protected:
+/// the request must carry an `Authorization: Bearer <session ID>` header naming a live session with an unexpired access token, or it is rejected
+/// with [`ApiError::Unauthorized`] (or [`ApiError::Expired`]) before the handler runs.
+///
+/// Requests made this way also count against the account's HTTP rate limit, on top of the per-IP one every request counts against.
+///
+/// Keeping the session ID in a header (rather than the path or body) keeps it out of URLs and access logs.
+///
+/// ## Fields
+/// * [`account`][`Account`] - The account the session belongs to.
+/// * [`session`][`Session`] - The session the request was made with.
+pub struct Authenticated
+{
+    pub account: Account,
+    pub session: Session
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Authenticated
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection>
+    {
+        let Ok(TypedHeader(Authorization(bearer))) = parts.extract::<TypedHeader<Authorization<Bearer>>>().await
+        else { return Err(ApiError::Unauthorized(String::from("Missing or malformed Authorization header."))) };
+
+        let mut session = utils::authenticate(state.db.as_ref(), bearer.token()).await?;
+
+        let Some(account) = state.db.get_account_by_id(&session.user_id).await?
+        else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
+
+        let now = utils::now();
+        state.limiter.take(Limit::Http, &[Key::Account(account.id.clone())], now)?;
+        if now - session.last_seen >= Session::TOUCH_INTERVAL
+        {
+            state.db.touch_session(&session.token_hash, now).await?;
+            session.last_seen = now;
+        }
+
+        Ok(Authenticated { account, session })
+    }
+}
+
+/// Hashes a password for storing as [`Account::hash`], with a fresh random salt.
+pub fn hash_password(password: &str) -> Result<String, ApiError>
+{
+    let salt = utils::rand_hex(32);
+    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &argon2::Config::default())
+        .map_err(|_| ApiError::Crypto(String::from("Failed to hash the password.")))
+}
+
+/// Checks the password of an account that is already logged in, before letting it change its security settings.
+/// Wrong passwords count towards the same lockout as failed logins (see [`LoginThrottle`][`super::throttle::LoginThrottle`]),
+/// so a stolen session can't be used to guess the password.
+///
+/// ## Returns
+/// * [`Result<(), ApiError>`][`std::result::Result`] - Ok if the password is right, [`ApiError::Unauthorized`] if it isn't, or [`ApiError::RateLimited`] during a lockout.
+pub fn check_password(state: &AppState, account: &Account, password: &str, ip: IpAddr) -> Result<(), ApiError>
+{
+    state.logins.check(&account.id, ip, utils::now())?;
+
+    let Ok(true) = argon2::verify_encoded(&account.hash, password.as_bytes()) // doesn't check for an Argon2 error
+    else
+    {
+        state.logins.failure(&account.id, ip, utils::now());
+        return Err(ApiError::Unauthorized(String::from("Invalid password.")))
+    };
+    state.logins.success(&account.id);
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use super::{errors::ApiError, structs::{Account, Conversation, Device, KeyType, UserKey}, utils};
+
+//----------------------------------------------//
+//                                              //
+//            Per-device key pairs              //
+//                                              //
+//----------------------------------------------//
+
+impl Device
+{
+    /// How long a device may wait to be approved, in milliseconds.
+    pub const PENDING_TTL: i64 = 10 * 60 * 1000;
+
+    /// A device waiting to be approved, signed in with the session `session`.
+    pub fn new(name: &str, key_type: KeyType, public_key: Vec<u8>, session: &str, now: i64) -> Device
+    {
+        Device { id: utils::rand_hex(8), name: name.to_string(), key_type, public_key, added: now, approved: false, session: session.to_string() }
+    }
+
+    /// What the device must answer a sign-in challenge for `session` with: a keyed hash of the device and session, so it needs no storing
+    /// and is worthless for any other session.
+    fn challenge_answer(&self, session: &str) -> String
+    {
+        utils::hash_token(&format!("device sign-in\0{}\0{session}\0{}", self.id, hex::encode(&self.public_key)))
+    }
+
+    /// A sign-in challenge for `session`: the answer, encrypted for the device's key, so only the device can produce it.
+    pub fn challenge(&self, session: &str) -> Result<Vec<u8>, ApiError>
+    {
+        UserKey::seal(self.key_type, &self.public_key, self.challenge_answer(session).as_bytes(), &self.name)
+    }
+
+    /// Whether `response` is the answer to the device's sign-in challenge for `session`.
+    pub fn check_challenge(&self, session: &str, response: &str) -> bool
+    {
+        self.challenge_answer(session) == response.trim()
+    }
+}
+
+impl Account
+{
+    /// The approved device signed in with the session `session`, if there is one.
+    pub fn device_for_session(&self, session: &str) -> Option<&Device>
+    {
+        self.devices.iter().find(|d| d.approved && d.session == session)
+    }
+
+    /// Drops devices that waited too long to be approved.
+    pub fn prune_pending_devices(&mut self, now: i64)
+    {
+        self.devices.retain(|d| d.approved || now - d.added < Device::PENDING_TTL);
+    }
+}
+
+impl Conversation
+{
+    /// The conversation as the account `user` (an ID) should get it on `device` (empty for none): only the keys that device can decrypt, those for the device
+    /// itself and those for the account's own key.
+    pub fn for_device(mut self, user: &str, device: &str) -> Conversation
+    {
+        self.keys.retain(|k| k.owner == user && (k.device.is_empty() || k.device == device));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::generics::x25519;
+
+    #[test]
+    fn only_the_device_can_answer_its_challenge_and_only_for_its_session()
+    {
+        let (_, public) = x25519::generate();
+        let device = Device::new("phone", KeyType::X25519, public.to_vec(), "session", 0);
+        let answer = device.challenge_answer("session");
+
+        assert!(device.challenge("session").unwrap().len() > answer.len(), "the answer is only handed out encrypted");
+        assert!(device.check_challenge("session", &answer));
+        assert!(!device.check_challenge("other session", &answer));
+        assert!(!device.check_challenge("session", ""));
+    }
+
+    #[test]
+    fn conversations_only_carry_the_keys_a_device_can_use()
+    {
+        let key = |owner: &str, device: &str| UserKey { owner: owner.to_string(), device: device.to_string(), ..UserKey::default() };
+        let convo = Conversation { keys: vec![key("alice", ""), key("alice", "phone"), key("alice", "laptop"), key("bob", "")], ..Conversation::default() };
+
+        let for_phone = convo.for_device("alice", "phone");
+        assert_eq!(for_phone.keys.iter().map(|k| k.device.as_str()).collect::<Vec<_>>(), ["", "phone"]);
+    }
+}
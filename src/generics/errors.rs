@@ -0,0 +1,171 @@
+use std::fmt;
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}, Json};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+//----------------------------------------------//
+//                                              //
+//            Errors returned to clients        //
+//                                              //
+//----------------------------------------------//
+
+/// Every error the API can hand back to a client. The variant decides the [`ErrorCode`] (and HTTP status) the client sees;
+/// the string is a human-readable message that is sent along with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError
+{
+    /// The account, conversation or other resource asked for doesn't exist.
+    NotFound(String),
+    /// The session or credentials are missing or wrong.
+    Unauthorized(String),
+    /// The session or its access token has expired.
+    Expired(String),
+    /// The request clashes with existing data, e.g. a username that is already taken.
+    Conflict(String),
+    /// The request itself is malformed or not allowed.
+    Validation(String),
+    /// Some of the request's fields were rejected, e.g. a username with disallowed characters. Reported with [`ErrorCode::Validation`], along with what is wrong with each field.
+    InvalidFields(String, Vec<FieldError>),
+    /// Too many requests or failed attempts; the client may try again after the given number of seconds.
+    RateLimited(String, u64),
+    /// The storage backend failed.
+    Storage(String),
+    /// Generating, encrypting or decrypting key material failed.
+    Crypto(String)
+}
+
+/// The stable, machine-readable part of an [`ApiError`]. Clients should branch on this rather than on the message.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode
+{
+    NotFound,
+    Unauthorized,
+    Expired,
+    Conflict,
+    Validation,
+    RateLimited,
+    Storage,
+    Crypto
+}
+
+/// Why one field of a request was rejected.
+///
+/// ## Fields
+/// * [`field`][`std::string::String`] - The name of the field, as it appears in the payload.
+/// * [`message`][`std::string::String`] - A human-readable description of what is wrong with it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError
+{
+    pub field: String,
+    pub message: String
+}
+
+impl FieldError
+{
+    pub fn new(field: &str, message: impl Into<String>) -> FieldError
+    {
+        FieldError { field: field.to_string(), message: message.into() }
+    }
+}
+
+/// The JSON body of an error response, and the payload of a [`WSAction::Error`][`super::structs::WSAction::Error`] packet.
+///
+/// ## Fields
+/// * [`code`][`ErrorCode`] - What kind of error occurred.
+/// * [`message`][`std::string::String`] - A human-readable description of the error.
+/// * [`retry_after`][`u64`] - For [`ErrorCode::RateLimited`], how many seconds to wait before trying again. Left out otherwise.
+/// * [`fields`][`FieldError`] - For [`ApiError::InvalidFields`], every field that was rejected and why. Left out otherwise.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorBody
+{
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>
+}
+
+impl ApiError
+{
+    pub fn code(&self) -> ErrorCode
+    {
+        match self
+        {
+            ApiError::NotFound(_) => ErrorCode::NotFound,
+            ApiError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ApiError::Expired(_) => ErrorCode::Expired,
+            ApiError::Conflict(_) => ErrorCode::Conflict,
+            ApiError::Validation(_) | ApiError::InvalidFields(..) => ErrorCode::Validation,
+            ApiError::RateLimited(..) => ErrorCode::RateLimited,
+            ApiError::Storage(_) => ErrorCode::Storage,
+            ApiError::Crypto(_) => ErrorCode::Crypto
+        }
+    }
+
+    pub fn message(&self) -> &str
+    {
+        match self
+        {
+            ApiError::NotFound(m) | ApiError::Unauthorized(m) | ApiError::Expired(m) | ApiError::Conflict(m) | ApiError::Validation(m) | ApiError::InvalidFields(m, _) | ApiError::RateLimited(m, _) | ApiError::Storage(m) | ApiError::Crypto(m) => m
+        }
+    }
+
+    /// The HTTP status this error is answered with.
+    pub fn status(&self) -> StatusCode
+    {
+        match self
+        {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized(_) | ApiError::Expired(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Validation(_) | ApiError::InvalidFields(..) => StatusCode::BAD_REQUEST,
+            ApiError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Storage(_) | ApiError::Crypto(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    /// How many seconds the client should wait before retrying, if the error says so.
+    pub fn retry_after(&self) -> Option<u64>
+    {
+        match self
+        {
+            ApiError::RateLimited(_, secs) => Some(*secs),
+            _ => None
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody
+    {
+        let fields = match self
+        {
+            ApiError::InvalidFields(_, fields) => fields.clone(),
+            _ => Vec::new()
+        };
+        ErrorBody { code: self.code(), message: self.message().to_string(), retry_after: self.retry_after(), fields }
+    }
+}
+
+impl fmt::Display for ApiError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{:?}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError
+{
+    fn into_response(self) -> Response
+    {
+        if self.status().is_server_error() { error!("{self}") }
+        match self.retry_after()
+        {
+            Some(secs) => (self.status(), [(header::RETRY_AFTER, secs.to_string())], Json(self.body())).into_response(),
+            None => (self.status(), Json(self.body())).into_response()
+        }
+    }
+}
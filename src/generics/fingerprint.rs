@@ -0,0 +1,134 @@
+use sha2::{Digest, Sha256, Sha512};
+use super::structs::{Account, Device, KeyChange, KeyType};
+
+//----------------------------------------------//
+//                                              //
+//       Public key fingerprints and safety     //
+//                     numbers                  //
+//                                              //
+//----------------------------------------------//
+
+/// Bumped whenever the way safety numbers are computed changes, so old and new ones can never match by accident.
+const VERSION: &[u8] = &[0, 2];
+/// Rounds of hashing behind each half of a safety number, which makes finding a key with a colliding number that much more expensive.
+const ITERATIONS: usize = 5200;
+/// Each half is 6 groups of 5 digits.
+const GROUPS: usize = 6;
+
+/// The SHA-256 fingerprint of a public key, in hex. Covers the key type too, so the same bytes under another type fingerprint differently.
+pub fn fingerprint(key_type: KeyType, public_key: &[u8]) -> String
+{
+    let mut hasher = Sha256::new();
+    hasher.update(key_type.as_str());
+    hasher.update([0]);
+    hasher.update(public_key);
+    hex::encode(hasher.finalize())
+}
+
+/// One account's half of a safety number: 30 digits derived from its ID and public key, the way Signal's are. The ID (rather than the username)
+/// never changes, so neither does the number unless the key does.
+fn half(account: &Account) -> String
+{
+    let mut digest = Sha512::new()
+        .chain_update(VERSION)
+        .chain_update(&account.public_key)
+        .chain_update(account.id.as_bytes())
+        .chain_update(account.key_type.as_str())
+        .finalize();
+    for _ in 0..ITERATIONS
+    {
+        digest = Sha512::new().chain_update(digest).chain_update(&account.public_key).finalize();
+    }
+
+    digest
+        .chunks(5)
+        .take(GROUPS)
+        .map(|chunk| format!("{:05}", chunk.iter().fold(0_u64, |n, &b| n << 8 | b as u64) % 100_000))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// The safety number of two accounts: 60 digits, in groups of 5, that both users see the same, whichever of them asks.
+/// If the numbers their clients show match when compared in person (or over another channel), neither key was swapped.
+pub fn safety_number(a: &Account, b: &Account) -> String
+{
+    let (first, second) = if a.id <= b.id { (a, b) } else { (b, a) };
+    format!("{} {}", half(first), half(second))
+}
+
+impl KeyChange
+{
+    /// A record of an account's own key becoming `public_key` at `timestamp`.
+    pub fn new(key_type: KeyType, public_key: &[u8], timestamp: i64) -> KeyChange
+    {
+        KeyChange { key_type, fingerprint: fingerprint(key_type, public_key), timestamp, device: String::new() }
+    }
+}
+
+impl Account
+{
+    /// Every public key the account and its devices have had, oldest first; the last one without a `device` is the account's current key.
+    /// Accounts from before key changes were recorded start with their key at that time, with a `timestamp` of 0.
+    pub fn key_history(&self) -> Vec<KeyChange>
+    {
+        if !self.key_changes.is_empty() { return self.key_changes.clone() }
+        vec![KeyChange::new(self.key_type, &self.public_key, 0)]
+    }
+
+    /// Replaces the account's public key, recording the change. The account still has to be stored.
+    pub fn replace_public_key(&mut self, key_type: KeyType, public_key: Vec<u8>, timestamp: i64) -> KeyChange
+    {
+        let change = KeyChange::new(key_type, &public_key, timestamp);
+        self.key_changes = self.key_history();
+        self.key_changes.push(change.clone());
+        (self.key_type, self.public_key) = (key_type, public_key);
+        change
+    }
+
+    /// Records that `device` got its key at `timestamp`. The account still has to be stored.
+    pub fn record_device_key(&mut self, device: &Device, timestamp: i64) -> KeyChange
+    {
+        let change = KeyChange { device: device.id.clone(), ..KeyChange::new(device.key_type, &device.public_key, timestamp) };
+        self.key_changes = self.key_history();
+        self.key_changes.push(change.clone());
+        change
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn safety_numbers_match_on_both_sides_and_change_with_the_key()
+    {
+        let alice = Account { username: String::from("alice"), id: Account::new_id(), public_key: vec![1; 32], key_type: KeyType::X25519, ..Account::default() };
+        let mut bob = Account { username: String::from("bob"), id: Account::new_id(), public_key: vec![2; 32], key_type: KeyType::X25519, ..Account::default() };
+
+        let number = safety_number(&alice, &bob);
+        assert_eq!(number, safety_number(&bob, &alice));
+        assert_eq!(number.split(' ').count(), 2 * GROUPS);
+        assert!(number.split(' ').all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+
+        assert_eq!(bob.key_history().len(), 1);
+        let change = bob.replace_public_key(KeyType::X25519, vec![3; 32], 1000);
+        assert_ne!(safety_number(&alice, &bob), number);
+        assert_eq!(bob.key_history().iter().map(|c| c.timestamp).collect::<Vec<i64>>(), vec![0, 1000]);
+        assert_eq!(bob.key_history().last().map(|c| &c.fingerprint), Some(&change.fingerprint));
+        assert_ne!(fingerprint(KeyType::Rsa, &[3; 32]), change.fingerprint, "the key type is part of the fingerprint");
+    }
+
+    #[test]
+    fn safety_numbers_survive_renames()
+    {
+        let alice = Account { username: String::from("alice"), id: Account::new_id(), public_key: vec![1; 32], key_type: KeyType::X25519, ..Account::default() };
+        let bob = Account { username: String::from("bob"), id: Account::new_id(), public_key: vec![2; 32], key_type: KeyType::X25519, ..Account::default() };
+        let number = safety_number(&alice, &bob);
+
+        // renamed past the other account, which would have swapped the halves if they were ordered by username
+        let renamed = Account { username: String::from("zoe"), ..alice.clone() };
+        assert_eq!(safety_number(&renamed, &bob), number);
+        assert_eq!(safety_number(&bob, &renamed), number);
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::OnceLock;
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng, generic_array::GenericArray}, Aes256Gcm, Key};
+use argon2::{Config, Variant, Version};
+use rand::RngCore;
+use super::{errors::ApiError, structs::{Account, KeyWrap}, utils};
+
+//----------------------------------------------//
+//                                              //
+//       Password-based private key wrapping    //
+//                                              //
+//----------------------------------------------//
+
+/// The salt every account's wrapping key used to be derived with, before each account got its own.
+const LEGACY_SALT: &[u8] = b"00000000";
+const SALT_LENGTH: usize = 16;
+const ALGORITHM: &str = "argon2id";
+
+/// The argon2id parameters new wrapping keys are derived with. Existing accounts keep the parameters stored in their [`KeyWrap`]
+/// until their key is re-wrapped (on their next password change), so these can be raised at any time.
+///
+/// ## Fields
+/// * [`mem_cost`][`u32`] - Memory used, in KiB (`KDF_MEM_COST_KIB`, default 19456).
+/// * [`time_cost`][`u32`] - Number of passes (`KDF_TIME_COST`, default 2).
+/// * [`lanes`][`u32`] - Degree of parallelism (`KDF_LANES`, default 1).
+#[derive(Debug, Clone, Copy)]
+pub struct KdfConfig
+{
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub lanes: u32
+}
+
+impl Default for KdfConfig
+{
+    fn default() -> KdfConfig
+    {
+        KdfConfig { mem_cost: 19 * 1024, time_cost: 2, lanes: 1 }
+    }
+}
+
+impl KdfConfig
+{
+    pub fn from_env() -> KdfConfig
+    {
+        let default = KdfConfig::default();
+        KdfConfig {
+            mem_cost: utils::env_or("KDF_MEM_COST_KIB", default.mem_cost),
+            time_cost: utils::env_or("KDF_TIME_COST", default.time_cost),
+            lanes: utils::env_or("KDF_LANES", default.lanes)
+        }
+    }
+
+    /// The config in effect, read from the environment the first time it is needed.
+    pub fn current() -> &'static KdfConfig
+    {
+        static CONFIG: OnceLock<KdfConfig> = OnceLock::new();
+        CONFIG.get_or_init(KdfConfig::from_env)
+    }
+}
+
+impl KeyWrap
+{
+    /// Parameters for wrapping a key from now on: a fresh random salt and the current [`KdfConfig`].
+    pub fn generate() -> KeyWrap
+    {
+        let mut salt = vec![0; SALT_LENGTH];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let config = KdfConfig::current();
+        KeyWrap { algorithm: String::from(ALGORITHM), salt, mem_cost: config.mem_cost, time_cost: config.time_cost, lanes: config.lanes }
+    }
+
+    /// The parameters of accounts created before keys were wrapped per account: the same fixed salt for everyone.
+    pub fn legacy() -> KeyWrap
+    {
+        let config = Config::default();
+        KeyWrap { algorithm: String::from(ALGORITHM), salt: LEGACY_SALT.to_vec(), mem_cost: config.mem_cost, time_cost: config.time_cost, lanes: config.lanes }
+    }
+
+    /// Derives the AES-256 key that wraps the private key from `password`.
+    pub fn derive_key(&self, password: &str) -> Result<Vec<u8>, ApiError>
+    {
+        let config = Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            hash_length: 32,
+            ..Config::default()
+        };
+        argon2::hash_raw(password.as_bytes(), &self.salt, &config).map_err(|_| ApiError::Crypto(String::from("Failed to derive the account's key.")))
+    }
+
+    /// Encrypts `private_key` under a key derived from `password`.
+    ///
+    /// ## Returns
+    /// * [`Result<(Vec<u8>, Vec<u8>), ApiError>`][`std::result::Result`] - The encrypted key and the nonce it was encrypted with.
+    pub fn wrap(&self, password: &str, private_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ApiError>
+    {
+        let key = self.derive_key(password)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .encrypt(&nonce, private_key)
+            .map_err(|_| ApiError::Crypto(String::from("Failed to encrypt the account's private key.")))?;
+        Ok((wrapped, nonce.to_vec()))
+    }
+
+    /// Decrypts a private key wrapped by [`KeyWrap::wrap`] with the same parameters.
+    pub fn unwrap(&self, password: &str, wrapped: &[u8], nonce: &[u8]) -> Result<Vec<u8>, ApiError>
+    {
+        let key = self.derive_key(password)?;
+        if nonce.len() != 12 { return Err(ApiError::Crypto(String::from("The account's private key has a malformed nonce."))) }
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(GenericArray::from_slice(nonce), wrapped)
+            .map_err(|_| ApiError::Crypto(String::from("Failed to decrypt the account's private key.")))
+    }
+}
+
+impl Account
+{
+    /// The parameters the account's private key is wrapped with.
+    pub fn key_wrap(&self) -> KeyWrap
+    {
+        self.key_wrap.clone().unwrap_or_else(KeyWrap::legacy)
+    }
+
+    /// Re-wraps the account's private key under `new_password` with a fresh salt and the current [`KdfConfig`]. `old_password` must
+    /// be the one it is wrapped under now. The new parameters and encrypted key are set on the account, which still has to be stored.
+    pub fn rewrap_key(&mut self, old_password: &str, new_password: &str) -> Result<(), ApiError>
+    {
+        let private_key = self.key_wrap().unwrap(old_password, &self.priv_key_enc, &self.nonce)?;
+        let key_wrap = KeyWrap::generate();
+        (self.priv_key_enc, self.nonce) = key_wrap.wrap(new_password, &private_key)?;
+        self.key_wrap = Some(key_wrap);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn legacy_keys_are_rewrapped_with_a_salt_of_their_own()
+    {
+        let legacy = KeyWrap::legacy();
+        let (priv_key_enc, nonce) = legacy.wrap("old password", b"private key").unwrap();
+        let mut alice = Account { username: String::from("alice"), priv_key_enc, nonce, ..Account::default() };
+        let mut bob = alice.clone();
+
+        alice.rewrap_key("old password", "new password").unwrap();
+        bob.rewrap_key("old password", "new password").unwrap();
+        let (a, b) = (alice.key_wrap.clone().unwrap(), bob.key_wrap.clone().unwrap());
+        assert_ne!(a.salt, b.salt);
+        assert_ne!(alice.priv_key_enc, bob.priv_key_enc);
+
+        assert_eq!(a.unwrap("new password", &alice.priv_key_enc, &alice.nonce).unwrap(), b"private key");
+        assert!(a.unwrap("old password", &alice.priv_key_enc, &alice.nonce).is_err());
+        assert!(alice.rewrap_key("wrong password", "anything").is_err());
+    }
+}
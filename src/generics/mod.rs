@@ -1,3 +1,14 @@
+pub mod auth;
+pub mod errors;
+pub mod rate_limit;
 pub mod structs;
+pub mod throttle;
+pub mod two_factor;
+pub mod validation;
+pub mod key_wrap;
+pub mod x25519;
+pub mod fingerprint;
+pub mod devices;
+pub mod recovery;
+pub mod user_ids;
 pub mod utils;
-use crate::db::mongo;
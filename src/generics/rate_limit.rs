@@ -0,0 +1,288 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+use axum::{extract::{ConnectInfo, Request, State}, middleware::Next, response::Response};
+use super::{errors::ApiError, structs::{AppState, WSAction}, utils};
+
+//----------------------------------------------//
+//                                              //
+//          Token-bucket rate limiting          //
+//                                              //
+//----------------------------------------------//
+
+/// A token bucket: holds up to `burst` tokens, refills at `per_sec` tokens a second, and every request takes one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate
+{
+    pub burst: f64,
+    pub per_sec: f64
+}
+
+impl Rate
+{
+    fn from_env(name: &str, default: Rate) -> Result<Rate, String>
+    {
+        Rate {
+            burst: utils::env_or(&format!("RATE_LIMIT_{name}_BURST"), default.burst),
+            per_sec: utils::env_or(&format!("RATE_LIMIT_{name}_PER_SEC"), default.per_sec)
+        }.checked(name)
+    }
+
+    /// Refuses buckets that could never let a request through (a burst below 1) or never refill (a rate of 0 or less),
+    /// as the wait for their next token would be endless.
+    fn checked(self, name: &str) -> Result<Rate, String>
+    {
+        if self.burst.is_nan() || self.burst < 1.0 { return Err(format!("RATE_LIMIT_{name}_BURST must be at least 1.")) }
+        if self.per_sec.is_nan() || self.per_sec <= 0.0 { return Err(format!("RATE_LIMIT_{name}_PER_SEC must be more than 0.")) }
+        Ok(self)
+    }
+}
+
+/// Which kind of request is being limited. Each has its own buckets, so e.g. chatting doesn't use up the allowance for adding friends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit
+{
+    /// Every HTTP request.
+    Http,
+    /// Websocket [`WSAction::SendMessage`] packets.
+    Messages,
+    /// Websocket packets that change friends or conversations, each of which costs several database writes.
+    Social,
+    /// Every other websocket packet.
+    Other
+}
+
+impl Limit
+{
+    /// The limit a websocket packet falls under, if any. Disconnecting is never limited.
+    pub fn for_action(action: &WSAction) -> Option<Limit>
+    {
+        match action
+        {
+            WSAction::Disconnect() => None,
+            WSAction::SendMessage(_) => Some(Limit::Messages),
+            WSAction::AddFriend(_) | WSAction::RemoveFriend(_) | WSAction::CreateConversation(_) | WSAction::DeleteConversation(_)
+                | WSAction::AddMembers(_) | WSAction::RemoveMembers(_) => Some(Limit::Social),
+            _ => Some(Limit::Other)
+        }
+    }
+}
+
+/// Who a bucket belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key
+{
+    /// The account's ID, so a rename doesn't hand it a fresh bucket.
+    Account(String),
+    Ip(IpAddr)
+}
+
+/// The rate of every [`Limit`], per account.
+///
+/// ## Fields
+/// * [`http`][`Rate`] - `RATE_LIMIT_HTTP_BURST` and `RATE_LIMIT_HTTP_PER_SEC`, default 60 and 10.
+/// * [`messages`][`Rate`] - `RATE_LIMIT_MESSAGES_BURST` and `RATE_LIMIT_MESSAGES_PER_SEC`, default 30 and 5.
+/// * [`social`][`Rate`] - `RATE_LIMIT_SOCIAL_BURST` and `RATE_LIMIT_SOCIAL_PER_SEC`, default 10 and 0.5.
+/// * [`other`][`Rate`] - `RATE_LIMIT_OTHER_BURST` and `RATE_LIMIT_OTHER_PER_SEC`, default 30 and 5.
+/// * [`ip_factor`][`f64`] - How many times an account's rate an IP gets, as many users can share one address (`RATE_LIMIT_IP_FACTOR`, default 4).
+/// * [`prune_interval`][`std::time::Duration`] - How often buckets that have refilled are dropped (`RATE_LIMIT_PRUNE_INTERVAL_SECS`, default 60).
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig
+{
+    pub http: Rate,
+    pub messages: Rate,
+    pub social: Rate,
+    pub other: Rate,
+    pub ip_factor: f64,
+    pub prune_interval: Duration
+}
+
+impl Default for RateLimitConfig
+{
+    fn default() -> RateLimitConfig
+    {
+        RateLimitConfig {
+            http: Rate { burst: 60.0, per_sec: 10.0 },
+            messages: Rate { burst: 30.0, per_sec: 5.0 },
+            social: Rate { burst: 10.0, per_sec: 0.5 },
+            other: Rate { burst: 30.0, per_sec: 5.0 },
+            ip_factor: 4.0,
+            prune_interval: Duration::from_secs(60)
+        }
+    }
+}
+
+impl RateLimitConfig
+{
+    /// Reads the config from the environment, falling back to the defaults for unset variables.
+    ///
+    /// ## Returns
+    /// * [`Result<RateLimitConfig, String>`][`std::result::Result`] - The config, or why it was refused: a burst below 1, or a rate or IP factor of 0 or less.
+    pub fn from_env() -> Result<RateLimitConfig, String>
+    {
+        let default = RateLimitConfig::default();
+        let ip_factor = utils::env_or("RATE_LIMIT_IP_FACTOR", default.ip_factor);
+        if ip_factor.is_nan() || ip_factor <= 0.0 { return Err(String::from("RATE_LIMIT_IP_FACTOR must be more than 0.")) }
+
+        Ok(RateLimitConfig {
+            http: Rate::from_env("HTTP", default.http)?,
+            messages: Rate::from_env("MESSAGES", default.messages)?,
+            social: Rate::from_env("SOCIAL", default.social)?,
+            other: Rate::from_env("OTHER", default.other)?,
+            ip_factor,
+            prune_interval: Duration::from_secs(utils::env_or("RATE_LIMIT_PRUNE_INTERVAL_SECS", default.prune_interval.as_secs()))
+        })
+    }
+
+    fn rate(&self, limit: Limit, key: &Key) -> Rate
+    {
+        let rate = match limit
+        {
+            Limit::Http => self.http,
+            Limit::Messages => self.messages,
+            Limit::Social => self.social,
+            Limit::Other => self.other
+        };
+        match key
+        {
+            Key::Account(_) => rate,
+            Key::Ip(_) => Rate { burst: rate.burst * self.ip_factor, per_sec: rate.per_sec * self.ip_factor }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket
+{
+    tokens: f64,
+    updated: i64
+}
+
+impl Bucket
+{
+    /// The tokens in the bucket at `now`, counting what has trickled in since it was last updated.
+    fn tokens(&self, rate: Rate, now: i64) -> f64
+    {
+        (self.tokens + (now - self.updated).max(0) as f64 / 1000.0 * rate.per_sec).min(rate.burst)
+    }
+}
+
+/// Token buckets for every [`Limit`], per account and per IP. Buckets live in memory, so they are per process.
+pub struct RateLimiter
+{
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(Limit, Key), Bucket>>
+}
+
+impl RateLimiter
+{
+    pub fn new(config: RateLimitConfig) -> RateLimiter
+    {
+        RateLimiter { config, buckets: Mutex::default() }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig { &self.config }
+
+    /// Takes a token from the bucket of every one of `keys`. Either all of them have one to spare and they are all taken, or none is.
+    ///
+    /// ## Returns
+    /// * [`Result<(), ApiError>`][`std::result::Result`] - Ok if the request may go ahead, or an [`ApiError::RateLimited`] saying when it may be retried.
+    pub fn take(&self, limit: Limit, keys: &[Key], now: i64) -> Result<(), ApiError>
+    {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait: f64 = 0.0;
+        for key in keys
+        {
+            let rate = self.config.rate(limit, key);
+            let tokens = buckets.get(&(limit, key.clone())).map_or(rate.burst, |b| b.tokens(rate, now));
+            if tokens < 1.0 { wait = wait.max((1.0 - tokens) / rate.per_sec); }
+        }
+        if wait > 0.0
+        {
+            let secs = wait.ceil() as u64;
+            return Err(ApiError::RateLimited(format!("Rate limited. Try again in {secs} seconds."), secs));
+        }
+
+        for key in keys
+        {
+            let rate = self.config.rate(limit, key);
+            let bucket = buckets.entry((limit, key.clone())).or_insert(Bucket { tokens: rate.burst, updated: now });
+            *bucket = Bucket { tokens: bucket.tokens(rate, now) - 1.0, updated: now };
+        }
+        Ok(())
+    }
+
+    /// Drops buckets that have refilled completely, as they are no different from a fresh one.
+    pub fn prune(&self, now: i64)
+    {
+        let config = self.config;
+        self.buckets.lock().unwrap().retain(|(limit, key), bucket| bucket.tokens(config.rate(*limit, key), now) < config.rate(*limit, key).burst);
+    }
+}
+
+/// Middleware limiting every HTTP request by the IP it came from. Requests from logged-in accounts are additionally limited per account,
+/// by [`Authenticated`][`super::auth::Authenticated`].
+pub async fn limit_http(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Result<Response, ApiError>
+{
+    state.limiter.take(Limit::Http, &[Key::Ip(addr.ip())], utils::now())?;
+    Ok(next.run(request).await)
+}
+
+/// Every [`RateLimitConfig::prune_interval`], drops buckets that have refilled.
+pub async fn prune(limiter: Arc<RateLimiter>)
+{
+    let mut interval = tokio::time::interval(limiter.config().prune_interval);
+    loop
+    {
+        interval.tick().await;
+        limiter.prune(utils::now());
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill()
+    {
+        let limiter = RateLimiter::new(RateLimitConfig { social: Rate { burst: 2.0, per_sec: 0.5 }, ..RateLimitConfig::default() });
+        let alice = [Key::Account(String::from("alice"))];
+        assert!(limiter.take(Limit::Social, &alice, 0).is_ok());
+        assert!(limiter.take(Limit::Social, &alice, 0).is_ok());
+        assert_eq!(limiter.take(Limit::Social, &alice, 0).unwrap_err().retry_after(), Some(2));
+
+        // other limits and other accounts have buckets of their own
+        assert!(limiter.take(Limit::Messages, &alice, 0).is_ok());
+        assert!(limiter.take(Limit::Social, &[Key::Account(String::from("bob"))], 0).is_ok());
+
+        assert!(limiter.take(Limit::Social, &alice, 2000).is_ok());
+        assert!(limiter.take(Limit::Social, &alice, 2000).is_err());
+    }
+
+    #[test]
+    fn every_key_needs_a_token()
+    {
+        let limiter = RateLimiter::new(RateLimitConfig { other: Rate { burst: 1.0, per_sec: 1.0 }, ip_factor: 2.0, ..RateLimitConfig::default() });
+        let ip = Key::Ip(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+        let (alice, bob) = (Key::Account(String::from("alice")), Key::Account(String::from("bob")));
+
+        assert!(limiter.take(Limit::Other, &[alice.clone(), ip.clone()], 0).is_ok());
+        // alice is out of tokens, so nothing is taken from the IP either
+        assert!(limiter.take(Limit::Other, &[alice.clone(), ip.clone()], 0).is_err());
+        assert!(limiter.take(Limit::Other, &[bob.clone(), ip.clone()], 0).is_ok());
+        assert!(limiter.take(Limit::Other, &[Key::Account(String::from("carol")), ip.clone()], 0).is_err(), "the IP's burst of 2 is used up");
+
+        limiter.prune(10_000);
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn buckets_that_never_refill_are_refused()
+    {
+        assert!(Rate { burst: 1.0, per_sec: 0.1 }.checked("OTHER").is_ok());
+        for rate in [Rate { burst: 0.5, per_sec: 1.0 }, Rate { burst: 10.0, per_sec: 0.0 }, Rate { burst: 10.0, per_sec: -1.0 }, Rate { burst: 10.0, per_sec: f64::NAN }]
+        {
+            assert!(rate.checked("OTHER").is_err(), "{rate:?} should be refused");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use super::{auth, errors::ApiError, key_wrap::KdfConfig, structs::{KeyBackup, KeyWrap, RecoveryKey}, utils};
+
+//----------------------------------------------//
+//                                              //
+//        Private key backup recovery keys      //
+//                                              //
+//----------------------------------------------//
+
+/// The shortest authentication key accepted, in characters. It is derived from the recovery key, so anything shorter is a client bug.
+const MIN_AUTH_KEY_LENGTH: usize = 32;
+const MAX_AUTH_KEY_LENGTH: usize = 256;
+
+impl RecoveryKey
+{
+    /// A recovery key holding `backup`, proven by `auth_key` from now on.
+    pub fn new(backup: KeyBackup, auth_key: &str, now: i64) -> Result<RecoveryKey, ApiError>
+    {
+        Ok(RecoveryKey {
+            priv_key_enc: backup.priv_key_enc,
+            nonce: backup.nonce,
+            key_wrap: backup.key_wrap,
+            auth_hash: auth::hash_password(auth_key)?,
+            created: now
+        })
+    }
+
+    /// Whether `auth_key` is the authentication key the recovery key was set with.
+    pub fn check(&self, auth_key: &str) -> bool
+    {
+        argon2::verify_encoded(&self.auth_hash, auth_key.as_bytes()).unwrap_or(false)
+    }
+
+    /// The encrypted private key, in the shape it was uploaded in.
+    pub fn backup(&self) -> KeyBackup
+    {
+        KeyBackup { priv_key_enc: self.priv_key_enc.clone(), nonce: self.nonce.clone(), key_wrap: self.key_wrap.clone() }
+    }
+
+    /// What is wrong with an authentication key, if anything. Its contents can't be checked, only its length.
+    pub fn check_auth_key(auth_key: &str) -> Option<String>
+    {
+        (!(MIN_AUTH_KEY_LENGTH..=MAX_AUTH_KEY_LENGTH).contains(&auth_key.len()))
+            .then(|| format!("Must be between {MIN_AUTH_KEY_LENGTH} and {MAX_AUTH_KEY_LENGTH} characters long."))
+    }
+
+    /// Made-up parameters for a username without a recovery key, so asking for them doesn't reveal which accounts have one (or exist).
+    /// The salt is a keyed hash of the username, so asking twice gets the same answer.
+    pub fn decoy_params(username: &str) -> KeyWrap
+    {
+        let salt = utils::hash_token(&format!("recovery decoy\0{}", username.to_lowercase()));
+        let config = KdfConfig::current();
+        KeyWrap {
+            algorithm: String::from("argon2id"),
+            salt: hex::decode(&salt[..32]).unwrap_or_default(),
+            mem_cost: config.mem_cost,
+            time_cost: config.time_cost,
+            lanes: config.lanes
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn only_the_authentication_key_unlocks_the_backup()
+    {
+        let backup = KeyBackup { priv_key_enc: vec![1; 48], nonce: vec![2; 12], key_wrap: KeyWrap::generate() };
+        let recovery = RecoveryKey::new(backup.clone(), &"a".repeat(64), 0).unwrap();
+
+        assert!(recovery.check(&"a".repeat(64)));
+        assert!(!recovery.check(&"b".repeat(64)));
+        assert!(!recovery.auth_hash.contains(&"a".repeat(64)), "the authentication key is only stored hashed");
+        assert_eq!(recovery.backup().priv_key_enc, backup.priv_key_enc);
+        assert!(RecoveryKey::check_auth_key("short").is_some());
+    }
+
+    #[test]
+    fn decoy_parameters_look_real_and_stay_the_same()
+    {
+        let decoy = RecoveryKey::decoy_params("Nobody");
+        assert_eq!(decoy, RecoveryKey::decoy_params("nobody"));
+        assert_ne!(decoy.salt, RecoveryKey::decoy_params("somebody").salt);
+        assert_eq!(decoy.salt.len(), KeyWrap::generate().salt.len());
+    }
+}
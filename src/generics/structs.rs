@@ -1,5 +1,5 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use rsa::{pkcs8::DecodePublicKey, rand_core::CryptoRngCore, traits::PublicKeyParts, Pkcs1v15Encrypt};
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::{Arc, OnceLock}, time::Duration};
+use rsa::{pkcs8::DecodePublicKey, Pkcs1v15Encrypt};
 use tokio::sync::Mutex;
 
 //----------------------------------------------//
@@ -7,12 +7,11 @@ use tokio::sync::Mutex;
 //        File for commonly-used structs        //
 //                                              //
 //----------------------------------------------//
-use super::{mongo, utils};
-use mongodb::bson::{self, doc, Document};
+use super::{errors::{ApiError, ErrorBody}, rate_limit::RateLimiter, throttle::LoginThrottle, two_factor::LoginChallenges, utils};
+use crate::db::storage::Db;
+use mongodb::bson::{self, Document};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
-use rand::Rng;
-use aes_gcm::aead::{generic_array, generic_array::typenum};
 
 //----------------------------------------------//
 //                                              //
@@ -24,188 +23,823 @@ use aes_gcm::aead::{generic_array, generic_array::typenum};
 //------------------------------//
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
-
+/// A user account, as stored in the database. Byte fields are stored as BSON Binary.
+///
+/// `id` is a stable, opaque ID assigned when the account is created (see [`Account::new_id`]). Everything that refers to an account (friends,
+/// friend requests, conversation members, the owners of conversation keys and the senders of messages) does so by ID, so the username can change.
+///
+/// `key_type` says what kind of key pair the account has, and so how `public_key` and the wrapped private key are encoded.
+///
+/// Accounts with `client_keys` set generated their key pair on the client, so the server never had their private key: `priv_key_enc`, `nonce`
+/// and `key_wrap` are an opaque backup the client uploaded (or empty), and the server never re-wraps them.
+///
+/// `key_changes` records every public key the account has had (see [`Account::key_history`]), so clients can notice a key being swapped.
+///
+/// `devices` are the account's devices, each with a key pair of its own (see [`Device`]). Conversation keys are encrypted for the account's key and
+/// for every approved device's key, so devices don't have to share the account's private key.
+///
+/// `recovery_key` is an optional second copy of the private key, encrypted under a recovery key only the user has (see [`RecoveryKey`]),
+/// for resetting a forgotten password without losing the key.
+///
+/// `delete_at` is set while the account is scheduled for deletion (see [`DeletionPolicy`]): when it will be deleted, in milliseconds since the Unix epoch.
 pub struct Account
 {
     pub username: String,
+    #[serde(default)]
+    pub id: String,
     pub hash: String,
+    #[serde(with = "serde_bytes")]
     pub public_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
     pub priv_key_enc: Vec<u8>,
+    #[serde(with = "serde_bytes")]
     pub nonce: Vec<u8>,
+    #[serde(default)]
     pub friends: Vec<String>,
+    #[serde(default)]
     pub friend_requests: Vec<FriendRequest>,
-    pub session_id: String
+    #[serde(default)]
+    pub two_factor: Option<TwoFactor>,
+    #[serde(default)]
+    pub key_wrap: Option<KeyWrap>,
+    #[serde(default)]
+    pub client_keys: bool,
+    #[serde(default)]
+    pub key_type: KeyType,
+    #[serde(default)]
+    pub key_changes: Vec<KeyChange>,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub recovery_key: Option<RecoveryKey>,
+    #[serde(default)]
+    pub delete_at: Option<i64>
 }
 
 impl Account
 {
-    /// Parses a BSON Document into an account value
-    pub fn from_document(doc: bson::Document) -> Account
+    /// Whether logging in takes a second factor on top of the password.
+    pub fn requires_second_factor(&self) -> bool
+    {
+        self.two_factor.as_ref().is_some_and(|t| t.confirmed)
+    }
+
+    /// Decodes a BSON [`Document`] into an account value, failing (rather than panicking) if the document is malformed.
+    pub fn from_document(doc: Document) -> Result<Account, bson::de::Error>
+    {
+        bson::from_document(doc)
+    }
+
+    /// Encodes the account as a BSON [`Document`].
+    pub fn to_document(&self) -> Result<Document, bson::ser::Error>
+    {
+        bson::to_document(self)
+    }
+}
+
+/// The kind of key pair an account has, and so how conversation keys are encrypted for it.
+/// Accounts and conversation keys from before there was a choice are RSA.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyType
+{
+    /// RSA-2048. The public key is a PEM-encoded SubjectPublicKeyInfo and the private key a PEM-encoded PKCS#8 document.
+    /// Conversation keys are encrypted with PKCS#1 v1.5 padding. Kept so older accounts keep working; new accounts don't get it.
+    #[default]
+    Rsa,
+    /// X25519. Both keys are 32 raw bytes. Conversation keys are sealed as described in [`super::x25519::seal`].
+    X25519
+}
+
+impl KeyType
+{
+    pub fn as_str(&self) -> &'static str
     {
-        Account {
-            username: doc.get_str("username").unwrap().to_string(),
-            hash: doc.get_str("hash").unwrap().to_string(),
-            public_key: doc
-                .get_array("public_key")
-                .unwrap()
-                .iter()
-                .map(|x| x.as_i32().unwrap() as u8)
-                .collect::<Vec<u8>>(),
-            priv_key_enc: doc
-                .get_array("priv_key_enc")
-                .unwrap()
-                .iter()
-                .map(|x| x.as_i32().unwrap() as u8)
-                .collect::<Vec<u8>>(),
-            nonce: doc
-                .get_array("nonce")
-                .unwrap()
-                .iter()
-                .map(|x| x.as_i32().unwrap() as u8)
-                .collect::<Vec<u8>>(),
-            friends: doc
-                .get_array("friends")
-                .unwrap()
-                .iter()
-                .map(|x| x.as_str().unwrap().to_string())
-                .collect(),
-            friend_requests: doc 
-                .get_array("friend_requests")
-                .unwrap()
-                .iter()
-                .map(|x| FriendRequest {
-                    sender: x.as_document().unwrap().get_str("sender").unwrap().to_string(),
-                    receiver: x.as_document().unwrap().get_str("receiver").unwrap().to_string(),
-                    status: x.as_document().unwrap().get_str("status").unwrap().to_string(),
-                })
-                .collect(),
-
-
-
-            session_id: doc.get_str("session_id").unwrap().to_string()
+        match self
+        {
+            KeyType::Rsa => "rsa",
+            KeyType::X25519 => "x25519"
         }
     }
+}
 
-    /// Retrieves an account value from the database by username.
-    /// 
-    /// ## Arguments
-    /// * [`username`][`String`] - The username of the account to retrieve.
-    /// 
-    /// ## Returns
-    /// * [`Result<Option<Account>, String>`][`std::result::Result`] - A result containing an account option (None if no account is found) or an error string, if an internal error occurred.
-    pub async fn get_account(username: &String) -> Result<Option<Account>, String>
+impl std::str::FromStr for KeyType
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KeyType, String>
     {
-        let Ok(mut doc) = mongo::get_collection("accounts")
-            .await
-            .find(bson::doc! { "username": username }, None)
-            .await
-        else { return Err(utils::gen_err("An error occurred querying the database for an account by username.")) };
+        match s
+        {
+            "rsa" => Ok(KeyType::Rsa),
+            "x25519" => Ok(KeyType::X25519),
+            _ => Err(format!("Unknown key type `{s}`."))
+        }
+    }
+}
 
-        if !doc.advance().await.unwrap() { return Ok(None) }
+/// How an account's private key is wrapped: the argon2id parameters its AES-256-GCM key is derived from the password with.
+/// Clients need these to unwrap the key after logging in. The logic lives in [`super::key_wrap`].
+///
+/// Accounts without one predate per-account salts and use [`KeyWrap::legacy`]; their key is re-wrapped the next time they log in.
+///
+/// ## Fields
+/// * [`algorithm`][`std::string::String`] - The key derivation function, always `argon2id` (version 0x13) for now.
+/// * [`salt`][`std::vec::Vec`] - The account's random salt.
+/// * [`mem_cost`][`u32`] - Memory used, in KiB.
+/// * [`time_cost`][`u32`] - Number of passes.
+/// * [`lanes`][`u32`] - Degree of parallelism.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyWrap
+{
+    pub algorithm: String,
+    #[serde(with = "serde_bytes")]
+    pub salt: Vec<u8>,
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub lanes: u32
+}
+
+/// An account's TOTP two-factor authentication settings. The logic lives in [`super::two_factor`].
+///
+/// ## Fields
+/// * [`secret`][`std::vec::Vec`] - The secret shared with the user's authenticator app.
+/// * [`confirmed`][`bool`] - Whether the user has proven their authenticator has the secret. Until then, logging in doesn't ask for a code.
+/// * [`last_step`][`i64`] - The last 30 second time step a code was accepted for. Codes for it or earlier are refused, so a code can't be used twice.
+/// * [`recovery_codes`][`std::vec::Vec`] - SHA-256 hashes of the recovery codes that haven't been used yet.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct TwoFactor
+{
+    #[serde(with = "serde_bytes")]
+    pub secret: Vec<u8>,
+    pub confirmed: bool,
+    pub last_step: i64,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>
+}
 
-        let doc: Account = Account::from_document(doc.current().try_into().unwrap());
-        Ok(Some(doc))
+impl fmt::Debug for TwoFactor
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("TwoFactor")
+            .field("secret", &"<redacted>")
+            .field("confirmed", &self.confirmed)
+            .field("last_step", &self.last_step)
+            .field("recovery_codes", &self.recovery_codes.len())
+            .finish()
     }
+}
 
-    /// Retrieves an account value from the database by session ID.
-    /// 
-    /// ## Arguments
-    /// * [`session_id`][`String`] - The session ID of the account to retrieve.
-    /// 
-    /// ## Returns
-    /// * [`Result<Option<Account>, String>`][`std::result::Result`] - A result containing an account option (None if no account is found) or an error string, if an internal error occurred.
-    /// 
-    pub async fn get_account_by_sid(session_id: &String) -> Result<Option<Account>, String>
+/// A private key encrypted by the client, which the server stores without being able to read it.
+///
+/// ## Fields
+/// * [`priv_key_enc`][`std::vec::Vec`] - The encrypted private key.
+/// * [`nonce`][`std::vec::Vec`] - The nonce it was encrypted with.
+/// * [`key_wrap`][`KeyWrap`] - The parameters the encryption key was derived from the password with, handed back at login.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct KeyBackup
+{
+    pub priv_key_enc: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub key_wrap: KeyWrap
+}
+
+/// A copy of an account's private key encrypted by the client under a recovery key it generated, which the server stores without being able to read it.
+/// The logic lives in [`super::recovery`].
+///
+/// The client derives two keys from the recovery key with `key_wrap`: one encrypts the private key, the other (the authentication key) is sent to
+/// prove the user has the recovery key when they forgot their password. Only a hash of the authentication key is stored.
+///
+/// ## Fields
+/// * [`priv_key_enc`][`std::vec::Vec`] - The encrypted private key.
+/// * [`nonce`][`std::vec::Vec`] - The nonce it was encrypted with.
+/// * [`key_wrap`][`KeyWrap`] - The parameters both keys are derived from the recovery key with.
+/// * [`auth_hash`][`std::string::String`] - An argon2 hash of the authentication key.
+/// * [`created`][`i64`] - When the recovery key was set, in milliseconds since the Unix epoch.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryKey
+{
+    #[serde(with = "serde_bytes")]
+    pub priv_key_enc: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    pub key_wrap: KeyWrap,
+    pub auth_hash: String,
+    pub created: i64
+}
+
+/// The payload setting (or replacing) an account's recovery key, or removing it.
+///
+/// ## Fields
+/// * [`password`][`std::string::String`] - The account's password.
+/// * [`key_backup`][`KeyBackup`] - The private key encrypted under the recovery key, and the parameters the recovery key's keys are derived with.
+/// * [`auth_key`][`std::string::String`] - The authentication key derived from the recovery key.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RecoveryKeySetup
+{
+    pub password: String,
+    pub key_backup: KeyBackup,
+    pub auth_key: String
+}
+
+/// The fields of the routes recovering an account with its recovery key. Each route only reads the ones it needs.
+///
+/// ## Fields
+/// * [`username`][`std::string::String`] - The account to recover.
+/// * [`auth_key`][`std::string::String`] - The authentication key derived from the recovery key.
+/// * [`code`][`std::string::String`] - A code from the authenticator app, or a two-factor recovery code, if the account has two-factor authentication on.
+/// * [`new_password`][`std::string::String`] - The password to reset to.
+/// * [`key_backup`][`KeyBackup`] - The private key encrypted under the new password.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RecoveryRequest
+{
+    pub username: String,
+    pub auth_key: String,
+    pub code: String,
+    pub new_password: String,
+    pub key_backup: KeyBackup
+}
+
+/// A public key an account has had.
+///
+/// ## Fields
+/// * [`key_type`][`KeyType`] - The kind of key.
+/// * [`fingerprint`][`std::string::String`] - Its fingerprint (see [`super::fingerprint::fingerprint`]).
+/// * [`timestamp`][`i64`] - When the account got the key, in milliseconds since the Unix epoch. 0 if it had it before key changes were recorded.
+/// * [`device`][`std::string::String`] - The ID of the [`Device`] the key belongs to, or empty for the account's own key.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyChange
+{
+    pub key_type: KeyType,
+    pub fingerprint: String,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub device: String
+}
+
+/// One of an account's devices, with a key pair it generated itself. Devices are added by one of the account's approved devices (or, for the
+/// first one, by simply asking), and each is bound to the session it is signed in with, which is how requests from it are recognised.
+///
+/// ## Fields
+/// * [`id`][`std::string::String`] - The device's ID.
+/// * [`name`][`std::string::String`] - A display name, picked by the user.
+/// * [`key_type`][`KeyType`] - The kind of key pair the device has.
+/// * [`public_key`][`std::vec::Vec`] - The device's public key, encoded as the account's is.
+/// * [`added`][`i64`] - When the device was asked to be added, in milliseconds since the Unix epoch.
+/// * [`approved`][`bool`] - Whether the device was approved. Unapproved devices get no conversation keys, and are dropped after [`Device::PENDING_TTL`].
+/// * [`session`][`std::string::String`] - The public ID of the [`Session`] the device is signed in with.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Device
+{
+    pub id: String,
+    pub name: String,
+    pub key_type: KeyType,
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    pub added: i64,
+    pub approved: bool,
+    pub session: String
+}
+
+/// A device asking to be added to an account.
+///
+/// ## Fields
+/// * [`name`][`std::string::String`] - A display name for the device.
+/// * [`key_type`][`KeyType`] - The kind of key pair, RSA if left out.
+/// * [`public_key`][`std::string::String`] - The device's public key, encoded as in [`ClientKeyRegistration`].
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct NewDevice
+{
+    pub name: String,
+    pub key_type: KeyType,
+    pub public_key: String
+}
+
+/// The payload of the device routes that act on one device. Which fields are used depends on the route.
+///
+/// ## Fields
+/// * [`device_id`][`std::string::String`] - The device.
+/// * [`password`][`std::string::String`] - The account's password.
+/// * [`response`][`std::string::String`] - The decrypted sign-in challenge.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct DeviceRequest
+{
+    pub device_id: String,
+    pub password: String,
+    pub response: String
+}
+
+/// An approved device adding a pending one, handing over the conversation keys it holds re-encrypted for the new device.
+///
+/// ## Fields
+/// * [`device_id`][`std::string::String`] - The pending device.
+/// * [`keys`][`HashMap`] - By conversation ID, that conversation's keys (of any epochs the account was a part of it in) encrypted for the new device,
+///   each with `device` set to its ID.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct DeviceApproval
+{
+    pub device_id: String,
+    pub keys: HashMap<String, Vec<UserKey>>
+}
+
+/// Sent to an account's online friends when its public key changes (arbitrary info 8), so their clients can warn about it.
+///
+/// ## Fields
+/// * [`id`][`std::string::String`] - The ID of the account whose key changed.
+/// * [`username`][`std::string::String`] - Its username.
+/// * [`change`][`KeyChange`] - The new key.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct KeyChangeNotice
+{
+    pub id: String,
+    pub username: String,
+    pub change: KeyChange
+}
+
+/// A request about another account's identity.
+///
+/// ## Fields
+/// * [`username`][`std::string::String`] - The account to ask about, by username or ID.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct IdentityRequest
+{
+    pub username: String
+}
+
+/// The safety number of the requester and a friend, and the fingerprints it covers.
+///
+/// ## Fields
+/// * [`username`][`std::string::String`] - The friend.
+/// * [`fingerprint`][`std::string::String`] - The fingerprint of the friend's current public key.
+/// * [`own_fingerprint`][`std::string::String`] - The fingerprint of the requester's current public key.
+/// * [`safety_number`][`std::string::String`] - 60 digits in groups of 5, the same for both of them (see [`super::fingerprint::safety_number`]).
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SafetyNumber
+{
+    pub username: String,
+    pub fingerprint: String,
+    pub own_fingerprint: String,
+    pub safety_number: String
+}
+
+/// A request for the IDs and usernames of some accounts.
+///
+/// ## Fields
+/// * [`users`][`std::vec::Vec`] - The accounts to look up, each by username or ID. At most [`UserLookup::MAX_USERS`].
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct UserLookup
+{
+    pub users: Vec<String>
+}
+
+impl UserLookup
+{
+    /// The most accounts one request can look up.
+    pub const MAX_USERS: usize = 100;
+}
+
+/// An account's ID and its current username.
+///
+/// ## Fields
+/// * [`id`][`std::string::String`] - The account's ID.
+/// * [`username`][`std::string::String`] - Its username.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct UserHandle
+{
+    pub id: String,
+    pub username: String
+}
+
+/// A new key pair for an account, generated by the client.
+///
+/// ## Fields
+/// * [`password`][`std::string::String`] - The account's password.
+/// * [`key_type`][`KeyType`] - The kind of key pair, RSA if left out.
+/// * [`public_key`][`std::string::String`] - The new public key, encoded as in [`ClientKeyRegistration`].
+/// * [`key_backup`][`KeyBackup`] - Optionally, the new private key encrypted by the client. Without one, the old backup is removed.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct KeyReplacement
+{
+    pub password: String,
+    pub key_type: KeyType,
+    pub public_key: String,
+    pub key_backup: Option<KeyBackup>
+}
+
+/// A new backup of a client-generated private key, e.g. re-encrypted under a new password.
+///
+/// ## Fields
+/// * [`password`][`std::string::String`] - The account's password.
+/// * [`key_backup`][`KeyBackup`] - The private key encrypted by the client.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct KeyBackupUpload
+{
+    pub password: String,
+    pub key_backup: KeyBackup
+}
+
+/// Registration of an account whose key pair was generated by the client.
+///
+/// ## Fields
+/// * [`username`][`std::string::String`] - The username of the new account.
+/// * [`password`][`std::string::String`] - The password of the new account.
+/// * [`key_type`][`KeyType`] - The kind of key pair, RSA if left out.
+/// * [`public_key`][`std::string::String`] - The account's public key: for RSA, a PEM-encoded SubjectPublicKeyInfo (`-----BEGIN PUBLIC KEY-----`);
+///   for X25519, the 32 raw bytes in base64.
+/// * [`key_backup`][`KeyBackup`] - Optionally, the private key encrypted by the client, so it can be fetched again at login.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ClientKeyRegistration
+{
+    pub username: String,
+    pub password: String,
+    pub key_type: KeyType,
+    pub public_key: String,
+    pub key_backup: Option<KeyBackup>
+}
+
+/// The payload of a password change.
+///
+/// ## Fields
+/// * [`password`][`std::string::String`] - The current password.
+/// * [`new_password`][`std::string::String`] - The password to change to.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct PasswordChange
+{
+    pub password: String,
+    pub new_password: String
+}
+
+/// The payload of a username change.
+///
+/// ## Fields
+/// * [`password`][`std::string::String`] - The account's password.
+/// * [`new_username`][`std::string::String`] - The username to change to.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct UsernameChange
+{
+    pub password: String,
+    pub new_username: String
+}
+
+/// Tells the clients of everyone who knows an account that it changed its username.
+///
+/// ## Fields
+/// * [`id`][`std::string::String`] - The account's ID, which stays the same.
+/// * [`username`][`std::string::String`] - The account's old username.
+/// * [`new_username`][`std::string::String`] - Its new one.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RenameNotice
+{
+    pub id: String,
+    pub username: String,
+    pub new_username: String
+}
+
+/// The fields of the two-factor routes' payloads. Each route only reads the ones it needs.
+///
+/// ## Fields
+/// * [`password`][`std::string::String`] - The account's password, for routes that change whether two-factor authentication is on.
+/// * [`code`][`std::string::String`] - A code from the authenticator app, or (where accepted) a recovery code.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct TwoFactorRequest
+{
+    pub password: String,
+    pub code: String
+}
+
+/// What enrolling in two-factor authentication hands back, to be entered into an authenticator app.
+///
+/// ## Fields
+/// * [`secret`][`std::string::String`] - The shared secret, base32 encoded, for entering by hand.
+/// * [`uri`][`std::string::String`] - An `otpauth://` URI holding the secret, usually shown as a QR code.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct TwoFactorEnrollment
+{
+    pub secret: String,
+    pub uri: String
+}
+
+/// The answer to a correct password for an account with two-factor authentication. No session exists yet; one is only started
+/// once the challenge is sent back with a valid code.
+///
+/// ## Fields
+/// * [`challenge`][`std::string::String`] - Identifies this login attempt.
+/// * [`expires`][`i64`] - When the challenge stops being accepted, in milliseconds since the Unix epoch.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct SecondFactorChallenge
+{
+    pub challenge: String,
+    pub expires: i64
+}
+
+/// Completes a login that was answered with a [`SecondFactorChallenge`].
+///
+/// ## Fields
+/// * [`challenge`][`std::string::String`] - The challenge the login was answered with.
+/// * [`code`][`std::string::String`] - A code from the authenticator app, or a recovery code.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct SecondFactorLogin
+{
+    pub challenge: String,
+    pub code: String
+}
+
+//------------------------------//
+
+/// How long sessions and their tokens live. All durations are in milliseconds.
+///
+/// ## Fields
+/// * [`access_ttl`][`i64`] - How long an access token is accepted after it was issued (`SESSION_ACCESS_TTL_SECS`, default 900).
+/// * [`idle_timeout`][`i64`] - How long a session may go unused before it ends (`SESSION_IDLE_TIMEOUT_SECS`, default 7 days).
+/// * [`max_age`][`i64`] - How long a session may last in total, no matter how often it is refreshed (`SESSION_MAX_AGE_SECS`, default 30 days).
+/// * [`sweep_interval`][`std::time::Duration`] - How often lapsed sessions are cleaned up and their websockets closed (`SESSION_SWEEP_INTERVAL_SECS`, default 30).
+#[derive(Debug, Clone, Copy)]
+pub struct SessionPolicy
+{
+    pub access_ttl: i64,
+    pub idle_timeout: i64,
+    pub max_age: i64,
+    pub sweep_interval: Duration
+}
+
+impl Default for SessionPolicy
+{
+    fn default() -> SessionPolicy
     {
-        let Ok(mut doc) = mongo::get_collection("accounts")
-            .await
-            .find(bson::doc! { "session_id": session_id }, None)
-            .await
-        else { return Err(utils::gen_err("An error occurred querying the database for an account by SID.")) };
+        SessionPolicy {
+            access_ttl: 15 * 60 * 1000,
+            idle_timeout: 7 * 24 * 60 * 60 * 1000,
+            max_age: 30 * 24 * 60 * 60 * 1000,
+            sweep_interval: Duration::from_secs(30)
+        }
+    }
+}
 
-        if !doc.advance().await.unwrap() { return Ok(None) }
+impl SessionPolicy
+{
+    pub fn from_env() -> SessionPolicy
+    {
+        let default = SessionPolicy::default();
+        SessionPolicy {
+            access_ttl: utils::env_or("SESSION_ACCESS_TTL_SECS", default.access_ttl / 1000) * 1000,
+            idle_timeout: utils::env_or("SESSION_IDLE_TIMEOUT_SECS", default.idle_timeout / 1000) * 1000,
+            max_age: utils::env_or("SESSION_MAX_AGE_SECS", default.max_age / 1000) * 1000,
+            sweep_interval: Duration::from_secs(utils::env_or("SESSION_SWEEP_INTERVAL_SECS", default.sweep_interval.as_secs()))
+        }
+    }
 
-        let doc: Account = Account::from_document(doc.current().try_into().unwrap());
-        Ok(Some(doc))
+    /// The policy in effect, read from the environment the first time it is needed.
+    pub fn current() -> &'static SessionPolicy
+    {
+        static POLICY: OnceLock<SessionPolicy> = OnceLock::new();
+        POLICY.get_or_init(SessionPolicy::from_env)
     }
+}
 
-    /// "Updates" an account value in the database. This is done by replacing the old account value with the new one.
-    /// 
-    /// ## Arguments
-    /// * [`new`][`Account`] - The new account value to replace the old one with.
-    /// 
-    /// ## Returns
-    /// * [`Result<(), String>`][`std::result::Result`] - Returns an error if one is present.
-    /// 
-    pub async fn update_account(new: &Account) -> Result<(), String>
+/// When deleted accounts go away. All durations are in milliseconds.
+///
+/// ## Fields
+/// * [`grace_period`][`i64`] - How long a deleted account is kept before it is deleted for good, during which its deletion can be cancelled
+///   (`ACCOUNT_DELETION_GRACE_SECS`, default 0: deleted right away).
+/// * [`sweep_interval`][`std::time::Duration`] - How often accounts whose grace period is over are deleted (`ACCOUNT_DELETION_SWEEP_INTERVAL_SECS`, default 60).
+#[derive(Debug, Clone, Copy)]
+pub struct DeletionPolicy
+{
+    pub grace_period: i64,
+    pub sweep_interval: Duration
+}
+
+impl Default for DeletionPolicy
+{
+    fn default() -> DeletionPolicy
     {
-        if let Ok(_) = mongo::get_collection("accounts")
-            .await
-            .update_one(bson::doc! { "username": &new.username }, bson::doc! { "$set": bson::to_document(&new).unwrap() }, None)
-            .await
-        { return Ok(()) }
-        else { return Err(utils::gen_err("An error occurred updating an account in the database.")) }
+        DeletionPolicy { grace_period: 0, sweep_interval: Duration::from_secs(60) }
     }
+}
 
-    /// Deletes a given account from the database.
-    /// 
-    /// ## Arguments
-    /// * [`username`][`String`] - The username of the account to delete.
-    /// 
+impl DeletionPolicy
+{
+    pub fn from_env() -> DeletionPolicy
+    {
+        let default = DeletionPolicy::default();
+        DeletionPolicy {
+            grace_period: utils::env_or("ACCOUNT_DELETION_GRACE_SECS", default.grace_period / 1000) * 1000,
+            sweep_interval: Duration::from_secs(utils::env_or("ACCOUNT_DELETION_SWEEP_INTERVAL_SECS", default.sweep_interval.as_secs()))
+        }
+    }
+
+    /// The policy in effect, read from the environment the first time it is needed.
+    pub fn current() -> &'static DeletionPolicy
+    {
+        static POLICY: OnceLock<DeletionPolicy> = OnceLock::new();
+        POLICY.get_or_init(DeletionPolicy::from_env)
+    }
+}
+
+/// One login of an account. Every successful login creates a new session, so an account can be logged in on several devices at once.
+///
+/// A session hands out two secrets: a short-lived access token that requests authenticate with, and a refresh token that is traded in
+/// for a new pair once the access token expires. Each refresh token only works once. The session itself ends once it goes unused for
+/// [`SessionPolicy::idle_timeout`], or [`SessionPolicy::max_age`] after it was created.
+///
+/// Only keyed hashes of the tokens (see [`utils::hash_token`]) are kept; the tokens themselves are handed to the client once, as [`SessionTokens`].
+///
+/// ## Fields
+/// * [`id`][`std::string::String`] - Public identifier of the session, used to list and revoke it. Unlike the tokens, this is safe to show to clients.
+/// * [`token_hash`][`std::string::String`] - The hash of the access token (session ID) clients authenticate with.
+/// * [`refresh_hash`][`std::string::String`] - The hash of the token that can be exchanged, once, for a new access and refresh token.
+/// * [`user_id`][`std::string::String`] - The ID of the account the session belongs to.
+/// * [`device`][`std::string::String`] - The user agent of the client that logged in.
+/// * [`ip`][`std::string::String`] - The address the login came from.
+/// * [`created`][`i64`] - When the session was created, in milliseconds since the Unix epoch.
+/// * [`last_seen`][`i64`] - When the session was last used, in milliseconds since the Unix epoch.
+/// * [`expires`][`i64`] - When the current access token stops being accepted, in milliseconds since the Unix epoch.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Session
+{
+    pub id: String,
+    pub token_hash: String,
+    pub refresh_hash: String,
+    pub user_id: String,
+    pub device: String,
+    pub ip: String,
+    pub created: i64,
+    pub last_seen: i64,
+    pub expires: i64
+}
+
+impl Session
+{
+    /// How stale [`Session::last_seen`] may get before a request refreshes it, so not every request costs a write.
+    pub const TOUCH_INTERVAL: i64 = 60_000;
+
+    /// Starts a new session for the account with the ID `user_id`, with a fresh pair of tokens.
+    ///
     /// ## Returns
-    /// * [`Result<(), String>`][`std::result::Result`] - Returns an error if one is present.
-    /// 
-    pub async fn delete_account(username: &String) -> Result<(), String>
+    /// * [`(Session, SessionTokens)`][`Session`] - The session to store, and the tokens to hand to the client.
+    pub fn new(user_id: &str, device: &str, ip: &str, now: i64) -> (Session, SessionTokens)
     {
-        if let Ok(_) = mongo::get_collection("accounts")
-            .await
-            .delete_one(bson::doc! { "username": username }, None)
-            .await  
-        { return Ok(()) }
-        else { return Err(utils::gen_err("An error occurred deleting an account from the database.")) }
+        Session {
+            id: utils::rand_hex(8),
+            user_id: user_id.to_string(),
+            device: device.to_string(),
+            ip: ip.to_string(),
+            created: now,
+            ..Session::default()
+        }
+        .rotated(now)
     }
 
-    /// Creates a new account entry from a given account value.
-    /// 
-    /// ## Arguments
-    /// * [`new`][`Account`] - The account value to be created.
-    /// 
+    /// The same session with a fresh pair of tokens, as handed out by a refresh.
+    ///
     /// ## Returns
-    /// * [`Result<(), String)>`][`std::result::Result`] - A result containing an error, if one is present.
-    /// 
-    pub async fn create_account(new: &Account) -> Result<(), String>
+    /// * [`(Session, SessionTokens)`][`Session`] - The updated session to store, and the new tokens to hand to the client.
+    pub fn rotated(&self, now: i64) -> (Session, SessionTokens)
+    {
+        let tokens = SessionTokens { token: utils::rand_hex(32), refresh_token: utils::rand_hex(32), expires: now + SessionPolicy::current().access_ttl };
+        let session = Session {
+            token_hash: utils::hash_token(&tokens.token),
+            refresh_hash: utils::hash_token(&tokens.refresh_token),
+            last_seen: now,
+            expires: tokens.expires,
+            ..self.clone()
+        };
+        (session, tokens)
+    }
+
+    /// Whether the session is over for good, because it went unused or is simply too old. Unlike an expired access token, this can't be refreshed.
+    pub fn lapsed(&self, now: i64) -> bool
+    {
+        let policy = SessionPolicy::current();
+        now - self.last_seen >= policy.idle_timeout || now - self.created >= policy.max_age
+    }
+
+    /// Checks that the session's access token may still be used, answering with an [`ApiError::Expired`] if it may not.
+    pub fn check(&self, now: i64) -> Result<(), ApiError>
+    {
+        if self.lapsed(now) { return Err(ApiError::Expired(String::from("Session expired, please log in again."))) }
+        if now >= self.expires { return Err(ApiError::Expired(String::from("Access token expired, use the refresh token to get a new one."))) }
+        Ok(())
+    }
+
+    pub fn from_document(doc: Document) -> Result<Session, bson::de::Error>
+    {
+        bson::from_document(doc)
+    }
+
+    pub fn to_document(&self) -> Result<Document, bson::ser::Error>
     {
-        println!("Creating account...");
-        if let Ok(_) = mongo::get_collection("accounts")
-            .await
-            .insert_one(bson::to_document(&new).unwrap(), None)
-            .await
-        { Ok(()) }
-        else { return Err(utils::gen_err("An error occurred creating an account in the database.")) }
+        bson::to_document(self)
     }
 }
 
+/// What a client gets to see of one of its sessions: everything but the token hashes.
+///
+/// ## Fields
+/// * [`current`][`bool`] - Whether this is the session the listing was requested with.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SessionInfo
+{
+    pub id: String,
+    pub device: String,
+    pub ip: String,
+    pub created: i64,
+    pub last_seen: i64,
+    pub current: bool
+}
+
+impl SessionInfo
+{
+    pub fn new(session: &Session, current: &Session) -> SessionInfo
+    {
+        SessionInfo {
+            id: session.id.clone(),
+            device: session.device.clone(),
+            ip: session.ip.clone(),
+            created: session.created,
+            last_seen: session.last_seen,
+            current: session.id == current.id
+        }
+    }
+}
+
+/// A request to trade a refresh token in for a new pair of tokens.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct RefreshRequest
+{
+    pub refresh_token: String
+}
+
+/// The tokens handed out by a login or refresh. The old access and refresh tokens stop working as soon as new ones are issued.
+///
+/// ## Fields
+/// * [`token`][`std::string::String`] - The new access token.
+/// * [`refresh_token`][`std::string::String`] - The new refresh token, to be used for the next refresh.
+/// * [`expires`][`i64`] - When the new access token expires, in milliseconds since the Unix epoch.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct SessionTokens
+{
+    pub token: String,
+    pub refresh_token: String,
+    pub expires: i64
+}
+
+/// A request to revoke one session, by its public [`Session::id`].
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RevokeSession
+{
+    pub id: String
+}
+
 //------------------------------//
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
-/// A client-side version of the [`Account`] struct. Contains only necessary client-side info, and the SID for authentication.
+#[serde(default)]
+/// A client-side version of the [`Account`] struct. Contains only necessary client-side info. Every field is optional in payloads, so requests only need to fill in the ones they use.
+///
+/// The session ID is never read from this struct; HTTP routes authenticate through the `Authorization: Bearer` header instead (see [`super::auth::Authenticated`]).
 ///
 ///  This struct is NEVER stored in the database. They are first converted to [`Account`] structs before being stored.
 /// 
 /// ## Fields
 /// * [`username`][`std::string::String`] - The username of the account.
+/// * [`id`][`std::string::String`] - The account's ID. Only filled in in responses.
 /// * [`password`][`std::string::String`] - The password of the account.
-/// * [`friends`][`std::vec::Vec`] - A vector of the usernames of the account's friends.
+/// * [`friends`][`std::vec::Vec`] - A vector of the IDs of the account's friends.
 /// * [`conversations`][`std::vec::Vec`] - A vector of the account's conversations.
-/// * [`session_id`][`std::string::String`] - The session ID of the account.
+/// * [`usernames`][`HashMap`] - The username of every account the response refers to by ID (friends, friend requests and conversation members), by ID.
+///   Accounts that no longer exist are left out. Only filled in in responses.
+/// * [`session_id`][`std::string::String`] - Unused, and always empty in responses. Kept so older clients can still parse them.
+/// * [`two_factor`][`bool`] - Whether the account has two-factor authentication turned on. Only filled in in responses.
+/// * [`key_type`][`KeyType`] - The kind of key pair the account has. Only filled in in responses.
+/// * [`recovery_key`][`bool`] - Whether the account has a recovery key set. Only filled in in responses.
+/// * [`delete_at`][`Option<i64>`] - When the account will be deleted, if it is scheduled for deletion. Only filled in in responses.
 pub struct ClientAccount
 {
     pub username: String,
+    pub id: String,
     pub password: String,
     pub friends: Vec<String>,
     pub friend_requests: Vec<FriendRequest>,
     pub conversations: Vec<Conversation>,
-    pub session_id: String
+    pub usernames: HashMap<String, String>,
+    pub session_id: String,
+    pub two_factor: bool,
+    pub key_type: KeyType,
+    pub recovery_key: bool,
+    pub delete_at: Option<i64>
 }
 
 //------------------------------//
@@ -216,7 +850,6 @@ pub enum UpdateAction
 {
     #[default]
     None,
-    ChangeUsername,
     ChangePassword,
     AddFriend,
     RemoveFriend,
@@ -231,6 +864,7 @@ pub enum UpdateAction
 /// * [`session_id`][`std::string::String`] - The session ID of the user making the request.
 /// 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
 pub struct UpdateUser
 {
     pub data: String,
@@ -238,11 +872,11 @@ pub struct UpdateUser
     pub session_id: String
 }
 
-/// A struct representing a friend request.
+/// A struct representing a friend request. Clients may name either side by username or ID, but they are always stored and sent out as IDs.
 /// 
 /// ## Fields
-/// * [`sender`][`std::string::String`] - The username of the user who sent the request.
-/// * [`receiver`][`std::string::String`] - The username of the user who received the request.
+/// * [`sender`][`std::string::String`] - The ID of the user who sent the request.
+/// * [`receiver`][`std::string::String`] - The ID of the user who received the request.
 /// 
 #[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq, Default)]
 pub struct FriendRequest
@@ -263,68 +897,72 @@ pub struct FriendRequest
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
 
 
-/// This contains a copy of the encrypted conversation key. The user who's name is attached to the `user` value is who's public key was used to encrypt it, and thus it can only be decrypted by the user with that name's attached.
+/// This contains a copy of the encrypted conversation key. The account whose ID is the `owner` value is whose public key was used to encrypt it, and thus it can only be decrypted by that account.
+/// `key_type` is the kind of key it was encrypted for, which is the owner's [`KeyType`] at the time, and `epoch` is the [`Conversation::epoch`] the key belongs to.
+/// `device` is the ID of the owner's [`Device`] it was encrypted for, or empty if it was encrypted for the account's own key.
 pub struct UserKey
 {
-    owner: String,
-    key: Vec<u8>
+    pub owner: String,
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>,
+    #[serde(default)]
+    pub key_type: KeyType,
+    #[serde(default)]
+    pub epoch: u32,
+    #[serde(default)]
+    pub device: String
 }
 
 impl UserKey
 {
-
-    /// Parses a [`UserKey`] value into a BSON [`Document`]. Necessary to ensure the key bytes remain i32s.
-    pub fn to_document(&self) -> Document
-    {
-        doc! {
-            "owner": &self.owner,
-            "key": &self.key.iter().map(|x| *x as i32).collect::<Vec<i32>>()
-        }
-    }
-
-    /// Parses a BSON [`Document`] into a [`UserKey`] value
-    pub fn from_document(doc: &Document) -> UserKey
-    {
-        let owner: String = doc.get_str("owner").unwrap().to_string();
-        let key: Vec<u8> = doc
-            .get_array("key")
-            .unwrap()
-            .iter()
-            .map(|x| x.as_i32().unwrap() as u8)
-            .collect();
-        UserKey {
-            owner,
-            key
-        }
-    }
-
-    /// Encrypts a key, intended to be the conversation key, with the public key of the provided user.
+    /// Encrypts a key, intended to be the conversation key, with the public key of the provided user, using the scheme of their [`KeyType`].
+    /// The returned key is for epoch 0; keys of later epochs have their `epoch` set by the caller.
     /// 
     /// ## Arguments
     /// * [`key`][`std::vec::Vec`] - The key to be encrypted.
+    /// * [`account`][`Account`] - The account whose public key the key is encrypted for.
     /// 
     /// ## Returns
-    /// * [`Result<UserKey, String>`][`std::result::Result`] - A result containing the encrypted key or an error string, if an internal error occurred.
+    /// * [`Result<UserKey, ApiError>`][`std::result::Result`] - A result containing the encrypted key or an [`ApiError::Crypto`], if the account's public key is unusable.
     /// 
-    pub async fn encrypt(key: &[u8], user: &String) -> Result<UserKey, String>
+    pub fn encrypt(key: &[u8], account: &Account) -> Result<UserKey, ApiError>
     {
-        
-        let Ok(Some(account)) = Account::get_account(user).await
-        else { return Err(utils::gen_err("Error retrieving account from database.")) };
-
-        let Ok(pub_key) = rsa::RsaPublicKey::from_public_key_pem(&String::from_utf8(account.public_key).unwrap())
-        else { return Err(utils::gen_err("Error retrieving public key from database.")) };
+        Ok(UserKey {
+            owner: account.id.clone(),
+            key: UserKey::seal(account.key_type, &account.public_key, key, &account.username)?,
+            key_type: account.key_type,
+            epoch: 0,
+            device: String::new()
+        })
+    }
 
-        let mut rng: rand::prelude::ThreadRng = rand::thread_rng();
-        let encrypted_key = pub_key
-            .encrypt(&mut rng, Pkcs1v15Encrypt, key)
-            .expect("failed to encrypt key");
-        
+    /// Like [`UserKey::encrypt`], but for one of the account's devices rather than the account's own key.
+    pub fn encrypt_for_device(key: &[u8], account: &Account, device: &Device) -> Result<UserKey, ApiError>
+    {
         Ok(UserKey {
-            owner: user.clone(),
-            key: encrypted_key
+            owner: account.id.clone(),
+            key: UserKey::seal(device.key_type, &device.public_key, key, &account.username)?,
+            key_type: device.key_type,
+            epoch: 0,
+            device: device.id.clone()
         })
+    }
+
+    /// Encrypts `plaintext` so only the holder of the private key to `public_key` can read it: sealed with [`super::x25519::seal`] for X25519 keys,
+    /// or with RSA PKCS#1 v1.5 for RSA keys. `owner` only names whose key it is in errors.
+    pub fn seal(key_type: KeyType, public_key: &[u8], plaintext: &[u8], owner: &str) -> Result<Vec<u8>, ApiError>
+    {
+        if key_type == KeyType::X25519 { return super::x25519::seal(public_key, plaintext) }
 
+        let Ok(pub_key) = String::from_utf8(public_key.to_vec())
+            .map_err(|_| ())
+            .and_then(|pem| rsa::RsaPublicKey::from_public_key_pem(&pem).map_err(|_| ()))
+        else { return Err(ApiError::Crypto(format!("The public key of {owner} is malformed."))) };
+
+        let mut rng: rand::prelude::ThreadRng = rand::thread_rng();
+        pub_key
+            .encrypt(&mut rng, Pkcs1v15Encrypt, plaintext)
+            .map_err(|_| ApiError::Crypto(String::from("Failed to encrypt the conversation key.")))
     }
 }
 
@@ -337,6 +975,7 @@ impl UserKey
 /// * [`time`][`std::string::String`] - The time the message was sent.
 /// 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(dead_code)]
 pub struct RawMessage
 {
     pub message: Vec<u8>,
@@ -345,51 +984,73 @@ pub struct RawMessage
 
 //------------------------------//
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 /// An encrypted message value.
 /// 
 /// ## Fields
 /// * [`data`][`std::vec::Vec`] - The encrypted message payload. See [`UserKey`] to see how this data is encrypted.
-/// * [`sender`][`std::string::String`] - The username of the user who sent the message.
-/// * [`dest_convo_id`][`std::string::String`] - The ID of the conversation the message is being sent to (removed before upload.)
+/// * [`sender`][`std::string::String`] - The ID of the user who sent the message. Clients may send their username instead, which the server replaces.
+/// * [`dest_convo_id`][`std::string::String`] - The ID of the conversation the message is being sent to.
 /// * [`sender_sid`][`std::string::String`] - The session ID of the user who sent the message (removed before upload.)
+/// * [`id`][`std::string::String`] - Unique ID of the message, assigned by the server on upload.
+/// * [`seq`][`i64`] - Position of the message in its conversation, starting at 1. Assigned by the server on upload, and used as the cursor for history pagination.
+/// * [`timestamp`][`i64`] - Server time the message was stored, in milliseconds since the Unix epoch.
+/// * [`epoch`][`u32`] - The [`Conversation::epoch`] whose key the message was encrypted with. Must be the conversation's current epoch when sent.
 /// 
 pub struct EncryptedMessage
 {
+    #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
+    #[serde(with = "serde_bytes")]
     pub nonce: Vec<u8>,
     pub sender: String,
+    #[serde(default)]
     pub dest_convo_id: String,
-    pub sender_sid: String
+    #[serde(default)]
+    pub sender_sid: String,
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub seq: i64,
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub epoch: u32
+}
+
+// written by hand so the sender's SID never ends up in logs
+impl fmt::Debug for EncryptedMessage
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("EncryptedMessage")
+            .field("data", &self.data)
+            .field("nonce", &self.nonce)
+            .field("sender", &self.sender)
+            .field("dest_convo_id", &self.dest_convo_id)
+            .field("sender_sid", &"<redacted>")
+            .field("id", &self.id)
+            .field("seq", &self.seq)
+            .field("timestamp", &self.timestamp)
+            .field("epoch", &self.epoch)
+            .finish()
+    }
 }
 
 impl EncryptedMessage
 {
-    /// Parses a BSON [`Document`] into a [`Conversation`] value
-    fn from_document(doc: &Document) -> EncryptedMessage
+    /// Decodes a BSON [`Document`] from the `messages` collection into an [`EncryptedMessage`] value.
+    pub fn from_document(doc: Document) -> Result<EncryptedMessage, bson::de::Error>
     {
-        let data: Vec<u8> = doc
-            .get("data")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| x.as_i32().unwrap() as u8)
-            .collect();
-        let nonce: Vec<u8> = doc
-            .get_array("nonce")
-            .unwrap()
-            .iter()
-            .map(|x| x.as_i32().unwrap() as u8)
-            .collect::<Vec<u8>>();
-        let sender: String = doc.get_str("sender").unwrap().to_string();
-        EncryptedMessage {
-            data,
-            nonce,
-            sender,
-            dest_convo_id: String::new(),
-            sender_sid: String::new()
-        }
+        bson::from_document(doc)
+    }
+
+    /// Encodes an [`EncryptedMessage`] as a BSON [`Document`] for the `messages` collection. The sender's SID is never stored.
+    pub fn to_document(&self) -> Result<Document, bson::ser::Error>
+    {
+        let mut doc = bson::to_document(self)?;
+        doc.remove("sender_sid");
+        Ok(doc)
     }
 }
 
@@ -401,152 +1062,110 @@ impl EncryptedMessage
 /// 
 /// ## Fields
 /// * [`id`][`std::string::String`] - The ID of the conversation.
-/// * [`users`][`std::vec::Vec`] - A vector of the usernames of the users in the conversation.
-/// * [`keys`][`UserKey`] - A vector of the encrypted [`UserKey`]s for each user in the conversation.
-/// * [`messages`][`EncryptedMessage`] - A vector of the [`EncryptedMessage`]s in the conversation.
+/// * [`users`][`std::vec::Vec`] - A vector of the IDs of the users in the conversation.
+/// * [`keys`][`UserKey`] - A vector of the encrypted [`UserKey`]s for each user in the conversation, for every epoch. Members only have keys for the epochs
+///   they were a part of the conversation in.
+/// * [`epoch`][`u32`] - The current key epoch. Every change of membership generates a new conversation key under the next epoch, so removed members
+///   can't read anything sent afterwards and added members can't read anything sent before.
+/// * [`messages`][`EncryptedMessage`] - The most recent [`EncryptedMessage`]s in the conversation. Messages are stored separately from their conversation,
+///   so this is empty when read from storage and only filled with the latest page of history when sent to a client. Older messages are fetched through [`HistoryRequest`]s.
 /// 
 pub struct Conversation
 {
     pub id: String,
     pub users: Vec<String>,
     pub keys: Vec<UserKey>,
-    pub messages: Vec<EncryptedMessage>
+    #[serde(default)]
+    pub messages: Vec<EncryptedMessage>,
+    #[serde(default)]
+    pub epoch: u32
 }
 
 impl Conversation
 {
-    /// Parses a [`Conversation`] value into a BSON [`Document`].
-    /// Most structs do not need a `to_document` because bson has a built-in method, but this is necessary to ensure the key bytes remain I32s; bson's `to_document()` will turn them into I64s.
-    pub fn to_document(&self) -> Document
+    /// Encodes a [`Conversation`] as a BSON [`Document`]. Messages live in their own collection, so they are left out.
+    pub fn to_document(&self) -> Result<Document, bson::ser::Error>
     {
-        doc! {
-            "id": &self.id,
-            "users": &self.users.iter().map(|x| x.as_str()).collect::<Vec<&str>>(),
-            "keys": &self.keys.iter().map(|x| x.to_document()).collect::<Vec<Document>>(),
-            "messages": &self.messages.iter().map(|x| bson::to_document(&x).unwrap()).collect::<Vec<Document>>()
-        }
+        let mut doc = bson::to_document(self)?;
+        doc.remove("messages");
+        Ok(doc)
     }
 
-    /// Parses a BSON [`Document`] into a [`Conversation`] value
-    pub fn from_document(doc: &Document) -> Conversation
+    /// Decodes a BSON [`Document`] into a [`Conversation`] value. Only conversations stored before messages moved into their own collection still embed them.
+    pub fn from_document(doc: Document) -> Result<Conversation, bson::de::Error>
     {
-        let id: String = doc.get_str("id").unwrap().to_string();
-        let users: Vec<String> = doc
-            .get("users")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| x.as_str().unwrap().to_string())
-            .collect();
-        let messages: Vec<EncryptedMessage> = doc
-            .get("messages")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| EncryptedMessage::from_document(x.as_document().unwrap()))
-            .collect();
-        let keys: Vec<UserKey> = doc
-            .get("keys")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| UserKey::from_document(x.as_document().unwrap()))
-            .collect();
-        Conversation {
-            id,
-            users,
-            messages,
-            keys
-        }
+        bson::from_document(doc)
     }
+}
 
-    /// Gets all conversations that a provided user is a part of.
-    /// 
-    /// ## Arguments
-    /// * [`username`][`String`] - The username of the user to retrieve conversations for.
-    /// 
-    /// ## Returns
-    /// * [`Result<Vec<Conversation>, String>`][`std::result::Result`] - A result containing a vector of conversations or an error string, if an internal error occurred.
-    pub async fn get_all(username: &String) -> Result<Vec<Conversation>, String>
-    {
-        let mut convos: Vec<Conversation> = Vec::new();
-        let Ok(mut doc) = mongo::get_collection("conversations")
-            .await
-            .find(Some(doc! {"users": username}), None)
-            .await
-        else { return Err(utils::gen_err("Failed to retrieve conversations from database.")) };
-
-        while doc.advance().await.unwrap() == true
-        {
-            convos.push(Conversation::from_document(&doc.current().try_into().unwrap()));
-        }
+//------------------------------//
 
-        Ok(convos)
-    }
+/// A request for a page of a conversation's message history.
+///
+/// `before` and `after` are message sequence numbers (see [`EncryptedMessage::seq`]). With `before`, the page holds the messages directly preceding that one;
+/// with `after`, the ones directly following it; with neither, the most recent messages in the conversation.
+///
+/// ## Fields
+/// * [`conversation_id`][`std::string::String`] - The conversation to read from.
+/// * [`before`][`Option<i64>`] - Only return messages older than this sequence number.
+/// * [`after`][`Option<i64>`] - Only return messages newer than this sequence number.
+/// * [`limit`][`Option<usize>`] - Maximum number of messages to return. Defaults to, and is capped at, [`HistoryRequest::MAX_LIMIT`].
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct HistoryRequest
+{
+    pub conversation_id: String,
+    #[serde(default)]
+    pub before: Option<i64>,
+    #[serde(default)]
+    pub after: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>
+}
 
-    /// Gets one conversation with the specified ID.
-    /// 
-    /// ## Arguments
-    /// * [`id`][`String`] - The ID of the conversation to retrieve.
-    /// 
-    /// ## Returns
-    /// * [`Result<Option<Conversation>, String>`][`std::result::Result`] - A result containing a conversation option (None if no conversation is found) or an error string, if an internal error occurred.
-    /// 
-    pub async fn get_one(id: &String) -> Result<Option<Conversation>, String>
-    {
-        let Ok(doc) = mongo::get_collection("conversations")
-            .await
-            .find_one(Some(doc! {"id": id}), None)
-            .await
-        else { return Err(utils::gen_err("There was an error trying to retrieve a conversation.")) };
+impl HistoryRequest
+{
+    pub const MAX_LIMIT: usize = 50;
 
-        match doc
-        {
-            Some(doc) => Ok(Some(Conversation::from_document(&doc))),
-            None => Ok(None)
-        }
-    }
+    /// The page size actually used for this request.
+    pub fn page_size(&self) -> usize { self.limit.unwrap_or(Self::MAX_LIMIT).clamp(1, Self::MAX_LIMIT) }
+}
 
-    /// Sends a message to a conversation.
-    /// 
-    /// ## Arguments
-    /// * [`message`][`EncryptedMessage`] - The message to be sent.
-    /// 
-    /// ## Returns
-    /// * [`Result<(), (String)>`][`std::result::Result`] - A result containing either an empty value or an error string.
-    /// 
-    pub async fn send(&mut self, message: EncryptedMessage) -> Result<(), String>
-    {
-        // strip message of useless/private data; attaching SID means other member of convo would be able to access the other user's SID with some client-side manipulation.
-        // TODO: pretty sure sender doesn't need to be on EncryptedMessage. Fix in client-side
-        let message: EncryptedMessage = EncryptedMessage {
-            data: message.data,
-            nonce: message.nonce,
-            sender: message.sender,
-            dest_convo_id: String::new(),
-            sender_sid: String::new()
-        };
-        self.messages.push(message);
-        
-        if let Ok(_) = mongo::get_collection("conversations")
-            .await
-            .find_one_and_update(doc! {"id": &self.id}, doc! {"$set": bson::to_document(&self).unwrap()}, None)
-            .await
-        { return Ok(()) } else { return Err(utils::gen_err("An error occurred pushing a new message to a conversation.")); }
-    }
+/// A page of message history, oldest message first.
+///
+/// ## Fields
+/// * [`conversation_id`][`std::string::String`] - The conversation the messages belong to.
+/// * [`messages`][`EncryptedMessage`] - The messages in the page, in ascending sequence order.
+/// * [`more`][`bool`] - Whether there are further messages past this page, in the direction that was requested.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct HistoryPage
+{
+    pub conversation_id: String,
+    pub messages: Vec<EncryptedMessage>,
+    pub more: bool
+}
 
-    /// Modifies an existing conversation
-    pub async fn modify(new: &Conversation) -> Result<(), String>
-    {
-        if let Ok(_) = mongo::get_collection("conversations")
-            .await
-            .find_one_and_update(doc! {"id": &new.id}, doc! {"$set": Conversation::to_document(&new)}, None)
-            .await
-        { return Ok(()) } else { return Err(utils::gen_err("An error occurred modifying a conversation.")); }
-    }
+//------------------------------//
+
+/// Members to add to or remove from a conversation. Either way, the conversation moves to a new key epoch.
+///
+/// ## Fields
+/// * [`conversation_id`][`std::string::String`] - The conversation to change.
+/// * [`users`][`std::vec::Vec`] - The users to add or remove, by username or ID.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MembershipChange
+{
+    pub conversation_id: String,
+    pub users: Vec<String>
+}
+
+/// A request for the requester's keys to a conversation.
+///
+/// ## Fields
+/// * [`conversation_id`][`std::string::String`] - The conversation to get the keys of.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ConversationKeysRequest
+{
+    pub conversation_id: String
 }
 
 //----------------------------------------------//
@@ -555,39 +1174,87 @@ impl Conversation
 //                                              //
 //----------------------------------------------//
 
-#[derive(Debug)]
 pub struct WebsocketClient
 {
     pub username: String,
+    /// [`Account::id`] of the account the client registered as.
+    pub user_id: String,
     pub session_id: String,
+    /// Public [`Session::id`] of the session the client registered with.
+    pub session: String,
+    /// When the access token the client registered with expires. The connection is closed then, unless the session is refreshed first.
+    pub expires: i64,
+    /// [`Device::id`] of the approved device signed in with the client's session, or empty if there is none. Conversation keys are only sent to the device they are for.
+    pub device: String,
     pub socket: Sender<WSPacket>
 }
 
 pub type ClientStore = Arc<Mutex<HashMap<SocketAddr, WebsocketClient>>>;
 
+/// Shared state handed to every route: the live websocket clients, the storage backend, the failed login tracker, the rate limiter
+/// and the logins waiting on a second factor.
+#[derive(Clone)]
+pub struct AppState
+{
+    pub clients: ClientStore,
+    pub db: Db,
+    pub logins: Arc<LoginThrottle>,
+    pub limiter: Arc<RateLimiter>,
+    pub challenges: Arc<LoginChallenges>
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum WSAction
 {
     SendMessage(EncryptedMessage),
     ReceiveMessage(EncryptedMessage),
+    MessageAck(EncryptedMessage),
     CreateConversation(Vec<String>),
     DeleteConversation(String),
+    AddMembers(MembershipChange),
+    RemoveMembers(MembershipChange),
+    FetchHistory(HistoryRequest),
+    History(HistoryPage),
     AddFriend(FriendRequest),
     RemoveFriend(String),
     Register(),
     Disconnect(),
     Info(String),
+    Error(ErrorBody),
+    SessionEnded(String), // (Reason) sent right before the server closes the connection
     ReceiveArbitraryInfo(String, u8), // (Serialized Data, Identifying Key)
     // ARBITRARY INFO KEYS:
     // 1 - Bulk Conversation Update
     // 2 - Single Conversation Update
     // 3 - Add Friend Locally and Update Conversation
+    // 4 - Remove Friend Locally (their ID)
+    // 5 - Friend Request Sent
+    // 6 - Friend Request Cancelled
+    // 7 - Removed From Conversation (the conversation's ID)
+    // 8 - Friend's Public Key Changed (a KeyChangeNotice)
+    // 9 - Device Waiting For Approval (the pending Device)
+    // 10 - Signed In As Device (the Device)
+    // 11 - Account Deleted (its ID), to be removed from friends and friend requests locally
+    // 12 - Username Changed (a RenameNotice), to be replaced everywhere locally
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct WSPacket
 {
     pub sender: String,
     pub sid: String,
     pub action: WSAction
 }
+
+// written by hand so session IDs never end up in logs
+impl fmt::Debug for WSPacket
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("WSPacket")
+            .field("sender", &self.sender)
+            .field("sid", &"<redacted>")
+            .field("action", &self.action)
+            .finish()
+    }
+}
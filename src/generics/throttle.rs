@@ -0,0 +1,265 @@
+use std::{collections::HashMap, fmt, net::IpAddr, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+use tracing::{info, warn};
+use super::{errors::ApiError, utils};
+
+//----------------------------------------------//
+//                                              //
+//          Login throttling and lockout        //
+//                                              //
+//----------------------------------------------//
+
+/// How failed password checks are throttled. All durations are in milliseconds.
+///
+/// ## Fields
+/// * [`free_attempts`][`u32`] - Failed attempts an account gets before it is locked out (`LOGIN_FREE_ATTEMPTS`, default 5).
+/// * [`free_attempts_per_ip`][`u32`] - Failed attempts an IP gets before it is locked out (`LOGIN_FREE_ATTEMPTS_PER_IP`, default 20). Higher, as many users can share one address.
+/// * [`base_lockout`][`i64`] - The lockout after the first failure past the free ones. It doubles with every further failure (`LOGIN_BASE_LOCKOUT_SECS`, default 1).
+/// * [`max_lockout`][`i64`] - The longest a lockout gets (`LOGIN_MAX_LOCKOUT_SECS`, default 900).
+/// * [`forget_after`][`i64`] - Failures are forgotten once there hasn't been another for this long (`LOGIN_FORGET_AFTER_SECS`, default 3600).
+/// * [`report_interval`][`std::time::Duration`] - How often the counters are logged and forgotten failures pruned (`LOGIN_REPORT_INTERVAL_SECS`, default 300).
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleConfig
+{
+    pub free_attempts: u32,
+    pub free_attempts_per_ip: u32,
+    pub base_lockout: i64,
+    pub max_lockout: i64,
+    pub forget_after: i64,
+    pub report_interval: Duration
+}
+
+impl Default for ThrottleConfig
+{
+    fn default() -> ThrottleConfig
+    {
+        ThrottleConfig {
+            free_attempts: 5,
+            free_attempts_per_ip: 20,
+            base_lockout: 1000,
+            max_lockout: 15 * 60 * 1000,
+            forget_after: 60 * 60 * 1000,
+            report_interval: Duration::from_secs(300)
+        }
+    }
+}
+
+impl ThrottleConfig
+{
+    pub fn from_env() -> ThrottleConfig
+    {
+        let default = ThrottleConfig::default();
+        ThrottleConfig {
+            free_attempts: utils::env_or("LOGIN_FREE_ATTEMPTS", default.free_attempts),
+            free_attempts_per_ip: utils::env_or("LOGIN_FREE_ATTEMPTS_PER_IP", default.free_attempts_per_ip),
+            base_lockout: utils::env_or("LOGIN_BASE_LOCKOUT_SECS", default.base_lockout / 1000) * 1000,
+            max_lockout: utils::env_or("LOGIN_MAX_LOCKOUT_SECS", default.max_lockout / 1000) * 1000,
+            forget_after: utils::env_or("LOGIN_FORGET_AFTER_SECS", default.forget_after / 1000) * 1000,
+            report_interval: Duration::from_secs(utils::env_or("LOGIN_REPORT_INTERVAL_SECS", default.report_interval.as_secs()))
+        }
+    }
+}
+
+/// What failed attempts are counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key
+{
+    /// The account's ID, or the username tried if there is no such account.
+    Account(String),
+    Ip(IpAddr)
+}
+
+impl fmt::Display for Key
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Key::Account(account) => write!(f, "account `{account}`"),
+            Key::Ip(ip) => write!(f, "IP {ip}")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Failures
+{
+    count: u32,
+    last: i64,
+    locked_until: i64
+}
+
+/// Running totals since startup, for operators.
+///
+/// ## Fields
+/// * [`failures`][`u64`] - Failed password checks.
+/// * [`lockouts`][`u64`] - Times an account or IP was locked out (or had its lockout extended).
+/// * [`rejected`][`u64`] - Attempts turned away because of a lockout, without checking the password.
+/// * [`locked`][`usize`] - Accounts and IPs locked out right now.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleStats
+{
+    pub failures: u64,
+    pub lockouts: u64,
+    pub rejected: u64,
+    pub locked: usize
+}
+
+/// Tracks failed password checks per account and per IP, and locks either out with exponential backoff once it runs out of free attempts.
+/// Accounts are counted by ID, so renaming one doesn't reset its failures; usernames that don't belong to any account are counted as they are.
+/// Locked-out attempts are turned away before the password is hashed, so they cost next to nothing.
+///
+/// Failures are kept in memory, so they are per process and forgotten on restart.
+pub struct LoginThrottle
+{
+    config: ThrottleConfig,
+    failures: Mutex<HashMap<Key, Failures>>,
+    failed_total: AtomicU64,
+    lockouts_total: AtomicU64,
+    rejected_total: AtomicU64
+}
+
+impl LoginThrottle
+{
+    pub fn new(config: ThrottleConfig) -> LoginThrottle
+    {
+        LoginThrottle {
+            config,
+            failures: Mutex::default(),
+            failed_total: AtomicU64::default(),
+            lockouts_total: AtomicU64::default(),
+            rejected_total: AtomicU64::default()
+        }
+    }
+
+    pub fn config(&self) -> &ThrottleConfig { &self.config }
+
+    /// Checks whether a password may be checked for `account` from `ip`. Call this before hashing anything.
+    ///
+    /// ## Returns
+    /// * [`Result<(), ApiError>`][`std::result::Result`] - Ok if the attempt may go ahead, or an [`ApiError::RateLimited`] saying how long the lockout lasts.
+    pub fn check(&self, account: &str, ip: IpAddr, now: i64) -> Result<(), ApiError>
+    {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = [Key::Account(account.to_string()), Key::Ip(ip)]
+            .iter()
+            .filter_map(|key| failures.get(key))
+            .map(|f| f.locked_until)
+            .max()
+            .unwrap_or_default();
+        if locked_until <= now { return Ok(()) }
+
+        self.rejected_total.fetch_add(1, Ordering::Relaxed);
+        let secs = ((locked_until - now) as u64).div_ceil(1000);
+        Err(ApiError::RateLimited(format!("Too many failed attempts. Try again in {secs} seconds."), secs))
+    }
+
+    /// Records a failed password check for `account` from `ip`. Once either runs out of free attempts it is locked out,
+    /// for twice as long with every further failure.
+    pub fn failure(&self, account: &str, ip: IpAddr, now: i64)
+    {
+        self.failed_total.fetch_add(1, Ordering::Relaxed);
+        let mut failures = self.failures.lock().unwrap();
+        for (key, free) in [(Key::Account(account.to_string()), self.config.free_attempts), (Key::Ip(ip), self.config.free_attempts_per_ip)]
+        {
+            let entry = failures.entry(key.clone()).or_default();
+            if now - entry.last >= self.config.forget_after { *entry = Failures::default() }
+            entry.count += 1;
+            entry.last = now;
+            if entry.count < free { continue }
+
+            let doublings = (entry.count - free).min(32);
+            let lockout = self.config.base_lockout.saturating_mul(1 << doublings).min(self.config.max_lockout);
+            entry.locked_until = now + lockout;
+            self.lockouts_total.fetch_add(1, Ordering::Relaxed);
+            warn!("Locked {key} out for {}s after {} failed attempts", lockout / 1000, entry.count);
+        }
+    }
+
+    /// Forgets the failed attempts of `account` after its password was entered correctly.
+    /// Those of the IP are kept, so logging into one account can't be used to keep guessing at others.
+    pub fn success(&self, account: &str)
+    {
+        self.failures.lock().unwrap().remove(&Key::Account(account.to_string()));
+    }
+
+    pub fn stats(&self, now: i64) -> ThrottleStats
+    {
+        ThrottleStats {
+            failures: self.failed_total.load(Ordering::Relaxed),
+            lockouts: self.lockouts_total.load(Ordering::Relaxed),
+            rejected: self.rejected_total.load(Ordering::Relaxed),
+            locked: self.failures.lock().unwrap().values().filter(|f| f.locked_until > now).count()
+        }
+    }
+
+    /// Drops failures that have been forgotten and aren't holding a lockout, so the map doesn't grow with every username ever tried.
+    pub fn prune(&self, now: i64)
+    {
+        let forget_after = self.config.forget_after;
+        self.failures.lock().unwrap().retain(|_, f| now - f.last < forget_after || f.locked_until > now);
+    }
+}
+
+/// Every [`ThrottleConfig::report_interval`], prunes forgotten failures and logs the throttle's counters if anything happened since the last report.
+pub async fn report(throttle: Arc<LoginThrottle>)
+{
+    let mut interval = tokio::time::interval(throttle.config().report_interval);
+    let mut last = ThrottleStats::default();
+    loop
+    {
+        interval.tick().await;
+        let now = utils::now();
+        throttle.prune(now);
+        let stats = throttle.stats(now);
+        if stats != last
+        {
+            info!(
+                "Login throttle: {} failed attempts, {} lockouts and {} rejected attempts since startup; {} accounts/IPs locked out now",
+                stats.failures, stats.lockouts, stats.rejected, stats.locked
+            );
+        }
+        last = stats;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn locks_out_with_exponential_backoff_and_forgets_on_success()
+    {
+        let throttle = LoginThrottle::new(ThrottleConfig { free_attempts: 3, ..ThrottleConfig::default() });
+        for _ in 0..2 { throttle.failure("alice", IP, 0); }
+        assert!(throttle.check("alice", IP, 0).is_ok(), "free attempts shouldn't lock anyone out");
+
+        throttle.failure("alice", IP, 0);
+        assert_eq!(throttle.check("alice", IP, 0).unwrap_err().retry_after(), Some(1));
+        assert!(throttle.check("alice", IP, 1000).is_ok());
+
+        throttle.failure("alice", IP, 1000);
+        assert_eq!(throttle.check("alice", IP, 1000).unwrap_err().retry_after(), Some(2));
+        assert!(throttle.check("bob", IP, 1000).is_ok(), "other usernames from the IP are still allowed");
+
+        throttle.success("alice");
+        assert!(throttle.check("alice", IP, 1000).is_ok());
+        assert_eq!(throttle.stats(1000), ThrottleStats { failures: 4, lockouts: 2, rejected: 2, locked: 0 });
+    }
+
+    #[test]
+    fn locks_out_ips_trying_many_usernames()
+    {
+        let throttle = LoginThrottle::new(ThrottleConfig { free_attempts_per_ip: 3, ..ThrottleConfig::default() });
+        for user in ["a", "b", "c"] { throttle.failure(user, IP, 0); }
+        assert!(matches!(throttle.check("d", IP, 0), Err(ApiError::RateLimited(..))));
+        assert!(throttle.check("d", IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1)), 0).is_ok());
+
+        // long after the lockout ended, the failures are forgotten and pruned
+        let later = ThrottleConfig::default().forget_after + 1;
+        throttle.prune(later);
+        assert!(throttle.failures.lock().unwrap().is_empty());
+    }
+}
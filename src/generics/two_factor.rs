@@ -0,0 +1,210 @@
+use std::{collections::HashMap, sync::Mutex};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use super::{structs::TwoFactor, utils};
+
+//----------------------------------------------//
+//                                              //
+//        TOTP two-factor authentication        //
+//                                              //
+//----------------------------------------------//
+
+/// The issuer authenticator apps list accounts under.
+const ISSUER: &str = "CRIM";
+/// Length of a TOTP time step, in milliseconds.
+const STEP: i64 = 30_000;
+const DIGITS: u32 = 6;
+/// How many recovery codes are handed out when two-factor authentication is turned on.
+const RECOVERY_CODES: usize = 10;
+
+impl TwoFactor
+{
+    /// Settings with a fresh random secret, not yet confirmed.
+    pub fn generate() -> TwoFactor
+    {
+        let mut secret = vec![0; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        TwoFactor { secret, ..TwoFactor::default() }
+    }
+
+    /// The secret, base32 encoded as authenticator apps expect it.
+    pub fn encoded_secret(&self) -> String
+    {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    /// The `otpauth://` URI for adding `username`'s secret to an authenticator app.
+    pub fn uri(&self, username: &str) -> String
+    {
+        let label = utf8_percent_encode(&format!("{ISSUER}:{username}"), NON_ALPHANUMERIC).to_string();
+        format!("otpauth://totp/{label}?secret={}&issuer={ISSUER}&algorithm=SHA1&digits={DIGITS}&period={}", self.encoded_secret(), STEP / 1000)
+    }
+
+    /// Checks a code from the authenticator app, allowing for one step of clock drift either way. An accepted code moves
+    /// [`TwoFactor::last_step`] forward, so it (and any older one) is refused from then on.
+    pub fn accept_code(&mut self, code: &str, now: i64) -> bool
+    {
+        let Ok(code) = code.trim().parse::<u32>() else { return false };
+        let current = now / STEP;
+        let Some(step) = (current - 1..=current + 1).find(|&step| step > self.last_step && totp(&self.secret, step) == code)
+        else { return false };
+        self.last_step = step;
+        true
+    }
+
+    /// Checks a recovery code, crossing it off if it is one.
+    pub fn accept_recovery_code(&mut self, code: &str) -> bool
+    {
+        let hash = hash_recovery_code(code);
+        let before = self.recovery_codes.len();
+        self.recovery_codes.retain(|h| *h != hash);
+        self.recovery_codes.len() < before
+    }
+
+    /// Checks a code from the authenticator app, or failing that, a recovery code.
+    pub fn accept(&mut self, code: &str, now: i64) -> bool
+    {
+        self.accept_code(code, now) || self.accept_recovery_code(code)
+    }
+
+    /// Replaces the recovery codes with new ones.
+    ///
+    /// ## Returns
+    /// * [`Vec<String>`][`std::vec::Vec`] - The new codes. Only their hashes are kept, so this is the only time they can be shown.
+    pub fn regenerate_recovery_codes(&mut self) -> Vec<String>
+    {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let code = utils::rand_hex(10);
+                format!("{}-{}", &code[..10], &code[10..])
+            })
+            .collect();
+        self.recovery_codes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        codes
+    }
+}
+
+/// The RFC 6238 code for one time step: HOTP (RFC 4226) with the step as counter.
+fn totp(secret: &[u8], step: i64) -> u32
+{
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+/// Recovery codes carry 80 random bits, so a plain SHA-256 is enough to keep them safe at rest. Dashes, spaces and case are ignored.
+fn hash_recovery_code(code: &str) -> String
+{
+    let normalized: String = code.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_lowercase()).collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+//------------------------------//
+
+/// A login whose password was right, waiting on its second factor.
+#[derive(Clone)]
+pub struct PendingLogin
+{
+    pub user_id: String,
+    pub device: String,
+    pub ip: String,
+    pub expires: i64
+}
+
+/// Logins waiting on a second factor, by challenge. They are kept in memory, so a login has to be completed on the same server
+/// it was started on, and restarting drops them.
+#[derive(Default)]
+pub struct LoginChallenges
+{
+    pending: Mutex<HashMap<String, PendingLogin>>
+}
+
+impl LoginChallenges
+{
+    /// How long a challenge may be answered for.
+    pub const TTL: i64 = 5 * 60 * 1000;
+
+    /// Starts waiting on a second factor for the account with the ID `user_id`.
+    ///
+    /// ## Returns
+    /// * [`(String, i64)`][`std::string::String`] - The challenge, and when it expires.
+    pub fn issue(&self, user_id: &str, device: &str, ip: &str, now: i64) -> (String, i64)
+    {
+        let challenge = utils::rand_hex(32);
+        let expires = now + Self::TTL;
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.expires > now);
+        pending.insert(challenge.clone(), PendingLogin { user_id: user_id.to_string(), device: device.to_string(), ip: ip.to_string(), expires });
+        (challenge, expires)
+    }
+
+    /// Stops waiting on `challenge`, handing back the login that was waiting on it if it hasn't expired. Only one of several requests
+    /// answering the same challenge at once gets it, so a code can't be used to start more than one session.
+    pub fn take(&self, challenge: &str, now: i64) -> Option<PendingLogin>
+    {
+        self.pending.lock().unwrap().remove(challenge).filter(|p| p.expires > now)
+    }
+
+    /// Waits on `challenge` again, after an answer to it was refused, so it can be answered again until it expires.
+    pub fn put_back(&self, challenge: &str, pending: PendingLogin)
+    {
+        self.pending.lock().unwrap().insert(challenge.to_string(), pending);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn codes_match_the_rfc_6238_test_vectors()
+    {
+        let secret = b"12345678901234567890";
+        // RFC 6238 appendix B, truncated to 6 digits
+        for (time, code) in [(59, 287082), (1111111109, 81804), (1111111111, 50471), (1234567890, 5924), (2000000000, 279037)]
+        {
+            assert_eq!(totp(secret, time / 30), code, "T = {time}");
+        }
+    }
+
+    #[test]
+    fn challenges_are_handed_out_once()
+    {
+        let challenges = LoginChallenges::default();
+        let (challenge, expires) = challenges.issue("alice", "laptop", "127.0.0.1", 0);
+        let pending = challenges.take(&challenge, 0).expect("a fresh challenge should be waiting");
+        assert!(challenges.take(&challenge, 0).is_none(), "a challenge must not be answered twice at once");
+
+        challenges.put_back(&challenge, pending);
+        assert_eq!(challenges.take(&challenge, 0).map(|p| p.user_id), Some(String::from("alice")));
+        challenges.put_back(&challenge, PendingLogin { user_id: String::from("alice"), device: String::new(), ip: String::new(), expires });
+        assert!(challenges.take(&challenge, expires).is_none(), "expired challenges are dropped");
+    }
+
+    #[test]
+    fn codes_and_recovery_codes_only_work_once()
+    {
+        let mut two_factor = TwoFactor::generate();
+        let now = 1_700_000_000_000;
+        let code = format!("{:06}", totp(&two_factor.secret, now / STEP));
+        assert!(two_factor.accept(&code, now));
+        assert!(!two_factor.accept(&code, now), "a code must not be accepted twice");
+
+        let next = format!("{:06}", totp(&two_factor.secret, now / STEP + 1));
+        assert!(two_factor.accept(&next, now), "the next step's code is accepted, for clock drift");
+
+        let codes = two_factor.regenerate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(two_factor.accept(&codes[3].to_uppercase(), now));
+        assert!(!two_factor.accept(&codes[3], now));
+        assert_eq!(two_factor.recovery_codes.len(), RECOVERY_CODES - 1);
+    }
+}
@@ -0,0 +1,52 @@
+use super::{structs::{Account, WebsocketClient}, utils};
+
+//----------------------------------------------//
+//                                              //
+//               Stable account IDs             //
+//                                              //
+//----------------------------------------------//
+
+impl Account
+{
+    /// The length of an account ID, in characters.
+    pub const ID_LENGTH: usize = 32;
+
+    /// A fresh random account ID: 32 lowercase hex characters.
+    pub fn new_id() -> String
+    {
+        utils::rand_hex(Account::ID_LENGTH / 2)
+    }
+
+    /// Whether `handle` has the shape of an account ID. Usernames never do (see
+    /// [`ValidationPolicy::check_username`][`super::validation::ValidationPolicy::check_username`]), so a handle that does is looked up as an ID first.
+    pub fn looks_like_id(handle: &str) -> bool
+    {
+        handle.len() == Account::ID_LENGTH && handle.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+}
+
+impl WebsocketClient
+{
+    /// Whether `handle`, as sent by a client, names the account this connection is registered as: by its username or its ID.
+    pub fn is(&self, handle: &str) -> bool
+    {
+        self.username == handle || self.user_id == handle
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn ids_are_unique_and_recognisable()
+    {
+        let id = Account::new_id();
+        assert_ne!(id, Account::new_id());
+        assert!(Account::looks_like_id(&id));
+        assert!(!Account::looks_like_id("alice"));
+        assert!(!Account::looks_like_id(&id.to_uppercase()), "IDs are lowercase");
+        assert!(!Account::looks_like_id(&id[1..]));
+    }
+}
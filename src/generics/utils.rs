@@ -1,26 +1,89 @@
 
+use std::sync::OnceLock;
+use hmac::{Hmac, Mac};
 use rand::RngCore;
-use super::structs::{Account, WSPacket, WSAction};
+use sha2::Sha256;
+use tracing::warn;
+use super::{errors::ApiError, structs::{Session, WSPacket, WSAction}};
+use crate::db::storage::Storage;
 
 
+/// Looks up the session an access token belongs to, making sure it hasn't expired. Sessions found to have lapsed are deleted on the spot.
+///
+/// ## Return Values:
+/// * [`Result<Session, ApiError>`][`std::result::Result`] - The session, [`ApiError::Unauthorized`] if there is no such session, or [`ApiError::Expired`] if it or its access token expired.
+pub async fn authenticate(db: &dyn Storage, token: &str) -> Result<Session, ApiError>
+{
+    let Some(session) = db.get_session(&hash_token(token)).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
+
+    let now = now();
+    if session.lapsed(now) { db.delete_session(&session.user_id, &session.id).await?; }
+    session.check(now)?;
+    Ok(session)
+}
+
 /// Verify a user's session
 ///
 /// ## Parameters:
-/// * username: [`&String`][`std::string::String`] // The username of the user to verify
-/// * session_id: [`&String`][`std::string::String`] // The session id of the user to verify
+/// * db: [`&dyn Storage`][`crate::db::storage::Storage`] // The storage backend to look the account up in
+/// * user_id: [`&String`][`std::string::String`] // The account ID of the user to verify
+/// * session_id: [`&String`][`std::string::String`] // The session id (token) of the user to verify
 ///
 ///
 /// ## Return Values:
-/// * [`bool`][`std::primitive::bool`] // True if the session id is valid, false if it is not
+/// * [`Result<Session, ApiError>`][`std::result::Result`] // The session if the session id is valid, unexpired and belongs to the user, [`ApiError::Unauthorized`] or [`ApiError::Expired`] if it isn't
 ///
 ///
-pub async fn verify(username: &String, session_id: &String) -> Result<bool, String>
+pub async fn verify(db: &dyn Storage, user_id: &str, session_id: &str) -> Result<Session, ApiError>
+{
+    let session = authenticate(db, session_id).await?;
+    if session.user_id != user_id { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
+    Ok(session)
+}
+
+/// Parses a JSON request payload, answering with a [`ApiError::Validation`] if it doesn't match the expected shape.
+pub fn parse_payload<T: serde::de::DeserializeOwned>(payload: &str) -> Result<T, ApiError>
+{
+    serde_json::from_str::<T>(payload).map_err(|_| ApiError::Validation(String::from("Invalid Payload.")))
+}
+
+/// Reads and parses an environment variable, falling back to `default` if it is unset or unparseable.
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T
+{
+    dotenv::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// The current server time, in milliseconds since the Unix epoch.
+pub fn now() -> i64
+{
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// The keyed hash (HMAC-SHA256) a session token is stored and looked up as, so the database never holds a token that could be used to log in.
+///
+/// The key is read from `SESSION_TOKEN_KEY`. Without one, a random key is made up on startup, which logs everyone out on every restart.
+pub fn hash_token(token: &str) -> String
 {
-    Account::get_account(username)
-    .await
-    .map_err(|e| e)?
-    .map(|a| &a.session_id == session_id)
-    .ok_or_else(|| String::from("Tried to validate with a non-existent account."))
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    let key = KEY.get_or_init(|| match dotenv::var("SESSION_TOKEN_KEY")
+    {
+        Ok(key) if !key.is_empty() => key.into_bytes(),
+        _ =>
+        {
+            warn!("SESSION_TOKEN_KEY is not set, so sessions won't survive a restart.");
+            let mut key = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        }
+    });
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
 }
 
 pub fn rand_hex(len: usize) -> String
@@ -30,12 +93,13 @@ pub fn rand_hex(len: usize) -> String
     hex::encode(bytes)
 }
 
-pub fn gen_err(msg: &str) -> String
+pub fn info_packet(msg: &str) -> WSPacket
 {
-    return format!("{} ({})", msg, std::env::current_dir().unwrap().to_str().unwrap().to_string());
+    WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::Info(msg.to_string())}
 }
 
-pub fn info_packet(msg: &str) -> WSPacket
+/// The websocket counterpart of an error response: carries the same `{code, message}` body as the HTTP routes.
+pub fn error_packet(err: &ApiError) -> WSPacket
 {
-    WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::Info(msg.to_string())}
+    WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::Error(err.body())}
 }
\ No newline at end of file
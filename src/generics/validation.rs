@@ -0,0 +1,240 @@
+use std::{collections::HashSet, sync::OnceLock};
+use tracing::{info, warn};
+use rsa::{pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding}, traits::PublicKeyParts, RsaPublicKey};
+use data_encoding::BASE64;
+use super::{errors::{ApiError, FieldError}, structs::{Account, KeyBackup, KeyType}, utils, x25519};
+
+//----------------------------------------------//
+//                                              //
+//        Validation of new account details     //
+//                                              //
+//----------------------------------------------//
+
+/// The smallest RSA modulus accepted for uploaded public keys, in bits.
+const MIN_RSA_BITS: usize = 2048;
+/// The largest encrypted private key backup accepted, in bytes.
+const MAX_KEY_BACKUP: usize = 16 * 1024;
+const MIN_SALT_LENGTH: usize = 16;
+
+/// Passwords that are refused no matter the policy, one per line. Compared case-insensitively.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// What usernames and passwords new accounts may have.
+///
+/// Usernames are limited to ASCII letters, digits and a few symbols, so names can't differ only by Unicode confusables, whitespace or
+/// control characters. Uniqueness is case-insensitive, which the storage backends enforce with a unique index.
+///
+/// ## Fields
+/// * [`username_min`][`usize`] - The shortest a username may be (`USERNAME_MIN_LENGTH`, default 3).
+/// * [`username_max`][`usize`] - The longest a username may be (`USERNAME_MAX_LENGTH`, default 32).
+/// * [`username_symbols`][`std::string::String`] - Characters allowed in usernames besides ASCII letters and digits (`USERNAME_SYMBOLS`, default `_.-`).
+///   A username must start and end with a letter or digit.
+/// * [`password_min`][`usize`] - The fewest characters a password may have (`PASSWORD_MIN_LENGTH`, default 10).
+/// * [`password_max`][`usize`] - The most characters a password may have, which bounds the work of hashing it (`PASSWORD_MAX_LENGTH`, default 128).
+/// * [`blocklist`][`HashSet`] - Refused passwords, lowercased: a built-in list of common passwords, plus every line of `PASSWORD_BLOCKLIST_FILE` if set
+///   (e.g. a list of breached passwords).
+#[derive(Debug, Clone)]
+pub struct ValidationPolicy
+{
+    pub username_min: usize,
+    pub username_max: usize,
+    pub username_symbols: String,
+    pub password_min: usize,
+    pub password_max: usize,
+    pub blocklist: HashSet<String>
+}
+
+impl Default for ValidationPolicy
+{
+    fn default() -> ValidationPolicy
+    {
+        ValidationPolicy {
+            username_min: 3,
+            username_max: 32,
+            username_symbols: String::from("_.-"),
+            password_min: 10,
+            password_max: 128,
+            blocklist: blocklist(COMMON_PASSWORDS)
+        }
+    }
+}
+
+impl ValidationPolicy
+{
+    pub fn from_env() -> ValidationPolicy
+    {
+        let default = ValidationPolicy::default();
+        let mut blocklist = default.blocklist;
+        if let Ok(path) = dotenv::var("PASSWORD_BLOCKLIST_FILE")
+        {
+            match std::fs::read_to_string(&path)
+            {
+                Ok(list) =>
+                {
+                    let before = blocklist.len();
+                    blocklist.extend(self::blocklist(&list));
+                    info!("Loaded {} blocked passwords from `{path}`", blocklist.len() - before);
+                },
+                Err(e) => warn!("Failed to read PASSWORD_BLOCKLIST_FILE `{path}`, only common passwords are blocked: {e}")
+            }
+        }
+
+        ValidationPolicy {
+            username_min: utils::env_or("USERNAME_MIN_LENGTH", default.username_min),
+            username_max: utils::env_or("USERNAME_MAX_LENGTH", default.username_max),
+            username_symbols: utils::env_or("USERNAME_SYMBOLS", default.username_symbols),
+            password_min: utils::env_or("PASSWORD_MIN_LENGTH", default.password_min),
+            password_max: utils::env_or("PASSWORD_MAX_LENGTH", default.password_max),
+            blocklist
+        }
+    }
+
+    /// The policy in effect, read from the environment the first time it is needed.
+    pub fn current() -> &'static ValidationPolicy
+    {
+        static POLICY: OnceLock<ValidationPolicy> = OnceLock::new();
+        POLICY.get_or_init(ValidationPolicy::from_env)
+    }
+
+    /// Everything wrong with `username`, if anything.
+    pub fn check_username(&self, username: &str) -> Option<String>
+    {
+        let length = username.chars().count();
+        if length < self.username_min || length > self.username_max
+        { return Some(format!("Must be between {} and {} characters long.", self.username_min, self.username_max)) }
+
+        if let Some(c) = username.chars().find(|&c| !c.is_ascii_alphanumeric() && !self.username_symbols.contains(c))
+        {
+            return Some(match c.is_ascii_graphic()
+            {
+                true => format!("May only contain letters, digits and `{}`, not `{c}`.", self.username_symbols),
+                false => format!("May only contain letters, digits and `{}`.", self.username_symbols)
+            })
+        }
+
+        let edges = [username.chars().next(), username.chars().last()];
+        if edges.iter().flatten().any(|c| !c.is_ascii_alphanumeric())
+        { return Some(String::from("Must start and end with a letter or digit.")) }
+
+        // handles are looked up as IDs first, so a username like that could never be found
+        if Account::looks_like_id(&username.to_ascii_lowercase()) { return Some(String::from("Must not look like an account ID.")) }
+        None
+    }
+
+    /// Everything wrong with `password` for an account called `username`, if anything.
+    pub fn check_password(&self, username: &str, password: &str) -> Option<String>
+    {
+        let length = password.chars().count();
+        if length < self.password_min { return Some(format!("Must be at least {} characters long.", self.password_min)) }
+        if length > self.password_max { return Some(format!("Must be at most {} characters long.", self.password_max)) }
+
+        let lowered = password.to_lowercase();
+        if self.blocklist.contains(&lowered) { return Some(String::from("Is too common, choose another one.")) }
+        if !username.is_empty() && lowered.contains(&username.to_lowercase()) { return Some(String::from("Must not contain the username.")) }
+        None
+    }
+
+    /// Checks the username and password of a new account.
+    ///
+    /// ## Returns
+    /// * [`Result<(), ApiError>`][`std::result::Result`] - Ok if both are allowed, or an [`ApiError::InvalidFields`] naming every one that isn't.
+    pub fn check_new_account(&self, username: &str, password: &str) -> Result<(), ApiError>
+    {
+        let fields: Vec<FieldError> = [("username", self.check_username(username)), ("password", self.check_password(username, password))]
+            .into_iter()
+            .filter_map(|(field, problem)| problem.map(|p| FieldError::new(field, p)))
+            .collect();
+        if fields.is_empty() { return Ok(()) }
+        Err(ApiError::InvalidFields(String::from("The account's details are invalid."), fields))
+    }
+}
+
+/// Parses a public key uploaded by a client. RSA keys must be a PEM-encoded SubjectPublicKeyInfo of at least 2048 bits;
+/// X25519 keys must be 32 bytes, base64 encoded.
+///
+/// ## Returns
+/// * [`Result<Vec<u8>, String>`][`std::result::Result`] - The key encoded the way server-generated keys of its type are stored, or what is wrong with it.
+pub fn parse_public_key(key_type: KeyType, key: &str) -> Result<Vec<u8>, String>
+{
+    match key_type
+    {
+        KeyType::Rsa => parse_rsa_public_key(key),
+        KeyType::X25519 => BASE64
+            .decode(key.trim().as_bytes())
+            .ok()
+            .filter(|bytes| x25519::public_key(bytes).is_some())
+            .ok_or_else(|| String::from("Must be a 32 byte X25519 public key in base64."))
+    }
+}
+
+fn parse_rsa_public_key(pem: &str) -> Result<Vec<u8>, String>
+{
+    let key = RsaPublicKey::from_public_key_pem(pem.trim())
+        .map_err(|_| String::from("Must be an RSA public key in PEM-encoded SubjectPublicKeyInfo format."))?;
+    if key.size() * 8 < MIN_RSA_BITS { return Err(format!("Must be at least {MIN_RSA_BITS} bits.")) }
+    key.to_public_key_pem(LineEnding::CRLF)
+        .map(|pem| pem.into_bytes())
+        .map_err(|_| String::from("Must be an RSA public key in PEM-encoded SubjectPublicKeyInfo format."))
+}
+
+/// What is wrong with a private key backup uploaded by a client, if anything. Its contents can't be checked, only its shape.
+pub fn check_key_backup(backup: &KeyBackup) -> Option<String>
+{
+    if backup.priv_key_enc.is_empty() || backup.priv_key_enc.len() > MAX_KEY_BACKUP
+    { return Some(format!("The encrypted key must be between 1 and {MAX_KEY_BACKUP} bytes.")) }
+    if backup.nonce.len() != 12 { return Some(String::from("The nonce must be 12 bytes.")) }
+    if backup.key_wrap.algorithm != "argon2id" { return Some(String::from("The key must be derived with argon2id.")) }
+    if backup.key_wrap.salt.len() < MIN_SALT_LENGTH { return Some(format!("The salt must be at least {MIN_SALT_LENGTH} bytes.")) }
+    None
+}
+
+fn blocklist(list: &str) -> HashSet<String>
+{
+    list.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_lowercase).collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn usernames_are_limited_to_a_plain_charset()
+    {
+        let policy = ValidationPolicy::default();
+        for ok in ["alice", "Bob_99", "c.d-e"] { assert_eq!(policy.check_username(ok), None, "{ok}"); }
+        for bad in ["", "al", "a".repeat(33).as_str(), "al ice", "alice\n", "аlice", "_alice", "alice.", "al|ce"]
+        {
+            assert!(policy.check_username(bad).is_some(), "{bad:?} should be refused");
+        }
+        assert!(policy.check_username(&Account::new_id().to_uppercase()).is_some(), "usernames can't pass for account IDs");
+    }
+
+    #[test]
+    fn weak_passwords_are_refused_per_field()
+    {
+        let policy = ValidationPolicy::default();
+        assert_eq!(policy.check_password("alice", "correct horse battery"), None);
+        assert!(policy.check_password("alice", "short").is_some());
+        assert!(policy.check_password("alice", "Password123").is_some(), "common passwords are refused case-insensitively");
+        assert!(policy.check_password("alice", "ALICE-is-great").is_some());
+
+        let Err(ApiError::InvalidFields(_, fields)) = policy.check_new_account(" ", "1234567890")
+        else { panic!("both fields should be refused") };
+        assert_eq!(fields.iter().map(|f| f.field.as_str()).collect::<Vec<_>>(), ["username", "password"]);
+    }
+
+    #[test]
+    fn uploaded_public_keys_must_be_large_enough_spki()
+    {
+        let small = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let pem = RsaPublicKey::from(&small).to_public_key_pem(LineEnding::LF).unwrap();
+        assert_eq!(parse_public_key(KeyType::Rsa, &pem), Err(format!("Must be at least {MIN_RSA_BITS} bits.")));
+        assert!(parse_public_key(KeyType::Rsa, "-----BEGIN PUBLIC KEY-----\nnope\n-----END PUBLIC KEY-----").is_err());
+
+        let (_, public) = x25519::generate();
+        assert_eq!(parse_public_key(KeyType::X25519, &BASE64.encode(&public)), Ok(public.to_vec()));
+        assert!(parse_public_key(KeyType::X25519, &BASE64.encode(&[0; 32])).is_err());
+        assert!(parse_public_key(KeyType::X25519, &pem).is_err());
+    }
+}
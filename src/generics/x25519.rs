@@ -0,0 +1,101 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use super::errors::ApiError;
+
+//----------------------------------------------//
+//                                              //
+//       X25519 conversation key wrapping       //
+//                                              //
+//----------------------------------------------//
+
+/// Binds derived keys to this use, so they can't be confused with keys derived from the same secret for anything else.
+const INFO: &[u8] = b"crim conversation key v1";
+pub const KEY_LENGTH: usize = 32;
+
+/// A new X25519 key pair.
+///
+/// ## Returns
+/// * [`([u8; 32], [u8; 32])`][`x25519_dalek::StaticSecret`] - The private key and the public key, as raw bytes.
+pub fn generate() -> ([u8; KEY_LENGTH], [u8; KEY_LENGTH])
+{
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    (secret.to_bytes(), public.to_bytes())
+}
+
+/// Reads a raw 32 byte X25519 public key, refusing the all-zero key.
+pub fn public_key(bytes: &[u8]) -> Option<PublicKey>
+{
+    let bytes: [u8; KEY_LENGTH] = bytes.try_into().ok()?;
+    (bytes != [0; KEY_LENGTH]).then(|| PublicKey::from(bytes))
+}
+
+/// The AES-256-GCM key and nonce for one sealed box: HKDF-SHA256 over the shared secret, salted with both public keys.
+/// Every box has a fresh ephemeral key, so the key is only ever used once and a derived nonce is safe.
+fn box_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> (Aes256Gcm, [u8; 12])
+{
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut okm = [0; KEY_LENGTH + 12];
+    Hkdf::<Sha256>::new(Some(&salt), shared).expand(INFO, &mut okm).expect("44 bytes is a valid HKDF-SHA256 output length");
+    let (key, nonce) = okm.split_at(KEY_LENGTH);
+    (Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)), nonce.try_into().expect("split at the key length"))
+}
+
+/// Encrypts `plaintext` so only the holder of `recipient`'s private key can read it: an ephemeral X25519 key agreement, HKDF-SHA256 and
+/// AES-256-GCM, along the lines of HPKE's base mode.
+///
+/// ## Returns
+/// * [`Result<Vec<u8>, ApiError>`][`std::result::Result`] - The ephemeral public key (32 bytes) followed by the ciphertext, or an [`ApiError::Crypto`]
+///   if the recipient's key is unusable.
+pub fn seal(recipient: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, ApiError>
+{
+    let unusable = || ApiError::Crypto(String::from("The recipient's X25519 public key is unusable."));
+    let recipient = public_key(recipient).ok_or_else(unusable)?;
+
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+    // a low-order recipient key would give a shared secret anyone can compute
+    if !shared.was_contributory() { return Err(unusable()) }
+
+    let (cipher, nonce) = box_key(shared.as_bytes(), &ephemeral_public, &recipient);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| ApiError::Crypto(String::from("Failed to encrypt the conversation key.")))?;
+    Ok([ephemeral_public.as_bytes().as_slice(), &ciphertext].concat())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// What clients do with a sealed box.
+    fn open(secret: [u8; KEY_LENGTH], sealed: &[u8]) -> Option<Vec<u8>>
+    {
+        let secret = StaticSecret::from(secret);
+        let (ephemeral, ciphertext) = sealed.split_at(KEY_LENGTH);
+        let ephemeral = public_key(ephemeral)?;
+        let shared = secret.diffie_hellman(&ephemeral);
+        let (cipher, nonce) = box_key(shared.as_bytes(), &ephemeral, &PublicKey::from(&secret));
+        cipher.decrypt(Nonce::from_slice(&nonce), ciphertext).ok()
+    }
+
+    #[test]
+    fn only_the_recipient_can_open_a_sealed_key()
+    {
+        let (secret, public) = generate();
+        let (other_secret, _) = generate();
+        let sealed = seal(&public, b"conversation key").unwrap();
+        assert_eq!(sealed.len(), KEY_LENGTH + b"conversation key".len() + 16);
+        assert_ne!(seal(&public, b"conversation key").unwrap(), sealed, "every box uses a fresh ephemeral key");
+
+        assert_eq!(open(secret, &sealed).as_deref(), Some(b"conversation key".as_slice()));
+        assert_eq!(open(other_secret, &sealed), None);
+        assert!(seal(&[0; KEY_LENGTH], b"conversation key").is_err());
+        assert!(seal(&public[..31], b"conversation key").is_err());
+    }
+}
@@ -1,8 +1,8 @@
 mod db;
 mod generics;
 mod routes;
-use tokio;
 use axum::{
+    middleware,
     routing::get,
     routing::post,
     Router,
@@ -12,7 +12,8 @@ use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 use tracing::log::{debug, error, info};
-use crate::generics::structs::ClientStore;
+use std::sync::Arc;
+use crate::generics::{rate_limit::{self, RateLimitConfig, RateLimiter}, structs::{AppState, ClientStore}, throttle::{self, LoginThrottle, ThrottleConfig}, two_factor::LoginChallenges};
 
 #[tokio::main]
 async fn main()
@@ -28,23 +29,75 @@ async fn main()
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    if let Err(e) = db::mongo::ping().await
+    let db = match db::from_env().await
     {
-        error!("Failed to connect to MongoDB! {e}");
+        Ok(db) => db,
+        Err(e) => { error!("{e}"); return; }
+    };
+
+    if let Err(e) = db.ping().await
+    {
+        error!("Failed to connect to the database! {e}");
         return;
     }
-    else { info!("Connected to MongoDB!") }
+    else { info!("Connected to the database!") }
+
+    let limits = match RateLimitConfig::from_env()
+    {
+        Ok(limits) => limits,
+        Err(e) => { error!("{e}"); return; }
+    };
 
-    let state = ClientStore::default();
+    let state = AppState {
+        clients: ClientStore::default(),
+        db,
+        logins: Arc::new(LoginThrottle::new(ThrottleConfig::from_env())),
+        limiter: Arc::new(RateLimiter::new(limits)),
+        challenges: Arc::new(LoginChallenges::default())
+    };
+    tokio::spawn(routes::ws::ws::sweep_sessions(state.clone()));
+    tokio::spawn(routes::auth::delete::sweep_deletions(state.clone()));
+    tokio::spawn(throttle::report(state.logins.clone()));
+    tokio::spawn(rate_limit::prune(state.limiter.clone()));
 
 
     let app = Router::new()
         .route("/api/auth/create", post(routes::auth::create::create_user))
+        .route("/api/auth/create_with_key", post(routes::auth::create::create_user_with_key))
         .route("/api/auth/delete", post(routes::auth::delete::delete_user))
+        .route("/api/auth/delete/cancel", post(routes::auth::delete::cancel_deletion))
         .route("/api/auth/login", post(routes::auth::login::login_user))
-        .route("/api/auth/get/:{sid}", get(routes::auth::get::get))
+        .route("/api/auth/login/2fa", post(routes::auth::two_factor::verify_login))
+        .route("/api/auth/get", get(routes::auth::get::get))
+        .route("/api/auth/lookup", post(routes::auth::get::lookup))
         .route("/api/auth/change_password", post(routes::auth::change_password::change_password))
+        .route("/api/auth/change_username", post(routes::auth::change_username::change_username))
+        .route("/api/auth/refresh", post(routes::auth::sessions::refresh))
+        .route("/api/auth/sessions", get(routes::auth::sessions::list))
+        .route("/api/auth/sessions/revoke", post(routes::auth::sessions::revoke))
+        .route("/api/auth/sessions/revoke_others", post(routes::auth::sessions::revoke_others))
+        .route("/api/auth/key_backup", post(routes::auth::key_backup::upload))
+        .route("/api/auth/replace_key", post(routes::auth::identity::replace_key))
+        .route("/api/auth/key_history", post(routes::auth::identity::key_history))
+        .route("/api/auth/safety_number", post(routes::auth::identity::safety_number))
+        .route("/api/auth/devices", get(routes::auth::devices::list))
+        .route("/api/auth/devices/add", post(routes::auth::devices::add))
+        .route("/api/auth/devices/approve", post(routes::auth::devices::approve))
+        .route("/api/auth/devices/challenge", post(routes::auth::devices::challenge))
+        .route("/api/auth/devices/sign_in", post(routes::auth::devices::sign_in))
+        .route("/api/auth/devices/remove", post(routes::auth::devices::remove))
+        .route("/api/auth/recovery/set", post(routes::auth::recovery::set))
+        .route("/api/auth/recovery/remove", post(routes::auth::recovery::remove))
+        .route("/api/auth/recovery/params", post(routes::auth::recovery::params))
+        .route("/api/auth/recovery/fetch", post(routes::auth::recovery::fetch))
+        .route("/api/auth/recovery/reset", post(routes::auth::recovery::reset))
+        .route("/api/auth/2fa/enroll", post(routes::auth::two_factor::enroll))
+        .route("/api/auth/2fa/confirm", post(routes::auth::two_factor::confirm))
+        .route("/api/auth/2fa/disable", post(routes::auth::two_factor::disable))
+        .route("/api/message/history", post(routes::message::history::history))
+        .route("/api/message/keys", post(routes::message::keys::keys))
         .route("/api/ws", get(routes::ws::ws::ws_handler))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_http))
         .with_state(state)
         .layer(
             TraceLayer::new_for_http()
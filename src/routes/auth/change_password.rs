@@ -1,66 +1,45 @@
-use super::generics::{utils, structs::{Account, ClientAccount}};
-use crate::db::mongo;
-use argon2::{self, Config};
-use axum::{http::StatusCode, response::IntoResponse};
+use super::generics::{auth::{self, Authenticated}, errors::{ApiError, FieldError}, utils, structs::{AppState, PasswordChange}, validation::ValidationPolicy};
+use crate::routes::ws::ws;
+use axum::extract::{ConnectInfo, State};
+use std::net::SocketAddr;
 
-/// Changes a user's password.
+/// Changes a user's password, re-wrapping their private key under the new one with a fresh salt (unless the key was generated by the client).
+/// Every other session of the account is revoked, and their websocket connections closed.
+///
+/// Wrong passwords count towards the same lockout as failed logins, so a stolen session can't be used to guess the password either.
 ///
 /// ## Arguments
-/// * [`payload`][`std::string::String`] - A JSON string containing a serialized ClientAccount of the account to create.
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`PasswordChange`].
 ///
 /// ## Returns
-/// * [`StatusCode`][`axum::http::StatusCode`] - The status code of the operation:
-///    * 200 OK if the password changed successfully
-///    * 400 BAD REQUEST if the account doesn't exist, or if the payload is invalid
-///    * 401 UNAUTHORIZED if the password is incorrect or the SID is incorrect
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or the new password is refused by the [`ValidationPolicy`]
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
 ///    * 500 INTERNAL SERVER ERROR if there was an error connecting to the database at any point
 ///
-pub async fn change_password(payload: String) -> impl IntoResponse
+pub async fn change_password(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
 {
-    // parse the string to an account value
-    let Ok(account) = serde_json::from_str::<ClientAccount>(&payload) 
-    else { return (StatusCode::BAD_REQUEST, utils::gen_err("Invalid Payload."))};
-    
-    if let Err(_) = mongo::ping().await
-    {
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Error occurred connecting to database.".to_string());
-    }
-
-    let server_account = match Account::get_account(&account.username).await
-    {
-        Ok(Some(account)) => account,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e),
-        Ok(None) => return (StatusCode::BAD_REQUEST, utils::gen_err("Tried changing the password of a non-existent account. Confirm the username is correct."))
-    };
+    let change: PasswordChange = utils::parse_payload(&payload)?;
+    let (mut account, session) = (auth.account, auth.session);
     
     // requires extra layer of security, will be asked for password to confirm
+    auth::check_password(&state, &account, &change.password, addr.ip())?;
 
-    let Ok(true) = argon2::verify_encoded(&server_account.hash, &account.password.as_bytes()) // doesn't check for an Argon2 error
-    else { return (StatusCode::UNAUTHORIZED, utils::gen_err("Invalid password.")) };
+    if let Some(problem) = ValidationPolicy::current().check_password(&account.username, &change.new_password)
+    { return Err(ApiError::InvalidFields(String::from("The new password is invalid."), vec![FieldError::new("new_password", problem)])) }
 
-    match utils::verify(&account.username, &account.session_id).await
-    {
-        Ok(false) => { return (StatusCode::UNAUTHORIZED, utils::gen_err("Invalid session ID.")) },
-        Err(e) =>  { return (StatusCode::INTERNAL_SERVER_ERROR, e) } ,
-        Ok(true) => {()}
-    }
-
-    let salt = utils::rand_hex(32);
-    let config = Config::default();
-    let hash: String = argon2::hash_encoded(account.password.as_bytes(), &salt.as_bytes(), &config).unwrap();
-
-    let account: Account = Account {
-        username: server_account.username,
-        hash,
-        public_key: server_account.public_key,
-        priv_key_enc: server_account.priv_key_enc,
-        nonce: server_account.nonce,
-        friends: server_account.friends,
-        friend_requests: server_account.friend_requests,
-        session_id: utils::rand_hex(32) // invalidate session on password change
-    };
+    account.hash = auth::hash_password(&change.new_password)?;
+    // a client-generated key's backup is the client's to re-encrypt (see [`key_backup::upload`][`super::key_backup::upload`])
+    if !account.client_keys { account.rewrap_key(&change.password, &change.new_password)?; }
     
-    if let Err(e) = Account::update_account(&account).await
-    { return (StatusCode::INTERNAL_SERVER_ERROR, utils::gen_err(&e)) }
-    else { return (StatusCode::OK, "Password changed successfully.".to_string())}
+    state.db.update_account(&account).await?;
+
+    // whoever knew the old password may be logged in elsewhere; only the session that changed it survives
+    let revoked = state.db.delete_sessions(&account.id, Some(&session.id)).await?;
+    ws::end_sessions(&state.clients, &revoked, "Your password was changed.").await;
+    Ok(String::from("Password changed successfully."))
 }
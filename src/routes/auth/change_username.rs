@@ -0,0 +1,64 @@
+use super::generics::{
+    auth::{self, Authenticated}, errors::{ApiError, FieldError}, utils, validation::ValidationPolicy,
+    structs::{AppState, RenameNotice, UsernameChange, WSAction, WSPacket}
+};
+use crate::routes::ws::ws;
+use axum::extract::{ConnectInfo, State};
+use std::net::SocketAddr;
+use tracing::{error, info};
+
+/// Changes a user's username. Everything else refers to the account by its ID, which stays the same (see
+/// [`Storage::rename_account`][`crate::db::storage::Storage::rename_account`]), and the old username can't be taken by anyone afterwards.
+/// Sessions and open websocket connections carry over to the new username.
+///
+/// Everyone online who knows the account (its friends, the other side of its friend requests, the members of its conversations, and its own other clients)
+/// is told about the change (arbitrary info 12), so they can replace the old username locally.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`UsernameChange`].
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, the new username is the current one, or it is refused by the [`ValidationPolicy`]
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 409 CONFLICT if the new username is taken, or belonged to a deleted account
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///    * 500 INTERNAL SERVER ERROR if there was an error connecting to the database at any point
+///
+pub async fn change_username(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
+{
+    let change: UsernameChange = utils::parse_payload(&payload)?;
+    let account = auth.account;
+    auth::check_password(&state, &account, &change.password, addr.ip())?;
+
+    let (old, new) = (account.username.as_str(), change.new_username.as_str());
+    if let Some(problem) = ValidationPolicy::current().check_username(new)
+    { return Err(ApiError::InvalidFields(String::from("The new username is invalid."), vec![FieldError::new("new_username", problem)])) }
+    if new == old { return Err(ApiError::Validation(String::from("That is already your username."))) }
+
+    if !state.db.rename_account(&account.id, new).await? { return Err(ApiError::Unauthorized(String::from("Account not found."))) }
+    ws::rename_user(&state.clients, &account.id, new).await;
+
+    let mut others: Vec<String> = account.friends
+        .iter()
+        .chain(account.friend_requests.iter().flat_map(|r| [&r.sender, &r.receiver]))
+        .cloned()
+        .chain(state.db.get_conversations(&account.id).await?.into_iter().flat_map(|c| c.users))
+        .collect();
+    others.push(account.id.clone());
+    others.sort();
+    others.dedup();
+
+    let notice = serde_json::to_string(&RenameNotice { id: account.id.clone(), username: old.to_string(), new_username: new.to_string() }).unwrap();
+    for client in state.clients.lock().await.values().filter(|c| others.contains(&c.user_id))
+    {
+        let packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(notice.clone(), 12) };
+        if client.socket.send(packet).await.is_err()
+        { error!("Failed to send the renaming of {old} to client {}. Did they abruptly disconnect?", client.username) }
+    }
+    info!("Renamed account {old} to {new}");
+    Ok(format!("Username changed to {new}."))
+}
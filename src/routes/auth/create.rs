@@ -1,74 +1,117 @@
-use super::generics::{utils, structs::{Account, ClientAccount}};
-use crate::db::mongo;
-use argon2::{self, Config};
-use axum::{debug_handler, http::StatusCode, response::IntoResponse};
-use rsa::{pkcs8::{EncodePrivateKey, EncodePublicKey}, RsaPrivateKey, RsaPublicKey};
-use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng, generic_array},
-    Aes256Gcm, Nonce, Key // Or `Aes128Gcm`
-    
-};
+use super::generics::{auth, errors::{ApiError, FieldError}, utils, structs::{Account, AppState, ClientAccount, ClientKeyRegistration, KeyChange, KeyType, KeyWrap}, validation::{self, ValidationPolicy}, x25519};
+use axum::{debug_handler, extract::State};
 
-/// Creates a user entry in the database.
+/// Creates a user entry in the database, with a new X25519 key pair whose private key is wrapped under the password.
+/// The username and password must satisfy the [`ValidationPolicy`], and the username must not be taken, ignoring case.
 ///
 /// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
 /// * [`payload`][`std::string::String`] - A JSON string containing a serialized ClientAccount of the account to create.
 ///
 /// ## Returns
-/// * [`StatusCode`][`axum::http::StatusCode`] - The status code of the operation:
-///    * 200 OK if the account was created successfully
-///    * 400 BAD REQUEST if the account already exists
+/// * [`Result<(), ApiError>`][`std::result::Result`] - 200 OK if the account was created successfully, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or the username or password is refused (with a `fields` entry for each)
+///    * 409 CONFLICT if the account already exists, under any capitalization
 ///
 #[debug_handler]
-pub async fn create_user(payload: String) -> impl IntoResponse
+pub async fn create_user(State(state): State<AppState>, payload: String) -> Result<(), ApiError>
 {
     // parse the string to an account value
-    let Ok(account) = serde_json::from_str::<ClientAccount>(&payload) 
-    else { return (StatusCode::BAD_REQUEST, utils::gen_err("Invalid Payload."))};
+    let account: ClientAccount = utils::parse_payload(&payload)?;
+    ValidationPolicy::current().check_new_account(&account.username, &account.password)?;
     
-    if let Err(_) = mongo::ping().await
-    {
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Error occurred connecting to database.".to_string());
-    }
-
-    if let Err(e) = Account::get_account(&account.username).await
-    {
-        return (StatusCode::BAD_REQUEST, e);
-    }
+    // saves generating keys for a name that is obviously taken; names differing only in case are caught by the backend's unique index
+    if state.db.get_account(&account.username).await?.is_some()
+    { return Err(ApiError::Conflict(String::from("An account with that username already exists."))) }
     // create account
 
     // first, create pw hash
-    let salt = utils::rand_hex(32);
-    let config = Config::default();
-    let hash: String = argon2::hash_encoded(account.password.as_bytes(), &salt.as_bytes(), &config).unwrap();
+    let hash: String = auth::hash_password(&account.password)?;
 
-    let priv_key: RsaPrivateKey = {
-        let mut rng: rand::prelude::ThreadRng = rand::thread_rng();
-        RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate a key")
-    };
-    let pub_key: RsaPublicKey = RsaPublicKey::from(&priv_key);
-    let public_key = pub_key.to_public_key_pem(rsa::pkcs8::LineEnding::CRLF).unwrap().as_bytes().to_vec();
-    let private_key = priv_key.to_pkcs8_pem(rsa::pkcs8::LineEnding::CRLF).unwrap();
-    let pvkeyhash: Vec<u8> = argon2::hash_raw(account.password.as_bytes(), b"00000000", &config).unwrap();
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng).to_vec();
-    let key = Key::<Aes256Gcm>::from_slice(&pvkeyhash);
-    println!("{:#?}", key);
-    let private_key = Aes256Gcm::new(&key).encrypt(&generic_array::GenericArray::clone_from_slice(nonce.as_slice()), private_key.as_bytes().as_ref()).unwrap();    
+    let (private_key, public_key) = x25519::generate();
+    let key_wrap = KeyWrap::generate();
+    let (private_key, nonce) = key_wrap.wrap(&account.password, &private_key)?;
     let account: Account = Account {
         username: account.username,
+        id: Account::new_id(),
         hash,
-        public_key,
+        public_key: public_key.to_vec(),
         priv_key_enc: private_key,
         nonce,
         friends: Vec::new(),
         friend_requests: Vec::new(),
-        session_id: "".to_string()
-        
+        two_factor: None,
+        key_wrap: Some(key_wrap),
+        client_keys: false,
+        key_type: KeyType::X25519,
+        key_changes: vec![KeyChange::new(KeyType::X25519, &public_key, utils::now())],
+        devices: Vec::new(),
+        recovery_key: None,
+        delete_at: None
     };
     
-    match Account::create_account(&account).await
+    state.db.create_account(&account).await
+}
+
+/// Creates a user entry for a client that generated its own key pair, so the server only ever sees the public key. The client may
+/// also upload its private key encrypted under a key of its own, which is stored as it is and handed back at login.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`ClientKeyRegistration`].
+///
+/// ## Returns
+/// * [`Result<(), ApiError>`][`std::result::Result`] - 200 OK if the account was created successfully, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or the username, password, public key or key backup is refused (with a `fields` entry for each)
+///    * 409 CONFLICT if the account already exists, under any capitalization
+///
+pub async fn create_user_with_key(State(state): State<AppState>, payload: String) -> Result<(), ApiError>
+{
+    let registration: ClientKeyRegistration = utils::parse_payload(&payload)?;
+    let policy = ValidationPolicy::current();
+
+    let public_key = validation::parse_public_key(registration.key_type, &registration.public_key);
+    let fields: Vec<FieldError> = [
+        ("username", policy.check_username(&registration.username)),
+        ("password", policy.check_password(&registration.username, &registration.password)),
+        ("public_key", public_key.as_ref().err().cloned()),
+        ("key_backup", registration.key_backup.as_ref().and_then(validation::check_key_backup))
+    ]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|p| FieldError::new(field, p)))
+        .collect();
+    let public_key = match (public_key, fields.is_empty())
+    {
+        (Ok(key), true) => key,
+        _ => return Err(ApiError::InvalidFields(String::from("The account's details are invalid."), fields))
+    };
+
+    if state.db.get_account(&registration.username).await?.is_some()
+    { return Err(ApiError::Conflict(String::from("An account with that username already exists."))) }
+
+    let (priv_key_enc, nonce, key_wrap) = match registration.key_backup
     {
-        Ok(_) => return (StatusCode::OK, "".to_string()),
-        Err(_) => return (StatusCode::BAD_REQUEST, utils::gen_err("Error creating account."))
-    }
+        Some(backup) => (backup.priv_key_enc, backup.nonce, Some(backup.key_wrap)),
+        None => (Vec::new(), Vec::new(), None)
+    };
+    let account: Account = Account {
+        username: registration.username,
+        id: Account::new_id(),
+        hash: auth::hash_password(&registration.password)?,
+        priv_key_enc,
+        nonce,
+        friends: Vec::new(),
+        friend_requests: Vec::new(),
+        two_factor: None,
+        key_wrap,
+        client_keys: true,
+        key_type: registration.key_type,
+        key_changes: vec![KeyChange::new(registration.key_type, &public_key, utils::now())],
+        devices: Vec::new(),
+        recovery_key: None,
+        delete_at: None,
+        public_key
+    };
+
+    state.db.create_account(&account).await
 }
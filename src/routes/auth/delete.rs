@@ -1,36 +1,152 @@
 use super::generics::{
-    structs::{Account, ClientAccount}, utils
+    auth::Authenticated, errors::ApiError, utils,
+    structs::{Account, AppState, DeletionPolicy, WSAction, WSPacket}
 };
-use axum::{http::StatusCode, response::IntoResponse};
+use crate::routes::{message::make, ws::ws};
+use axum::extract::State;
+use tracing::{error, info};
 
-/// Deletes a user entry in the database.
+/// Deletes the account the request is authenticated as. Any of its open websocket connections are closed.
+///
+/// With a grace period configured (see [`DeletionPolicy`]), the account is only scheduled for deletion: every session is ended, and logging in again
+/// before the grace period is over lets the deletion be cancelled at [`cancel_deletion`]. Otherwise it is deleted right away, as described in [`delete_account`].
 ///
 /// ## Arguments
-/// * [`payload`][`super::generics::structs::ClientAccount`] - A JSON string containing the a serialized of the user to be deleted.
-///     * Utilized Fields:
-///         * `username`
-///         * `session_id`
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to. This is the account that gets deleted.
 ///
 /// ## Returns
-/// * [`StatusCode`][axum::http::StatusCode] - The status code of the operation:
-///     * 200 OK if deletion was successful
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), saying when the account will be deleted if it was scheduled, or an [`ApiError`]:
 ///     * 500 INTERNAL_SERVER_ERROR if an error occurred deleting the account
-///     * 401 UNAUTHORIZED if the session is invalid.
+///     * 401 UNAUTHORIZED if the bearer token is missing or invalid.
+///
+pub async fn delete_user(State(state): State<AppState>, auth: Authenticated) -> Result<String, ApiError>
+{
+    let policy = DeletionPolicy::current();
+    let mut account = auth.account;
+    if policy.grace_period <= 0
+    {
+        delete_account(&state, account).await?;
+        return Ok(String::from("Account deleted."))
+    }
+
+    let delete_at = *account.delete_at.get_or_insert(utils::now() + policy.grace_period);
+    state.db.update_account(&account).await?;
+    let sessions = state.db.delete_sessions(&account.id, None).await?;
+    ws::end_sessions(&state.clients, &sessions, "Your account is scheduled for deletion.").await;
+    Ok(format!("Your account will be deleted at {delete_at}. Log in and cancel the deletion before then to keep it."))
+}
+
+/// Cancels the deletion of an account scheduled for deletion, keeping it.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///     * 400 BAD REQUEST if the account isn't scheduled for deletion
+///     * 401 UNAUTHORIZED if the bearer token is missing or invalid.
+///
+pub async fn cancel_deletion(State(state): State<AppState>, auth: Authenticated) -> Result<String, ApiError>
+{
+    let mut account = auth.account;
+    if account.delete_at.take().is_none() { return Err(ApiError::Validation(String::from("This account isn't scheduled for deletion."))) }
+    state.db.update_account(&account).await?;
+    Ok(String::from("Deletion cancelled."))
+}
+
+/// Deletes an account and everything that refers to it:
+/// * it leaves every conversation it was a part of, which moves to a new key epoch without it (see [`make::change_members`]) and loses the keys
+///   encrypted for it. Conversations it was the last member of are deleted. Online members get the changed conversation (arbitrary info 2).
+///   Conversations whose members are changed in the meantime are read again and moved from there. If one can't be moved, the error is returned
+///   before the account is deleted, so the whole cascade is run again on the next try.
+/// * it is removed from the friends and friend requests of everyone it was friends with or had a friend request with. Those online are told its ID (arbitrary info 11).
+/// * the account and its sessions are deleted, leaving a tombstone of the username (see [`Storage::delete_account`][`crate::db::storage::Storage::delete_account`]),
+///   and its websocket connections are closed.
 ///
-pub async fn delete_user(payload: String) -> impl IntoResponse
+/// Messages the account sent stay in their conversations, under its ID.
+pub async fn delete_account(state: &AppState, account: Account) -> Result<(), ApiError>
 {
+    let (username, user_id) = (account.username.as_str(), account.id.as_str());
+    for convo in state.db.get_conversations(user_id).await?
+    {
+        let id = convo.id.clone();
+        let mut convo = Some(convo);
+        while let Some(current) = convo.take()
+        {
+            let users: Vec<String> = current.users.iter().filter(|u| *u != user_id).cloned().collect();
+            if users.is_empty() { state.db.delete_conversation(&id).await?; break }
+            match make::change_members(state.db.as_ref(), current, users).await
+            {
+                // someone else changed the members in the meantime, so it is moved from where they left it
+                Err(ApiError::Conflict(_)) => convo = state.db.get_conversation(&id).await?,
+                // anything else is passed on before the account is deleted, so the cascade is run again on the next try
+                result => { result?; }
+            }
+        }
+        state.db.remove_conversation_keys(&id, user_id).await?;
 
-    let Ok(account) = serde_json::from_str::<ClientAccount>(&payload) 
-    else { return (StatusCode::BAD_REQUEST, utils::gen_err("Invalid Payload."))};
+        let Some(convo) = state.db.get_conversation(&id).await? else { continue };
+        for client in state.clients.lock().await.values().filter(|c| convo.users.contains(&c.user_id))
+        {
+            let changed = serde_json::to_string(&convo.clone().for_device(&client.user_id, &client.device)).unwrap();
+            let packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(changed, 2) };
+            if client.socket.send(packet).await.is_err()
+            { error!("Failed to send conversation {id} to client {}. Did they abruptly disconnect?", client.username) }
+        }
+    }
 
-    match utils::verify(&account.username, &account.session_id).await
+    let mut others: Vec<&String> = account.friends
+        .iter()
+        .chain(account.friend_requests.iter().flat_map(|r| [&r.sender, &r.receiver]))
+        .filter(|u| *u != user_id)
+        .collect();
+    others.sort();
+    others.dedup();
+    for other in &others
     {
-        Ok(true) => (),
-        Ok(false) => return (StatusCode::UNAUTHORIZED, utils::gen_err("Invalid session ID.")),
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e)
+        let Some(mut other) = state.db.get_account_by_id(other).await? else { continue };
+        other.friends.retain(|f| f != user_id);
+        other.friend_requests.retain(|r| r.sender != user_id && r.receiver != user_id);
+        state.db.update_account(&other).await?;
     }
-    
-    if let Err(e) = Account::delete_account(&account.username).await { return (StatusCode::INTERNAL_SERVER_ERROR, e) }
-    else { return (StatusCode::OK, String::new()); }
 
+    state.db.delete_account(user_id).await?;
+    ws::end_user(&state.clients, user_id, "Your account was deleted.").await;
+
+    for client in state.clients.lock().await.values().filter(|c| others.contains(&&c.user_id))
+    {
+        let packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(user_id.to_string(), 11) };
+        if client.socket.send(packet).await.is_err()
+        { error!("Failed to send the deletion of {username} to client {}. Did they abruptly disconnect?", client.username) }
+    }
+    info!("Deleted account {username}");
+    Ok(())
+}
+
+/// Runs forever, every [`DeletionPolicy::sweep_interval`]: deletes accounts whose grace period is over.
+pub async fn sweep_deletions(state: AppState)
+{
+    let mut interval = tokio::time::interval(DeletionPolicy::current().sweep_interval);
+    loop
+    {
+        interval.tick().await;
+        let due = match state.db.get_accounts_due_for_deletion(utils::now()).await
+        {
+            Ok(due) => due,
+            Err(e) => { error!("Failed to look up accounts due for deletion: {e}"); continue }
+        };
+        for id in due
+        {
+            // the deletion may have been cancelled since
+            let account = match state.db.get_account_by_id(&id).await
+            {
+                Ok(Some(account)) if account.delete_at.is_some_and(|at| at <= utils::now()) => account,
+                Ok(_) => continue,
+                Err(e) => { error!("Failed to look up account {id}, which is due for deletion: {e}"); continue }
+            };
+            if let Err(e) = delete_account(&state, account).await { error!("Failed to delete account {id}: {e}") }
+        }
+    }
 }
@@ -0,0 +1,314 @@
+use super::generics::{
+    auth::{self, Authenticated}, errors::{ApiError, FieldError}, utils, validation,
+    structs::{AppState, Device, DeviceApproval, DeviceRequest, NewDevice, UserKey, WSAction, WSPacket}
+};
+use super::identity::notify_key_change;
+use crate::routes::{message::make, ws::ws};
+use axum::{extract::{ConnectInfo, State}, Json};
+use std::net::SocketAddr;
+use tracing::error;
+
+/// The most devices (approved or waiting) an account may have.
+const MAX_DEVICES: usize = 20;
+/// The longest a device's display name may be, in characters.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Lists the account's devices, including those waiting to be approved.
+///
+/// ## Returns
+/// * [`Result<Json<Vec<Device>>, ApiError>`][`std::result::Result`] - The account's [`Device`]s, or an [`ApiError`] (401 UNAUTHORIZED if the bearer token is missing or invalid).
+///
+pub async fn list(auth: Authenticated) -> Result<Json<Vec<Device>>, ApiError>
+{
+    let mut account = auth.account;
+    account.prune_pending_devices(utils::now());
+    Ok(Json(account.devices))
+}
+
+/// Asks for the device making the request to be added to the account, with a key pair it generated. It is bound to the request's session.
+///
+/// The account's first device is approved right away. Any later one waits (for up to [`Device::PENDING_TTL`]) until one of the account's approved devices
+/// approves it at [`approve`], and the account's online approved devices are sent it (arbitrary info 9), so they can show its fingerprint to compare.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`NewDevice`].
+///
+/// ## Returns
+/// * [`Result<Json<Device>, ApiError>`][`std::result::Result`] - The new [`Device`], or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, the name or public key is refused (with a `fields` entry for each), or the account has too many devices
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///    * 409 CONFLICT if the session already belongs to a device
+///
+pub async fn add(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<Device>, ApiError>
+{
+    let new: NewDevice = utils::parse_payload(&payload)?;
+    let name = new.name.trim();
+    let public_key = validation::parse_public_key(new.key_type, &new.public_key);
+    let name_problem = (name.is_empty() || name.chars().count() > MAX_NAME_LENGTH || name.chars().any(char::is_control))
+        .then(|| format!("Must be between 1 and {MAX_NAME_LENGTH} characters long, without control characters."));
+    let fields: Vec<FieldError> = [("name", name_problem), ("public_key", public_key.as_ref().err().cloned())]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|p| FieldError::new(field, p)))
+        .collect();
+    let public_key = match (public_key, fields.is_empty())
+    {
+        (Ok(key), true) => key,
+        _ => return Err(ApiError::InvalidFields(String::from("The device is invalid."), fields))
+    };
+
+    let now = utils::now();
+    let mut account = auth.account;
+    account.prune_pending_devices(now);
+    if account.devices.iter().any(|d| d.session == auth.session.id)
+    { return Err(ApiError::Conflict(String::from("This session already belongs to a device."))) }
+    if account.devices.len() >= MAX_DEVICES
+    { return Err(ApiError::Validation(format!("An account can't have more than {MAX_DEVICES} devices, remove one first."))) }
+
+    let mut device = Device::new(name, new.key_type, public_key, &auth.session.id, now);
+    // the first device has no other device to approve it
+    let first = !account.devices.iter().any(|d| d.approved);
+    device.approved = first;
+    let change = first.then(|| account.record_device_key(&device, now));
+    account.devices.push(device.clone());
+    state.db.update_account(&account).await?;
+
+    match change
+    {
+        Some(change) =>
+        {
+            bind_clients(&state, &device).await;
+            notify_key_change(&state, &account, &change).await;
+        },
+        None =>
+        {
+            let approvers: Vec<&str> = account.devices.iter().filter(|d| d.approved).map(|d| d.id.as_str()).collect();
+            let notice = serde_json::to_string(&device).unwrap();
+            for client in state.clients.lock().await.values().filter(|c| c.user_id == account.id && approvers.contains(&c.device.as_str()))
+            {
+                let packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(notice.clone(), 9) };
+                if client.socket.send(packet).await.is_err()
+                { error!("Failed to send pending device to client {}. Did they abruptly disconnect?", client.username) }
+            }
+        }
+    }
+    Ok(Json(device))
+}
+
+/// Approves a device waiting to be added, from one of the account's approved devices. The approving device hands over the conversation keys it holds,
+/// encrypted for the new device, so the new device can read what was sent before it was added; later keys are encrypted for it by the server.
+///
+/// The new key is recorded in the account's key history, online friends are told about it (arbitrary info 8), and the new device is told it was approved
+/// (arbitrary info 10), if it is online.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to. Its session must be an approved device's.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`DeviceApproval`].
+///
+/// ## Returns
+/// * [`Result<Json<Device>, ApiError>`][`std::result::Result`] - The approved [`Device`], or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or a key is for a conversation, epoch or device it can't be for
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid, or its session isn't an approved device's
+///    * 404 NOT FOUND if no such device is waiting to be approved
+///
+pub async fn approve(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<Device>, ApiError>
+{
+    let approval: DeviceApproval = utils::parse_payload(&payload)?;
+    let now = utils::now();
+    let mut account = auth.account;
+    account.prune_pending_devices(now);
+
+    if account.device_for_session(&auth.session.id).is_none()
+    { return Err(ApiError::Unauthorized(String::from("Devices can only be approved from one of the account's approved devices."))) }
+    let Some(index) = account.devices.iter().position(|d| d.id == approval.device_id && !d.approved)
+    else { return Err(ApiError::NotFound(String::from("No such device is waiting to be approved."))) };
+
+    // every key must be one the account is entitled to, for the new device and nobody else
+    let mut keys: Vec<(String, Vec<UserKey>)> = Vec::new();
+    for (id, convo_keys) in approval.keys
+    {
+        let Some(convo) = state.db.get_conversation(&id).await?.filter(|c| c.users.contains(&account.id))
+        else { return Err(ApiError::Validation(format!("No such conversation `{id}`."))) };
+
+        let mut accepted: Vec<UserKey> = Vec::new();
+        for key in convo_keys
+        {
+            let entitled = convo.keys.iter().any(|k| k.owner == account.id && k.epoch == key.epoch);
+            let duplicate = accepted.iter().chain(&convo.keys).any(|k| k.epoch == key.epoch && k.device == approval.device_id);
+            if key.owner != account.id || key.device != approval.device_id || !entitled || duplicate
+            { return Err(ApiError::Validation(format!("A key for conversation `{id}` isn't one the new device can be given."))) }
+            accepted.push(UserKey { key_type: account.devices[index].key_type, ..key });
+        }
+        keys.push((id, accepted));
+    }
+
+    account.devices[index].approved = true;
+    let device = account.devices[index].clone();
+    let change = account.record_device_key(&device, now);
+    state.db.update_account(&account).await?;
+
+    for (id, convo_keys) in keys
+    {
+        if !state.db.add_conversation_keys(&id, &convo_keys).await?
+        { error!("Conversation {id} disappeared before device {} got its keys", device.id) }
+    }
+
+    notify_key_change(&state, &account, &change).await;
+    bind_clients(&state, &device).await;
+    Ok(Json(device))
+}
+
+/// Gets a sign-in challenge for one of the account's approved devices: a secret only the device's private key can decrypt.
+/// Answering it at [`sign_in`] binds the request's session to the device, e.g. after the device logged in again.
+///
+/// ## Arguments
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`DeviceRequest`].
+///     * Utilized Fields:
+///         * `device_id`
+///
+/// ## Returns
+/// * [`Result<Json<Vec<u8>>, ApiError>`][`std::result::Result`] - The encrypted challenge, sealed the same way conversation keys are for the device's key type, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///    * 404 NOT FOUND if the account has no such approved device
+///
+pub async fn challenge(auth: Authenticated, payload: String) -> Result<Json<Vec<u8>>, ApiError>
+{
+    let request: DeviceRequest = utils::parse_payload(&payload)?;
+    let Some(device) = auth.account.devices.iter().find(|d| d.id == request.device_id && d.approved)
+    else { return Err(ApiError::NotFound(String::from("No such device."))) };
+    device.challenge(&auth.session.id).map(Json)
+}
+
+/// Binds the request's session to one of the account's approved devices, once it proves it holds the device's private key by answering a [`challenge`].
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`DeviceRequest`].
+///     * Utilized Fields:
+///         * `device_id`
+///         * `response`
+///
+/// ## Returns
+/// * [`Result<Json<Device>, ApiError>`][`std::result::Result`] - The [`Device`], or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the response is wrong, or the bearer token is missing or invalid
+///    * 404 NOT FOUND if the account has no such approved device
+///
+pub async fn sign_in(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<Device>, ApiError>
+{
+    let request: DeviceRequest = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+
+    let Some(index) = account.devices.iter().position(|d| d.id == request.device_id && d.approved)
+    else { return Err(ApiError::NotFound(String::from("No such device."))) };
+    if !account.devices[index].check_challenge(&auth.session.id, &request.response)
+    { return Err(ApiError::Unauthorized(String::from("Invalid response."))) }
+
+    // a session belongs to one device at most
+    for device in account.devices.iter_mut().filter(|d| d.session == auth.session.id) { device.session.clear() }
+    account.devices[index].session = auth.session.id.clone();
+    let device = account.devices[index].clone();
+    state.db.update_account(&account).await?;
+
+    bind_clients(&state, &device).await;
+    Ok(Json(device))
+}
+
+/// Removes a device from the account, e.g. one that was lost. Takes the password, so it works from any session. The session the device is signed in with
+/// is ended, and its websocket connections closed. Every conversation the account is a part of then moves to a new key epoch, so the removed device
+/// can't read anything sent afterwards.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`DeviceRequest`].
+///     * Utilized Fields:
+///         * `device_id`
+///         * `password`
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 404 NOT FOUND if the account has no such device
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///
+pub async fn remove(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
+{
+    let request: DeviceRequest = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &request.password, addr.ip())?;
+
+    let Some(index) = account.devices.iter().position(|d| d.id == request.device_id)
+    else { return Err(ApiError::NotFound(String::from("No such device."))) };
+    let device = account.devices.remove(index);
+    state.db.update_account(&account).await?;
+
+    // a lost device mustn't stay signed in, or it could go on fetching the keys of every epoch the rotation below starts
+    if !device.session.is_empty()
+    {
+        if let Some(session) = state.db.delete_session(&account.id, &device.session).await?
+        { ws::end_sessions(&state.clients, &[session], "This device was removed.").await }
+    }
+    for client in state.clients.lock().await.values_mut().filter(|c| c.user_id == account.id && c.device == device.id) { client.device.clear() }
+    if device.approved { make::rotate_conversations(state.db.as_ref(), &account.id).await?; }
+    Ok(format!("Removed device {}.", device.name))
+}
+
+/// Marks the online clients signed in with `device`'s session as being that device, and tells them (arbitrary info 10).
+async fn bind_clients(state: &AppState, device: &Device)
+{
+    let notice = serde_json::to_string(device).unwrap();
+    for client in state.clients.lock().await.values_mut().filter(|c| c.session == device.session)
+    {
+        client.device = device.id.clone();
+        let packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(notice.clone(), 10) };
+        if client.socket.send(packet).await.is_err()
+        { error!("Failed to send device to client {}. Did they abruptly disconnect?", client.username) }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{db::memory::MemoryStore, generics::{
+        rate_limit::{RateLimitConfig, RateLimiter}, throttle::{LoginThrottle, ThrottleConfig}, two_factor::LoginChallenges,
+        structs::{Account, ClientStore, KeyType, Session}
+    }};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn removed_devices_are_signed_out()
+    {
+        let state = AppState {
+            clients: ClientStore::default(),
+            db: Arc::new(MemoryStore::default()),
+            logins: Arc::new(LoginThrottle::new(ThrottleConfig::default())),
+            limiter: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            challenges: Arc::new(LoginChallenges::default())
+        };
+        let (now, id) = (utils::now(), Account::new_id());
+        let (lost, tokens) = Session::new(&id, "lost phone", "127.0.0.1", now);
+        let (current, _) = Session::new(&id, "laptop", "127.0.0.1", now);
+        let phone = Device { id: String::from("phone"), name: String::from("Phone"), key_type: KeyType::X25519, public_key: vec![1; 32], added: now, approved: true, session: lost.id.clone() };
+        let account = Account { username: String::from("alice"), id: id.clone(), hash: auth::hash_password("hunter22").unwrap(), devices: vec![phone], ..Account::default() };
+        state.db.create_account(&account).await.unwrap();
+        for session in [&lost, &current] { state.db.create_session(session).await.unwrap(); }
+        assert!(utils::authenticate(state.db.as_ref(), &tokens.token).await.is_ok());
+
+        let request = DeviceRequest { device_id: String::from("phone"), password: String::from("hunter22"), ..DeviceRequest::default() };
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        remove(State(state.clone()), ConnectInfo(addr), Authenticated { account, session: current.clone() }, serde_json::to_string(&request).unwrap()).await.unwrap();
+
+        assert!(state.db.get_account("alice").await.unwrap().unwrap().devices.is_empty());
+        assert!(utils::authenticate(state.db.as_ref(), &tokens.token).await.is_err(), "the removed device's token must stop working");
+        assert_eq!(state.db.get_sessions(&id).await.unwrap().into_iter().map(|s| s.id).collect::<Vec<String>>(), vec![current.id]);
+    }
+}
@@ -1,42 +1,96 @@
-use crate::generics::{structs::Conversation, utils};
-use axum::{extract::Path, http::StatusCode};
-use axum::response::IntoResponse;
-use super::generics::structs::{Account, ClientAccount};
+use crate::generics::{errors::ApiError, structs::{Conversation, HistoryRequest, UserHandle, UserLookup}};
+use axum::{extract::State, Json};
+use super::generics::{auth::Authenticated, utils, structs::{AppState, ClientAccount}};
+use std::collections::HashMap;
 
 
-/// Gets a users data (conversations included) from the database.
+/// Gets a users data (conversations and their most recent messages included) from the database.
+///
+/// Friends, friend requests, conversation members and message senders are account IDs; the current username of each of them that still exists
+/// is included in `usernames`.
 ///
 /// ## Arguments
-/// * [`payload`][`std::string::String`] - A JSON string containing a serialized SID.
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
 ///
 /// ## Returns
-/// * [`(StatusCode, String)`][axum::response::Response] - A tuple containing the [`StatusCode`] of the request and a serialized [`ClientAccount`] value.
+/// * [`Result<Json<ClientAccount>, ApiError>`][`std::result::Result`] - The serialized [`ClientAccount`] value, or an [`ApiError`] (401 UNAUTHORIZED if the bearer token is missing or invalid).
 ///
-pub async fn get( Path(sid): Path<String>,) -> impl IntoResponse
+pub async fn get(State(state): State<AppState>, auth: Authenticated) -> Result<Json<ClientAccount>, ApiError>
 {
-    println!("GET");
-    let server_account: Account = match Account::get_account_by_sid(&sid).await
-    {
-        Ok(Some(account)) => account,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e),
-        Ok(None) => return (StatusCode::BAD_REQUEST, utils::gen_err("Invalid SID."))
-    };
+    let server_account = auth.account;
+    let device = server_account.device_for_session(&auth.session.id).map(|d| d.id.clone()).unwrap_or_default();
     
-    let convos: Vec<Conversation> = match Conversation::get_all(&server_account.username).await
+    // each device only gets the conversation keys it can decrypt
+    let mut convos: Vec<Conversation> = state.db.get_conversations(&server_account.id).await?
+        .into_iter()
+        .map(|c| c.for_device(&server_account.id, &device))
+        .collect();
+
+    // only send the latest page of each conversation; clients page further back through the history endpoint.
+    for convo in convos.iter_mut()
     {
-        Ok(convos) => convos,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e)
-    };
+        convo.messages = state.db.get_messages(&convo.id, None, None, HistoryRequest::MAX_LIMIT).await?;
+    }
+
+    let mut referenced: Vec<String> = server_account.friends
+        .iter()
+        .chain(server_account.friend_requests.iter().flat_map(|r| [&r.sender, &r.receiver]))
+        .chain(convos.iter().flat_map(|c| c.users.iter().chain(c.messages.iter().map(|m| &m.sender))))
+        .cloned()
+        .collect();
+    referenced.sort();
+    referenced.dedup();
+    let mut usernames: HashMap<String, String> = HashMap::new();
+    for id in &referenced
+    {
+        if let Some(account) = state.db.get_account_by_id(id).await? { usernames.insert(account.id, account.username); }
+    }
     
+    let two_factor = server_account.requires_second_factor();
     let result: ClientAccount = ClientAccount 
     {
         username: server_account.username,
+        id: server_account.id,
         password: String::new(),
         friends: server_account.friends,
         friend_requests: server_account.friend_requests,
         conversations: convos,
+        usernames,
         session_id: String::new(),
+        two_factor,
+        key_type: server_account.key_type,
+        recovery_key: server_account.recovery_key.is_some(),
+        delete_at: server_account.delete_at
     };
 
-    return (StatusCode::OK, serde_json::to_string(&result).unwrap());
+    Ok(Json(result))
+}
+
+/// Looks up accounts by username or ID, to get both. Unknown accounts are left out of the result.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`_auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`UserLookup`].
+///
+/// ## Returns
+/// * [`Result<Json<Vec<UserHandle>>, ApiError>`][`std::result::Result`] - A [`UserHandle`] for each account found, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or asks for more than [`UserLookup::MAX_USERS`] accounts
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///
+pub async fn lookup(State(state): State<AppState>, _auth: Authenticated, payload: String) -> Result<Json<Vec<UserHandle>>, ApiError>
+{
+    let lookup: UserLookup = utils::parse_payload(&payload)?;
+    if lookup.users.len() > UserLookup::MAX_USERS
+    { return Err(ApiError::Validation(format!("At most {} accounts can be looked up at once.", UserLookup::MAX_USERS))) }
+
+    let mut found: Vec<UserHandle> = Vec::new();
+    for handle in &lookup.users
+    {
+        let Some(account) = state.db.find_account(handle).await? else { continue };
+        let handle = UserHandle { id: account.id, username: account.username };
+        if !found.contains(&handle) { found.push(handle) }
+    }
+    Ok(Json(found))
 }
@@ -0,0 +1,134 @@
+use super::generics::{
+    auth::{self, Authenticated}, errors::{ApiError, FieldError}, fingerprint, utils, validation,
+    structs::{Account, AppState, IdentityRequest, KeyChange, KeyChangeNotice, KeyReplacement, SafetyNumber, WSAction, WSPacket}
+};
+use crate::routes::message::make;
+use axum::{extract::{ConnectInfo, State}, Json};
+use std::net::SocketAddr;
+use tracing::error;
+
+/// Looks up an account, by username or ID, that the requester may see the identity of: their own, or a friend's. Anyone else is reported as not existing,
+/// so usernames can't be probed.
+async fn visible_account(state: &AppState, requester: Account, handle: &str) -> Result<Account, ApiError>
+{
+    if requester.username == handle || requester.id == handle { return Ok(requester) }
+    match state.db.find_account(handle).await?
+    {
+        Some(account) if requester.friends.contains(&account.id) => Ok(account),
+        _ => Err(ApiError::NotFound(String::from("No such user.")))
+    }
+}
+
+/// Gets the safety number of the requester and one of their friends. Both see the same number, and it changes whenever either public key does,
+/// so comparing it over another channel shows whether the server handed out the keys it should have.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`IdentityRequest`] naming the friend.
+///
+/// ## Returns
+/// * [`Result<Json<SafetyNumber>, ApiError>`][`std::result::Result`] - The [`SafetyNumber`], or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///    * 404 NOT FOUND if the user doesn't exist or isn't a friend of the requester
+///
+pub async fn safety_number(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<SafetyNumber>, ApiError>
+{
+    let request: IdentityRequest = utils::parse_payload(&payload)?;
+    let own = auth.account;
+    if own.username == request.username || own.id == request.username { return Err(ApiError::Validation(String::from("A safety number is between you and someone else."))) }
+    let friend = visible_account(&state, own.clone(), &request.username).await?;
+
+    Ok(Json(SafetyNumber {
+        fingerprint: fingerprint::fingerprint(friend.key_type, &friend.public_key),
+        own_fingerprint: fingerprint::fingerprint(own.key_type, &own.public_key),
+        safety_number: fingerprint::safety_number(&own, &friend),
+        username: friend.username
+    }))
+}
+
+/// Gets every public key an account has had, oldest first. Works for the requester's own account and their friends'.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`IdentityRequest`].
+///
+/// ## Returns
+/// * [`Result<Json<Vec<KeyChange>>, ApiError>`][`std::result::Result`] - The account's [`KeyChange`]s, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///    * 404 NOT FOUND if the user doesn't exist or isn't the requester or a friend of theirs
+///
+pub async fn key_history(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<Vec<KeyChange>>, ApiError>
+{
+    let request: IdentityRequest = utils::parse_payload(&payload)?;
+    Ok(Json(visible_account(&state, auth.account, &request.username).await?.key_history()))
+}
+
+/// Replaces an account's key pair with one generated by the client, e.g. after losing the old private key. The account manages its own keys from then on.
+///
+/// The change is recorded in the account's key history and online friends are told about it. Any recovery key is removed, as it holds the old private key. Conversation keys can't be re-encrypted for the new key,
+/// so every conversation the account is a part of moves to a new key epoch, which the new key can read; what was sent before stays readable with the old key only.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`KeyReplacement`].
+///
+/// ## Returns
+/// * [`Result<Json<KeyChange>, ApiError>`][`std::result::Result`] - The recorded [`KeyChange`], or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or the public key or key backup is refused (with a `fields` entry for each)
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///
+pub async fn replace_key(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<Json<KeyChange>, ApiError>
+{
+    let replacement: KeyReplacement = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &replacement.password, addr.ip())?;
+
+    let public_key = validation::parse_public_key(replacement.key_type, &replacement.public_key);
+    let fields: Vec<FieldError> = [
+        ("public_key", public_key.as_ref().err().cloned()),
+        ("key_backup", replacement.key_backup.as_ref().and_then(validation::check_key_backup))
+    ]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|p| FieldError::new(field, p)))
+        .collect();
+    let public_key = match (public_key, fields.is_empty())
+    {
+        (Ok(key), true) => key,
+        _ => return Err(ApiError::InvalidFields(String::from("The new key is invalid."), fields))
+    };
+
+    (account.priv_key_enc, account.nonce, account.key_wrap) = match replacement.key_backup
+    {
+        Some(backup) => (backup.priv_key_enc, backup.nonce, Some(backup.key_wrap)),
+        None => (Vec::new(), Vec::new(), None)
+    };
+    account.client_keys = true;
+    // the recovery key encrypts the old private key, which is no use for the new public key
+    account.recovery_key = None;
+    let change = account.replace_public_key(replacement.key_type, public_key, utils::now());
+    state.db.update_account(&account).await?;
+
+    make::rotate_conversations(state.db.as_ref(), &account.id).await?;
+    notify_key_change(&state, &account, &change).await;
+    Ok(Json(change))
+}
+
+/// Tells every online friend of `account` that it (or one of its devices) has a new public key.
+pub async fn notify_key_change(state: &AppState, account: &Account, change: &KeyChange)
+{
+    let notice = KeyChangeNotice { id: account.id.clone(), username: account.username.clone(), change: change.clone() };
+    let notice = serde_json::to_string(&notice).unwrap();
+    for client in state.clients.lock().await.values().filter(|c| account.friends.contains(&c.user_id))
+    {
+        let packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(notice.clone(), 8) };
+        if client.socket.send(packet).await.is_err()
+        { error!("Failed to send key change of {} to client {}. Did they abruptly disconnect?", account.username, client.username) }
+    }
+}
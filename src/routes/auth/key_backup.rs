@@ -0,0 +1,36 @@
+use super::generics::{auth::{self, Authenticated}, errors::{ApiError, FieldError}, utils, structs::{AppState, KeyBackupUpload}, validation};
+use axum::extract::{ConnectInfo, State};
+use std::net::SocketAddr;
+
+/// Replaces the encrypted private key backup of an account whose key pair was generated by the client, e.g. after the client
+/// re-encrypted it under a new password. The backup is stored as it is; the server can't read it. As it is the server's only copy of the private key,
+/// replacing it takes the password, so a stolen access token can't overwrite it.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which wrong passwords are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`KeyBackupUpload`].
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, the backup is malformed, or the account's private key is managed by the server
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///
+pub async fn upload(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
+{
+    let upload: KeyBackupUpload = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &upload.password, addr.ip())?;
+    let backup = upload.key_backup;
+
+    if !account.client_keys
+    { return Err(ApiError::Validation(String::from("This account's private key is managed by the server, and is re-wrapped when the password changes."))) }
+    if let Some(problem) = validation::check_key_backup(&backup)
+    { return Err(ApiError::InvalidFields(String::from("The key backup is invalid."), vec![FieldError::new("key_backup", problem)])) }
+
+    (account.priv_key_enc, account.nonce, account.key_wrap) = (backup.priv_key_enc, backup.nonce, Some(backup.key_wrap));
+    state.db.update_account(&account).await?;
+    Ok(String::from("Key backup updated."))
+}
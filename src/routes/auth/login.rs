@@ -1,54 +1,106 @@
-use super::generics::{utils, structs::{Account, ClientAccount}};
-use argon2;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-/// "Logs" a user in. Generates a session ID and spits it back if the login was successful.
+use super::generics::{errors::ApiError, utils, structs::{Account, AppState, ClientAccount, SecondFactorChallenge, Session}};
+use axum::{extract::{ConnectInfo, State}, http::StatusCode, response::{IntoResponse, Response}, Json};
+use axum_extra::{headers::UserAgent, TypedHeader};
+use std::net::SocketAddr;
+use tracing::warn;
+/// "Logs" a user in. Starts a new session and spits its session ID back if the login was successful. Sessions on other devices are left alone.
+///
+/// If the account has two-factor authentication on, a correct password only gets a [`SecondFactorChallenge`] back (202 ACCEPTED);
+/// the session is started once the challenge is answered with a code at [`verify_login`][`super::two_factor::verify_login`].
+///
+/// Failed attempts are counted per account (by ID, or by username if there is no such account) and per IP, and either is locked out for a while once it has too many (see [`LoginThrottle`][`super::generics::throttle::LoginThrottle`]).
 ///
 /// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the login came from, recorded on the session.
+/// * [`user_agent`][`UserAgent`] - The client's user agent, recorded on the session as its device name.
 /// * [`payload`][`std::string::String`] - A JSON string containing a serialized ClientAccount of the account to log into.
 ///     * Utilized Fields:
 ///         * `username`
 ///         * `password`
 ///
 /// ## Returns
-/// * [`(StatusCode, String)`][axum::response::Response] - A tuple containing the [`StatusCode`] of the request and a [`String`] containing the newly minted session ID and the encrypted private key, separated by the signifier "|PRIVATEKEY:|"
+/// * [`Result<Response, ApiError>`][`std::result::Result`] - Either a [`String`] containing the newly minted session ID (access token), the encrypted private key, its nonce,
+///   the refresh token, the access token's expiry and the JSON [`KeyWrap`][`super::generics::structs::KeyWrap`] needed to unwrap the private key,
+///   separated by the signifier "|||" (200 OK), or a JSON [`SecondFactorChallenge`] (202 ACCEPTED),
+///   or an [`ApiError`] (401 UNAUTHORIZED if the username or password is wrong, 429 TOO MANY REQUESTS if the username or IP is locked out).
 /// 
-pub async fn login_user(payload: String) -> impl IntoResponse
+pub async fn login_user(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, user_agent: Option<TypedHeader<UserAgent>>, payload: String) -> Result<Response, ApiError>
 {
-    let Ok(client_account) = serde_json::from_str::<ClientAccount>(&payload) 
-    else { return (StatusCode::BAD_REQUEST, utils::gen_err("Invalid Payload."))};
+    let client_account: ClientAccount = utils::parse_payload(&payload)?;
+    state.logins.check(&client_account.username, addr.ip(), utils::now())?;
     
-    let mut server_account: Account = match Account::get_account(&client_account.username).await
+    // unknown usernames and wrong passwords get the same answer (and count the same), so the response doesn't reveal which accounts exist
+    let Some(mut server_account): Option<Account> = state.db.get_account(&client_account.username).await?
+    else
     {
-        Ok(Some(account)) => account,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e),
-        Ok(None) => return (StatusCode::BAD_REQUEST, utils::gen_err("Invalid Username or Password."))
+        state.logins.failure(&client_account.username, addr.ip(), utils::now());
+        return Err(ApiError::Unauthorized(String::from("Invalid Username or Password.")))
     };
+    // failures against an account are counted by its ID, so renaming it doesn't reset them
+    state.logins.check(&server_account.id, addr.ip(), utils::now())?;
 
     let Ok(true) = argon2::verify_encoded(&server_account.hash, client_account.password.as_bytes()) // doesn't check for an Argon2 error
-    else { return (StatusCode::UNAUTHORIZED, utils::gen_err("Invalid Username or Password.")) };
+    else
+    {
+        state.logins.failure(&server_account.id, addr.ip(), utils::now());
+        return Err(ApiError::Unauthorized(String::from("Invalid Username or Password.")))
+    };
+
+    // keys wrapped before accounts had salts of their own are re-wrapped now, while the password is at hand
+    if server_account.key_wrap.is_none() && !server_account.client_keys
+    {
+        match server_account.rewrap_key(&client_account.password, &client_account.password)
+        {
+            Ok(()) => state.db.update_account(&server_account).await?,
+            Err(e) => warn!("Couldn't re-wrap the private key of `{}`, leaving it as it is: {e}", server_account.username)
+        }
+    }
 
-    server_account.session_id = utils::rand_hex(32);
+    let device = user_agent.map(|TypedHeader(ua)| ua.to_string()).unwrap_or_else(|| String::from("Unknown browser"));
+    if server_account.requires_second_factor()
+    {
+        // failures are only forgotten once the code is right too, or a known password would let anyone guess codes without ever being locked out
+        let (challenge, expires) = state.challenges.issue(&server_account.id, &device, &addr.ip().to_string(), utils::now());
+        return Ok((StatusCode::ACCEPTED, Json(SecondFactorChallenge { challenge, expires })).into_response());
+    }
+    state.logins.success(&server_account.id);
 
-    if let Err(e) = Account::update_account(&server_account).await 
-    { return (StatusCode::INTERNAL_SERVER_ERROR, e) }
-    else 
-    { 
-        return (
-        StatusCode::OK, 
-        server_account.session_id + 
+    Ok(start_session(&state, &server_account, &device, &addr.ip().to_string()).await?.into_response())
+}
+
+/// Starts a session for an account that has fully logged in.
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - The session's tokens and the account's encrypted private key, in the format [`login_user`] answers with.
+pub async fn start_session(state: &AppState, account: &Account, device: &str, ip: &str) -> Result<String, ApiError>
+{
+    let (session, tokens) = Session::new(&account.id, device, ip, utils::now());
+    state.db.create_session(&session).await?;
+
+    Ok(
+        tokens.token + 
         "|||" 
-        + &server_account.priv_key_enc
+        + &account.priv_key_enc
             .iter()
             .map(|&x| x.to_string()).
             collect::<Vec<String>>()
             .join(",")
         + "|||"
-        + &server_account.nonce
+        + &account.nonce
             .iter()
             .map(|&x| x.to_string()).
             collect::<Vec<String>>()
             .join(",")
-        ) 
-    }
+        + "|||"
+        + &tokens.refresh_token
+        + "|||"
+        + &tokens.expires.to_string()
+        + "|||"
+        + &match account.client_keys
+        {
+            true => serde_json::to_string(&account.key_wrap),
+            false => serde_json::to_string(&account.key_wrap())
+        }.map_err(|_| ApiError::Crypto(String::from("Failed to encode the account's key parameters.")))?
+    )
 }
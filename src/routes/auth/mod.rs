@@ -3,4 +3,11 @@ pub mod delete;
 pub mod get;
 pub mod login;
 pub mod change_password;
+pub mod change_username;
+pub mod sessions;
+pub mod two_factor;
+pub mod key_backup;
+pub mod identity;
+pub mod devices;
+pub mod recovery;
 use super::generics;
@@ -0,0 +1,187 @@
+use super::generics::{
+    auth::{self, Authenticated}, errors::{ApiError, FieldError}, utils, validation::{self, ValidationPolicy},
+    structs::{Account, AppState, KeyBackup, KeyWrap, RecoveryKey, RecoveryKeySetup, RecoveryRequest}
+};
+use crate::routes::ws::ws;
+use axum::{extract::{ConnectInfo, State}, Json};
+use std::net::{IpAddr, SocketAddr};
+
+/// Sets the account's recovery key, or replaces it with a new one. The client generates the recovery key, shows it to the user to keep somewhere safe,
+/// and uploads the account's private key encrypted under it along with the authentication key derived from it. The server can read neither.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RecoveryKeySetup`].
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or the key backup or authentication key is refused (with a `fields` entry for each)
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///
+pub async fn set(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
+{
+    let setup: RecoveryKeySetup = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &setup.password, addr.ip())?;
+
+    let fields: Vec<FieldError> = [
+        ("key_backup", validation::check_key_backup(&setup.key_backup)),
+        ("auth_key", RecoveryKey::check_auth_key(&setup.auth_key))
+    ]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|p| FieldError::new(field, p)))
+        .collect();
+    if !fields.is_empty() { return Err(ApiError::InvalidFields(String::from("The recovery key is invalid."), fields)) }
+
+    account.recovery_key = Some(RecoveryKey::new(setup.key_backup, &setup.auth_key, utils::now())?);
+    state.db.update_account(&account).await?;
+    Ok(String::from("Recovery key set."))
+}
+
+/// Removes the account's recovery key, so the password can't be reset with it anymore.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RecoveryKeySetup`].
+///     * Utilized Fields:
+///         * `password`
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid or the account has no recovery key
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///
+pub async fn remove(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
+{
+    let setup: RecoveryKeySetup = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &setup.password, addr.ip())?;
+
+    if account.recovery_key.take().is_none() { return Err(ApiError::Validation(String::from("This account has no recovery key."))) }
+    state.db.update_account(&account).await?;
+    Ok(String::from("Recovery key removed."))
+}
+
+/// Gets the parameters the keys of an account's recovery key are derived with, the first step of recovering an account. This route takes no bearer token.
+///
+/// Usernames without a recovery key (or without an account) get made-up parameters that never change, so the answer doesn't reveal which accounts have one.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RecoveryRequest`].
+///     * Utilized Fields:
+///         * `username`
+///
+/// ## Returns
+/// * [`Result<Json<KeyWrap>, ApiError>`][`std::result::Result`] - The [`KeyWrap`] parameters, or an [`ApiError`] (400 BAD REQUEST if the payload is invalid).
+///
+pub async fn params(State(state): State<AppState>, payload: String) -> Result<Json<KeyWrap>, ApiError>
+{
+    let request: RecoveryRequest = utils::parse_payload(&payload)?;
+    let params = state.db.get_account(&request.username).await?
+        .and_then(|a| a.recovery_key)
+        .map(|r| r.key_wrap)
+        .unwrap_or_else(|| RecoveryKey::decoy_params(&request.username));
+    Ok(Json(params))
+}
+
+/// Gets the private key encrypted under an account's recovery key, once the request proves it has the recovery key. This route takes no bearer token.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RecoveryRequest`].
+///     * Utilized Fields:
+///         * `username`
+///         * `auth_key`
+///
+/// ## Returns
+/// * [`Result<Json<KeyBackup>, ApiError>`][`std::result::Result`] - The encrypted private key, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the username or authentication key is wrong, or the account has no recovery key
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong attempts
+///
+pub async fn fetch(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, payload: String) -> Result<Json<KeyBackup>, ApiError>
+{
+    let request: RecoveryRequest = utils::parse_payload(&payload)?;
+    let account = recovering(&state, &request, addr.ip()).await?;
+    state.logins.success(&account.id);
+    Ok(Json(account.recovery_key.map(|r| r.backup()).unwrap_or_default()))
+}
+
+/// Resets a forgotten password with the account's recovery key. The client decrypts the private key from [`fetch`] with the recovery key and
+/// sends it back encrypted under the new password, so the account keeps its key pair and can still read its conversations. This route takes no bearer token.
+///
+/// Accounts with two-factor authentication on also need a code from their authenticator app (or a two-factor recovery code). Every session of the account
+/// is revoked, and their websocket connections closed. The recovery key stays set, as it still encrypts the same private key.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RecoveryRequest`].
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid, or the new password or key backup is refused (with a `fields` entry for each)
+///    * 401 UNAUTHORIZED if the username, authentication key or code is wrong, or the account has no recovery key
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong attempts
+///
+pub async fn reset(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, payload: String) -> Result<String, ApiError>
+{
+    let request: RecoveryRequest = utils::parse_payload(&payload)?;
+    let fields: Vec<FieldError> = [
+        ("new_password", ValidationPolicy::current().check_password(&request.username, &request.new_password)),
+        ("key_backup", validation::check_key_backup(&request.key_backup))
+    ]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|p| FieldError::new(field, p)))
+        .collect();
+    if !fields.is_empty() { return Err(ApiError::InvalidFields(String::from("The new password or key backup is invalid."), fields)) }
+
+    let mut account = recovering(&state, &request, addr.ip()).await?;
+    if let Some(two_factor) = account.two_factor.as_mut().filter(|t| t.confirmed)
+    {
+        if !two_factor.accept(&request.code, utils::now())
+        {
+            state.logins.failure(&account.id, addr.ip(), utils::now());
+            return Err(ApiError::Unauthorized(String::from("Invalid code.")))
+        }
+    }
+    state.logins.success(&account.id);
+
+    account.hash = auth::hash_password(&request.new_password)?;
+    let backup = request.key_backup;
+    (account.priv_key_enc, account.nonce, account.key_wrap) = (backup.priv_key_enc, backup.nonce, Some(backup.key_wrap));
+    state.db.update_account(&account).await?;
+
+    // whoever knew the old password may still be logged in
+    let revoked = state.db.delete_sessions(&account.id, None).await?;
+    ws::end_sessions(&state.clients, &revoked, "Your password was reset.").await;
+    Ok(String::from("Password reset, please log in again."))
+}
+
+/// Checks that `request` proves it has the recovery key of the account it names, counting failures towards the same lockout as failed logins.
+/// Unknown usernames, accounts without a recovery key and wrong authentication keys all get the same answer.
+async fn recovering(state: &AppState, request: &RecoveryRequest, ip: IpAddr) -> Result<Account, ApiError>
+{
+    state.logins.check(&request.username, ip, utils::now())?;
+    let account = state.db.get_account(&request.username).await?;
+    // failures against an account are counted by its ID, like those of logins
+    let throttled = account.as_ref().map_or(request.username.as_str(), |a| a.id.as_str());
+    state.logins.check(throttled, ip, utils::now())?;
+    match account
+    {
+        Some(account) if account.recovery_key.as_ref().is_some_and(|r| r.check(&request.auth_key)) => Ok(account),
+        _ =>
+        {
+            state.logins.failure(throttled, ip, utils::now());
+            Err(ApiError::Unauthorized(String::from("Invalid username or recovery key.")))
+        }
+    }
+}
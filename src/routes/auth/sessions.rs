@@ -0,0 +1,95 @@
+use super::generics::{auth::Authenticated, errors::ApiError, utils, structs::{AppState, RefreshRequest, RevokeSession, SessionInfo, SessionTokens}};
+use crate::routes::ws::ws;
+use axum::{extract::State, Json};
+
+/// Lists every session (i.e. every logged-in device) of the requesting account.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+///
+/// ## Returns
+/// * [`Result<Json<Vec<SessionInfo>>, ApiError>`][`std::result::Result`] - The account's sessions, oldest first, with the requesting one marked `current`.
+///
+pub async fn list(State(state): State<AppState>, auth: Authenticated) -> Result<Json<Vec<SessionInfo>>, ApiError>
+{
+    let sessions = state.db.get_sessions(&auth.account.id).await?;
+    Ok(Json(sessions.iter().map(|s| SessionInfo::new(s, &auth.session)).collect()))
+}
+
+/// Trades a refresh token in for a new access token and refresh token. Both old tokens stop working, and websocket connections
+/// made with the old access token carry on under the new one.
+///
+/// This route takes no bearer token, as it is meant to be used once the access token has expired.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RefreshRequest`].
+///
+/// ## Returns
+/// * [`Result<Json<SessionTokens>, ApiError>`][`std::result::Result`] - The new tokens, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the refresh token is unknown or was already used, or the session has expired
+///
+pub async fn refresh(State(state): State<AppState>, payload: String) -> Result<Json<SessionTokens>, ApiError>
+{
+    let request: RefreshRequest = utils::parse_payload(&payload)?;
+
+    let Some(session) = state.db.get_session_by_refresh(&utils::hash_token(&request.refresh_token)).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid refresh token."))) };
+
+    let now = utils::now();
+    if session.lapsed(now)
+    {
+        state.db.delete_session(&session.user_id, &session.id).await?;
+        ws::end_sessions(&state.clients, &[session], "Your session expired.").await;
+        return Err(ApiError::Expired(String::from("Session expired, please log in again.")));
+    }
+
+    // the swap only goes through if nobody traded the same refresh token in since we read it
+    let (rotated, tokens) = session.rotated(now);
+    if !state.db.rotate_session(&session.refresh_hash, &rotated).await?
+    { return Err(ApiError::Unauthorized(String::from("Invalid refresh token."))) }
+
+    ws::refresh_sessions(&state.clients, &rotated, &tokens).await;
+    Ok(Json(tokens))
+}
+
+/// Revokes one session of the requesting account, closing its websocket connection if it has one.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`RevokeSession`].
+///
+/// ## Returns
+/// * [`Result<(), ApiError>`][`std::result::Result`] - 200 OK if the session was revoked, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 404 NOT FOUND if the account has no session with that ID
+///
+pub async fn revoke(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<(), ApiError>
+{
+    let request: RevokeSession = utils::parse_payload(&payload)?;
+
+    let Some(session) = state.db.delete_session(&auth.account.id, &request.id).await?
+    else { return Err(ApiError::NotFound(String::from("No such session."))) };
+
+    ws::end_sessions(&state.clients, &[session], "This session was revoked.").await;
+    Ok(())
+}
+
+/// Revokes every session of the requesting account except the one the request was made with.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+///
+/// ## Returns
+/// * [`Result<Json<Vec<SessionInfo>>, ApiError>`][`std::result::Result`] - The sessions that were revoked.
+///
+pub async fn revoke_others(State(state): State<AppState>, auth: Authenticated) -> Result<Json<Vec<SessionInfo>>, ApiError>
+{
+    let revoked = state.db.delete_sessions(&auth.account.id, Some(&auth.session.id)).await?;
+    ws::end_sessions(&state.clients, &revoked, "This session was revoked.").await;
+    Ok(Json(revoked.iter().map(|s| SessionInfo::new(s, &auth.session)).collect()))
+}
@@ -0,0 +1,161 @@
+use super::generics::{auth::{self, Authenticated}, errors::ApiError, utils, structs::{AppState, SecondFactorLogin, TwoFactor, TwoFactorEnrollment, TwoFactorRequest}};
+use super::login;
+use axum::{extract::{ConnectInfo, State}, Json};
+use std::net::SocketAddr;
+
+/// Starts turning on two-factor authentication. A new secret is generated and handed back for the user's authenticator app;
+/// it only starts being asked for at login once a code from the app is sent to [`confirm`]. Enrolling again before then replaces the secret.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`TwoFactorRequest`].
+///     * Utilized Fields:
+///         * `password`
+///
+/// ## Returns
+/// * [`Result<Json<TwoFactorEnrollment>, ApiError>`][`std::result::Result`] - The secret and its `otpauth://` URI, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the password is incorrect or the bearer token is missing or invalid
+///    * 409 CONFLICT if two-factor authentication is already on
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong passwords
+///
+pub async fn enroll(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<Json<TwoFactorEnrollment>, ApiError>
+{
+    let request: TwoFactorRequest = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &request.password, addr.ip())?;
+
+    if account.requires_second_factor()
+    { return Err(ApiError::Conflict(String::from("Two-factor authentication is already on."))) }
+
+    let two_factor = TwoFactor::generate();
+    let enrollment = TwoFactorEnrollment { secret: two_factor.encoded_secret(), uri: two_factor.uri(&account.username) };
+    account.two_factor = Some(two_factor);
+    state.db.update_account(&account).await?;
+    Ok(Json(enrollment))
+}
+
+/// Finishes turning on two-factor authentication, once the user shows their authenticator app produces the right codes.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`TwoFactorRequest`].
+///     * Utilized Fields:
+///         * `code`
+///
+/// ## Returns
+/// * [`Result<Json<Vec<String>>, ApiError>`][`std::result::Result`] - The account's recovery codes, which are never shown again, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid or there is no enrollment to confirm
+///    * 401 UNAUTHORIZED if the code is wrong or the bearer token is missing or invalid
+///
+pub async fn confirm(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<Vec<String>>, ApiError>
+{
+    let request: TwoFactorRequest = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+
+    let Some(two_factor) = account.two_factor.as_mut().filter(|t| !t.confirmed)
+    else { return Err(ApiError::Validation(String::from("There is no two-factor enrollment waiting to be confirmed."))) };
+
+    if !two_factor.accept_code(&request.code, utils::now())
+    { return Err(ApiError::Unauthorized(String::from("Invalid code."))) }
+
+    two_factor.confirmed = true;
+    let codes = two_factor.regenerate_recovery_codes();
+    state.db.update_account(&account).await?;
+    Ok(Json(codes))
+}
+
+/// Turns two-factor authentication off. Takes both the password and a code (or recovery code), so neither a stolen session
+/// nor a stolen password alone is enough.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`TwoFactorRequest`].
+///     * Utilized Fields:
+///         * `password`
+///         * `code`
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - A confirmation message (200 OK), or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid or two-factor authentication isn't on
+///    * 401 UNAUTHORIZED if the password or code is wrong or the bearer token is missing or invalid
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong attempts
+///
+pub async fn disable(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, auth: Authenticated, payload: String) -> Result<String, ApiError>
+{
+    let request: TwoFactorRequest = utils::parse_payload(&payload)?;
+    let mut account = auth.account;
+    auth::check_password(&state, &account, &request.password, addr.ip())?;
+
+    let Some(two_factor) = account.two_factor.as_mut().filter(|t| t.confirmed)
+    else { return Err(ApiError::Validation(String::from("Two-factor authentication isn't on."))) };
+
+    if !two_factor.accept(&request.code, utils::now())
+    {
+        state.logins.failure(&account.id, addr.ip(), utils::now());
+        return Err(ApiError::Unauthorized(String::from("Invalid code.")))
+    }
+
+    account.two_factor = None;
+    state.db.update_account(&account).await?;
+    Ok(String::from("Two-factor authentication turned off."))
+}
+
+/// Finishes a login that was answered with a [`SecondFactorChallenge`][`super::generics::structs::SecondFactorChallenge`], starting its session.
+///
+/// Wrong codes count towards the same lockout as wrong passwords. This route takes no bearer token, as there is no session yet.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`addr`][`SocketAddr`] - The address the request came from, which failed attempts are also counted against.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`SecondFactorLogin`].
+///
+/// ## Returns
+/// * [`Result<String, ApiError>`][`std::result::Result`] - The same "|||"-separated string a login without two-factor authentication gets, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 401 UNAUTHORIZED if the challenge is unknown or expired, or the code is wrong
+///    * 429 TOO MANY REQUESTS if the account or IP is locked out after too many wrong attempts
+///
+pub async fn verify_login(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, payload: String) -> Result<String, ApiError>
+{
+    let request: SecondFactorLogin = utils::parse_payload(&payload)?;
+
+    // the challenge is taken out while it is being answered, so concurrent answers can't both start a session, and only put back if the answer is refused
+    let Some(pending) = state.challenges.take(&request.challenge, utils::now())
+    else { return Err(ApiError::Unauthorized(String::from("Invalid or expired challenge, please log in again."))) };
+    if let Err(e) = state.logins.check(&pending.user_id, addr.ip(), utils::now())
+    {
+        state.challenges.put_back(&request.challenge, pending);
+        return Err(e)
+    }
+
+    let Some(mut account) = state.db.get_account_by_id(&pending.user_id).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid or expired challenge, please log in again."))) };
+
+    // an account that turned two-factor authentication off in the meantime has nothing left to check
+    if let Some(two_factor) = account.two_factor.as_mut().filter(|t| t.confirmed)
+    {
+        let before = two_factor.clone();
+        if !two_factor.accept(&request.code, utils::now())
+        {
+            state.logins.failure(&account.id, addr.ip(), utils::now());
+            state.challenges.put_back(&request.challenge, pending);
+            return Err(ApiError::Unauthorized(String::from("Invalid code.")))
+        }
+        // keeps the used code (or crossed-off recovery code) from being accepted again. This fails if another login used a code since the account was read,
+        // which may well have been the same one
+        if !state.db.use_two_factor_code(&account.id, &before, two_factor).await?
+        {
+            state.challenges.put_back(&request.challenge, pending);
+            return Err(ApiError::Unauthorized(String::from("Invalid code.")))
+        }
+    }
+    state.logins.success(&account.id);
+
+    login::start_session(&state, &account, &pending.device, &pending.ip).await
+}
@@ -0,0 +1,53 @@
+use super::generics::{auth::Authenticated, errors::ApiError, structs::{AppState, HistoryPage, HistoryRequest}, utils};
+use crate::db::storage::Storage;
+use axum::{extract::State, Json};
+
+/// Reads a page of a conversation's message history on behalf of one of its members.
+///
+/// ## Arguments:
+/// * [`db`][`crate::db::storage::Storage`] - The storage backend to read from.
+/// * [`user`][`str`] - The ID of the account asking for the history. Must be a part of the conversation.
+/// * [`request`][`HistoryRequest`] - Which conversation, and which page of it, to read.
+///
+/// ## Returns:
+/// * [`Result<HistoryPage, ApiError>`] - The requested page, or an [`ApiError::NotFound`] if the conversation doesn't exist or the user isn't a part of it.
+///
+pub async fn fetch(db: &dyn Storage, user: &str, request: &HistoryRequest) -> Result<HistoryPage, ApiError>
+{
+    // conversations the user isn't a part of are reported the same as missing ones, so their IDs can't be probed
+    let Some(convo) = db.get_conversation(&request.conversation_id).await?.filter(|c| c.users.iter().any(|u| u == user))
+    else { return Err(ApiError::NotFound(String::from("No such conversation."))) };
+
+    // ask for one message more than the page holds, to find out whether there's anything past it
+    let page_size = request.page_size();
+    let mut messages = db.get_messages(&convo.id, request.before, request.after, page_size + 1).await?;
+    let more = messages.len() > page_size;
+    if more
+    {
+        // drop the extra message from the far end of the page, away from the cursor
+        if request.after.is_some() { messages.truncate(page_size) } else { messages.remove(0); }
+    }
+
+    Ok(HistoryPage { conversation_id: convo.id, messages, more })
+}
+
+/// Gets a page of a conversation's message history.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`HistoryRequest`].
+///
+/// ## Returns
+/// * [`Result<Json<HistoryPage>, ApiError>`][`std::result::Result`] - The serialized [`HistoryPage`] value, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 404 NOT FOUND if the conversation doesn't exist or the user isn't a part of it
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///    * 500 INTERNAL SERVER ERROR if there was an error connecting to the database at any point
+///
+pub async fn history(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<HistoryPage>, ApiError>
+{
+    let request: HistoryRequest = utils::parse_payload(&payload)?;
+
+    fetch(state.db.as_ref(), &auth.account.id, &request).await.map(Json)
+}
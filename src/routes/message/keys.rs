@@ -0,0 +1,33 @@
+use super::generics::{auth::Authenticated, errors::ApiError, structs::{AppState, ConversationKeysRequest, UserKey}, utils};
+use axum::{extract::State, Json};
+
+/// Gets the requester's keys to a conversation, one for every epoch they were a part of it in, oldest first.
+/// Together these decrypt every message the requester was a member for, whichever epoch it was sent under.
+///
+/// Requests from one of the account's devices get the keys encrypted for that device, as well as those encrypted for the account's own key.
+///
+/// ## Arguments
+/// * [`state`][`AppState`] - The shared app state, holding the storage backend.
+/// * [`auth`][`Authenticated`] - The account the request's bearer token belongs to.
+/// * [`payload`][`std::string::String`] - A JSON string containing a serialized [`ConversationKeysRequest`].
+///
+/// ## Returns
+/// * [`Result<Json<Vec<UserKey>>, ApiError>`][`std::result::Result`] - The requester's encrypted [`UserKey`]s, or an [`ApiError`]:
+///    * 400 BAD REQUEST if the payload is invalid
+///    * 404 NOT FOUND if the conversation doesn't exist or the user isn't a part of it
+///    * 401 UNAUTHORIZED if the bearer token is missing or invalid
+///
+pub async fn keys(State(state): State<AppState>, auth: Authenticated, payload: String) -> Result<Json<Vec<UserKey>>, ApiError>
+{
+    let request: ConversationKeysRequest = utils::parse_payload(&payload)?;
+    let user = &auth.account.id;
+
+    // conversations the user isn't a part of are reported the same as missing ones, so their IDs can't be probed
+    let Some(convo) = state.db.get_conversation(&request.conversation_id).await?.filter(|c| c.users.contains(user))
+    else { return Err(ApiError::NotFound(String::from("No such conversation."))) };
+
+    let device = auth.account.device_for_session(&auth.session.id).map(|d| d.id.as_str()).unwrap_or_default();
+    let mut keys: Vec<UserKey> = convo.for_device(user, device).keys;
+    keys.sort_by_key(|k| k.epoch);
+    Ok(Json(keys))
+}
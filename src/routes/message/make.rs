@@ -1,44 +1,97 @@
-use super::{
-    db::mongo, generics::{
-        structs::{Conversation, UserKey}, utils
-    }
+use super::generics::{
+    errors::ApiError, structs::{Conversation, UserKey}, utils
 };
+use crate::db::storage::Storage;
 use getrandom::getrandom;
-use mongodb::bson::Document;
 
-pub async fn create_conversation(users: Vec<&String>) -> Result<Conversation, String>
+/// A fresh random conversation key.
+fn new_conversation_key() -> [u8; 32]
 {
     let mut raw_conversation_key: [u8; 32] = [0; 32];
     getrandom(&mut raw_conversation_key).expect("Failed to generate random conversation key.");
-    while raw_conversation_key.iter().any(|x| *x == 0_u8)
+    while raw_conversation_key.contains(&0_u8)
     {
         getrandom(&mut raw_conversation_key).expect("Failed to generate random conversation key.");
     } // getrandom() can sometimes give a 0, which will fuck everything up.
+    raw_conversation_key
+}
 
-    let conversation: Conversation = Conversation {
-        id: utils::rand_hex(4),     
-        users: users.iter().map(|x| x.to_owned().to_owned()).collect(), // double to_owned()? really?
-        keys: 
+/// Encrypts a conversation key for each of `users` (account IDs), and each of their approved devices, as the key of `epoch`.
+async fn wrap_key(db: &dyn Storage, key: &[u8], users: &[String], epoch: u32) -> Result<Vec<UserKey>, ApiError>
+{
+    let mut keys: Vec<UserKey> = Vec::new();
+    for user in users {
+        let Some(account) = db.get_account_by_id(user).await?
+        else { return Err(ApiError::NotFound(format!("User {user} does not exist."))) };
+        keys.push(UserKey { epoch, ..UserKey::encrypt(key, &account)? });
+        for device in account.devices.iter().filter(|d| d.approved)
         {
-            let mut k: Vec<UserKey> = Vec::new();
-            for user in users {
-                k.push(
-                    UserKey::encrypt(&raw_conversation_key, &user)
-                        .await
-                        .map_err(|x|  utils::gen_err(&x))?
-                );
-            }
-            k
-        },
-        messages: vec![]
+            keys.push(UserKey { epoch, ..UserKey::encrypt_for_device(key, &account, device)? });
+        }
+    }
+    Ok(keys)
+}
+
+pub async fn create_conversation(db: &dyn Storage, users: Vec<&String>) -> Result<Conversation, ApiError>
+{
+    let users: Vec<String> = users.into_iter().cloned().collect();
+    let conversation: Conversation = Conversation {
+        id: utils::rand_hex(4),
+        keys: wrap_key(db, &new_conversation_key(), &users, 0).await?,
+        users,
+        messages: vec![],
+        epoch: 0
     };
 
-    let doc: Document = conversation.to_document();
-    if let Err(e) = mongo::get_collection("conversations")
-        .await
-        .insert_one(doc, None)
-        .await
-    { return Err(utils::gen_err(&format!("An error occurred generating a conversation: {}", e)))}
+    db.create_conversation(&conversation).await?;
+
+    Ok(conversation)
+}
+
+/// Replaces the members of a conversation, moving it to a new epoch with a new key that only the new members get.
+///
+/// ## Arguments:
+/// * [`db`][`crate::db::storage::Storage`] - The storage backend the conversation is in.
+/// * [`conversation`][`Conversation`] - The conversation as it was read, whose epoch the change builds on.
+/// * [`users`][`std::vec::Vec`] - The account IDs of everyone who is a member after the change.
+///
+/// ## Returns:
+/// * [`Result<Conversation, ApiError>`] - The changed conversation, or an [`ApiError::Conflict`] if its members were changed by someone else in the meantime.
+///
+pub async fn change_members(db: &dyn Storage, conversation: Conversation, users: Vec<String>) -> Result<Conversation, ApiError>
+{
+    let epoch = conversation.epoch + 1;
+    let keys = wrap_key(db, &new_conversation_key(), &users, epoch).await?;
+
+    if !db.change_members(&conversation.id, conversation.epoch, &users, &keys).await?
+    { return Err(ApiError::Conflict(String::from("The conversation's members were changed by someone else, please try again."))) }
+
+    Ok(Conversation { users, keys: [conversation.keys, keys].concat(), epoch, ..conversation })
+}
 
-    return Ok(conversation);
+/// Moves every conversation the account `user` (an ID) is a part of to a new epoch, without changing its members. Used when the keys the account's conversation keys
+/// are encrypted for change, so that whatever is sent from now on is only readable with the current ones. Conversations whose members are changed in the meantime
+/// are read again and moved from there, as the change may have wrapped its key for the old keys.
+///
+/// ## Returns:
+/// * [`Result<(), ApiError>`] - Ok once every conversation is moved, or the [`ApiError`] of the first that couldn't be, leaving the rest as they were.
+///
+pub async fn rotate_conversations(db: &dyn Storage, user: &str) -> Result<(), ApiError>
+{
+    for convo in db.get_conversations(user).await?
+    {
+        let id = convo.id.clone();
+        let mut convo = Some(convo);
+        while let Some(current) = convo.take()
+        {
+            let users = current.users.clone();
+            match change_members(db, current, users).await
+            {
+                // conversations the account was removed from in the meantime were moved without it
+                Err(ApiError::Conflict(_)) => convo = db.get_conversation(&id).await?.filter(|c| c.users.iter().any(|u| u == user)),
+                result => { result?; }
+            }
+        }
+    }
+    Ok(())
 }
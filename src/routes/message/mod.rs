@@ -1,3 +1,5 @@
+pub mod history;
+pub mod keys;
 pub mod make;
 pub mod send;
-use super::{db, generics};
+use super::generics;
@@ -1,33 +1,38 @@
-use super::generics::{structs::{Conversation, EncryptedMessage}, utils };
+use super::generics::{errors::ApiError, structs::EncryptedMessage, utils };
+use crate::db::storage::Storage;
 
 /// Uploads a message to a conversation in the database.
 ///
+/// The membership and epoch checks and the append happen as one atomic operation in the storage backend, so concurrent senders never overwrite each other.
+///
 /// ## Arguments:
-/// * [`message`][`super::generics::structs::EncryptedMessage`] - The message to be sent.
+/// * [`db`][`crate::db::storage::Storage`] - The storage backend to upload the message to.
+/// * [`user_id`][`str`] - The account ID of the sender, whose session the message must come with.
+/// * [`message`][`super::generics::structs::EncryptedMessage`] - The message to be sent, with the sender's ID as its `sender`.
 ///
 /// ## Returns:
-/// * [`Result<(), String>`] - A result containing an error message, if any.
+/// * [`Result<EncryptedMessage, ApiError>`] - The message as stored, with its ID, sequence number and timestamp, or an [`ApiError`].
 /// 
-pub async fn send(message: EncryptedMessage) -> Result<(), String>
+pub async fn send(db: &dyn Storage, user_id: &str, message: EncryptedMessage) -> Result<EncryptedMessage, ApiError>
 {
 
-    match utils::verify(&message.sender, &message.sender_sid).await
-    {
-        Ok(true) => (),
-        Ok(false) => return Err(utils::gen_err("Invalid SID.")),
-        Err(e) => return Err(e)
-    }
+    utils::verify(db, user_id, &message.sender_sid).await?;
 
-    let mut convo = match Conversation::get_one(&message.dest_convo_id).await
-    {
-        Ok(Some(convo)) => convo,
-        Err(_) => return Err(utils::gen_err("Error retrieving conversation.")),
-        Ok(None) => return Err(utils::gen_err("Attempted to send message to non-existent conversation.")),
+    // strip message of useless/private data; attaching SID means other member of convo would be able to access the other user's SID with some client-side manipulation.
+    // TODO: pretty sure sender doesn't need to be on EncryptedMessage. Fix in client-side
+    let message: EncryptedMessage = EncryptedMessage {
+        data: message.data,
+        nonce: message.nonce,
+        sender: message.sender,
+        dest_convo_id: message.dest_convo_id,
+        sender_sid: String::new(),
+        id: utils::rand_hex(12),
+        seq: 0, // assigned by the storage backend
+        timestamp: utils::now(),
+        epoch: message.epoch
     };
 
-    if !convo.users.contains(&message.sender) 
-    { return Err(utils::gen_err("User is not a part of the conversation they're trying to send to.")) };
-
-    convo.send(message).await
-
+    db.append_message(&message)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(String::from("Attempted to send message to a conversation that doesn't exist, that the user isn't a part of, or whose key has changed since.")))
 }
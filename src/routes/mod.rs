@@ -1,5 +1,4 @@
 pub mod auth;
 pub mod message;
 pub mod ws;
-use super::db;
 use super::generics;
@@ -1,68 +1,62 @@
 use std::net::SocketAddr;
 use axum::extract::State;
-use tracing::{error, info};
-use super::generics::structs::{Account, WSAction, Conversation};
-use crate::routes::message::make;
-use crate::tokio::sync::mpsc::Sender;
-use crate::generics::{structs::WSPacket, utils};
-use super::generics::structs::ClientStore;
+use tracing::error;
+use super::generics::structs::{Account, WSAction};
+use tokio::sync::mpsc::Sender;
+use crate::generics::{errors::ApiError, structs::WSPacket, utils};
+use super::generics::structs::AppState;
 
 
-pub async fn add_friend(packet: WSPacket, who: SocketAddr, State(store): State<ClientStore>, tx: &Sender<WSPacket>) -> Result<(), ()>
+pub async fn add_friend(packet: WSPacket, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
 {
 
-    let store = store.lock().await;
+    let store = state.clients.lock().await;
 
     let Some(client) = store.get(&who)
-    else { tx.send(utils::info_packet("You are not registered with the server.")).await.ok(); return Ok(()); };
+    else { return Err(ApiError::Unauthorized(String::from("You are not registered with the server."))) };
 
-    if client.session_id != packet.sid || client.username != packet.sender
-    { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return Ok(()); }
+    if client.session_id != packet.sid || !client.is(&packet.sender)
+    { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
 
-    let WSAction::AddFriend(x) = packet.action
-    else { tx.send(utils::info_packet("Invalid action.")).await.ok(); return Ok(()); };
+    let WSAction::AddFriend(mut x) = packet.action
+    else { return Err(ApiError::Validation(String::from("Invalid action."))) };
 
-    let mut client: Account = match Account::get_account_by_sid(&client.session_id).await
-    {
-        Ok(Some(client)) => client,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return Ok(()); }
-        Ok(None) => { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return Ok(()); }
-    };
+    let Some(mut client): Option<Account> = state.db.get_account_by_id(&client.user_id).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
 
-    let mut friend: Account = match Account::get_account(if client.username == x.receiver { &x.sender } else { &x.receiver} ).await
-    {
-        Ok(Some(user)) => user,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return Ok(()); }
-        Ok(None) => { tx.send(utils::info_packet("Friend does not exist.")).await.ok(); return Ok(()); }
-    };
+    // either side may be named by username or ID, but requests are stored and sent out with IDs
+    let receiving = client.username == x.receiver || client.id == x.receiver;
+    let Some(mut friend): Option<Account> = state.db.find_account(if receiving { &x.sender } else { &x.receiver }).await?
+    else { return Err(ApiError::NotFound(String::from("Friend does not exist."))) };
+    (x.sender, x.receiver) = if receiving { (friend.id.clone(), client.id.clone()) } else { (client.id.clone(), friend.id.clone()) };
 
-    if client.friends.iter().any(|user| user == &x.receiver)
-    { tx.send(utils::info_packet("You are already friends with this user.")).await.ok(); return Ok(()); }
+    if client.friends.contains(&friend.id)
+    { return Err(ApiError::Conflict(String::from("You are already friends with this user."))) }
 
-    let mut info_code: u8 = 0;
+    let info_code: u8;
 
     match x.status.as_str() {
         "PENDING" => {
             // ensure no existing friend request exists
             if client.friend_requests.iter().any(|req| req.sender == x.sender && req.receiver == x.receiver)
-            { tx.send(utils::info_packet("You have already sent a friend request to this user.")).await.ok(); return Ok(()); }
+            { return Err(ApiError::Conflict(String::from("You have already sent a friend request to this user."))) }
             
             friend.friend_requests.push(x.clone());
-            Account::update_account(&friend).await.ok();
+            state.db.update_account(&friend).await?;
 
             client.friend_requests.push(x.clone());
-            Account::update_account(&client).await.ok();
-            tx.send(utils::info_packet(&format!("Sent friend request to {}!", &x.receiver))).await.ok();
+            state.db.update_account(&client).await?;
+            tx.send(utils::info_packet(&format!("Sent friend request to {}!", &friend.username))).await.ok();
             info_code = 5;
 
         },
         "REJECTED" => {
             client.friend_requests.retain(|req| req.sender != x.sender && req.receiver != x.receiver);
-            Account::update_account(&client).await.ok();
+            state.db.update_account(&client).await?;
 
             println!("{:#?} || {:#?}", friend.friend_requests, &x);
             friend.friend_requests.retain(|req| req.sender != x.sender && req.receiver != x.receiver);
-            Account::update_account(&friend).await.ok();
+            state.db.update_account(&friend).await?;
 
             tx.send(utils::info_packet("Friend request cancelled.")).await.ok();
 
@@ -71,32 +65,32 @@ pub async fn add_friend(packet: WSPacket, who: SocketAddr, State(store): State<C
         },
         "ACCEPTED" => {
             // ensure authenticity. The only person who can accept requests is the recipient.
-            if client.username != x.receiver
-            { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return Ok(()); }
+            if client.id != x.receiver
+            { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
 
             client.friend_requests.retain(|req| req.sender != x.sender && req.receiver != x.receiver);
-            Account::update_account(&client).await.ok();
+            state.db.update_account(&client).await?;
 
             friend.friend_requests.retain(|req| req.sender != x.sender && req.receiver != x.receiver);
-            Account::update_account(&friend).await.ok();
+            state.db.update_account(&friend).await?;
 
-            client.friends.push(friend.username.clone());
-            Account::update_account(&client).await.ok();
+            client.friends.push(friend.id.clone());
+            state.db.update_account(&client).await?;
 
-            friend.friends.push(client.username.clone());
-            Account::update_account(&friend).await.ok();
+            friend.friends.push(client.id.clone());
+            state.db.update_account(&friend).await?;
 
-            tx.send(utils::info_packet(&format!("You are now friends with {}!", &x.receiver))).await.ok();
+            tx.send(utils::info_packet(&format!("You are now friends with {}!", &friend.username))).await.ok();
             info_code = 7;
         },
-        _ => { tx.send(utils::info_packet("Invalid friend request status.")).await.ok(); return Ok(()); }
+        _ => { return Err(ApiError::Validation(String::from("Invalid friend request status."))) }
     }
 
         let c_packet: WSPacket = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(serde_json::to_string(&x).unwrap(), info_code) };
         if tx.send(c_packet).await.is_err() 
         { error!("Failed to send conversations to client {who}. Did they abruptly disconnect?") }
 
-        let Some(friend_client) = store.values().find(|c| &c.username == (if client.username == x.receiver { &x.sender } else { &x.receiver}))
+        let Some(friend_client) = store.values().find(|c| c.user_id == friend.id)
         else { return Ok(()) }; // user is not online
 
         let f_packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(serde_json::to_string(&x).unwrap(), info_code) };
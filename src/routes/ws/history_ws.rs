@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+use axum::extract::State;
+use tracing::error;
+use tokio::sync::mpsc::Sender;
+use crate::generics::{errors::ApiError, structs::{AppState, WSAction, WSPacket}};
+use super::super::message::history;
+
+/// Client interface for paging through a conversation's message history over the websocket. Replies with a [`WSAction::History`] packet.
+/// 
+/// ## Arguments
+/// * [`packet`][`WSPacket`] - The packet carrying the [`WSAction::FetchHistory`] request.
+/// * [`who`][`SocketAddr`] - The address of the client.
+/// * [`State<AppState>`][`State`] - The global app state (client store and storage backend).
+/// * [`tx`][`Sender<WSPacket>`] - Transmitter, so we can send the page back to the client
+/// 
+pub async fn fetch_history(packet: WSPacket, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
+{
+    let user = {
+        let store = state.clients.lock().await;
+        let Some(client) = store.get(&who)
+        else { return Err(ApiError::Unauthorized(String::from("You are not registered with the server."))) };
+
+        if client.session_id != packet.sid || !client.is(&packet.sender)
+        { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
+        client.user_id.clone()
+    };
+
+    let WSAction::FetchHistory(request) = packet.action
+    else { return Err(ApiError::Validation(String::from("Invalid action."))) };
+
+    let page = history::fetch(state.db.as_ref(), &user, &request).await?;
+
+    if tx.send(WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::History(page) }).await.is_err()
+    { error!("Failed to send history to client {who}. Did they abruptly disconnect?") }
+    Ok(())
+}
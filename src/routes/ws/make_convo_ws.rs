@@ -3,46 +3,49 @@ use axum::extract::State;
 use tracing::error;
 use crate::generics::structs::{Account, WSAction};
 use crate::routes::message::make;
-use crate::tokio::sync::mpsc::Sender;
-use crate::generics::{structs::WSPacket, utils};
-use super::generics::structs::ClientStore;
+use tokio::sync::mpsc::Sender;
+use crate::generics::{errors::ApiError, structs::WSPacket, utils};
+use super::generics::structs::AppState;
 use tracing::info;
 
-pub async fn make_convo(packet: WSPacket, who: SocketAddr, State(store): State<ClientStore>, tx: &Sender<WSPacket>)
+pub async fn make_convo(packet: WSPacket, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
 {
 
-    let store = store.lock().await;
+    let store = state.clients.lock().await;
 
     let Some(client) = store.get(&who)
-    else { tx.send(utils::info_packet("You are not registered with the server.")).await.ok(); return; };
+    else { return Err(ApiError::Unauthorized(String::from("You are not registered with the server."))) };
 
-    if client.session_id != packet.sid || client.username != packet.sender
-    { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return; }
+    if client.session_id != packet.sid || !client.is(&packet.sender)
+    { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
 
-    let WSAction::CreateConversation(mut x) = packet.action
-    else { tx.send(utils::info_packet("Invalid action.")).await.ok(); return; };
+    let WSAction::CreateConversation(handles) = packet.action
+    else { return Err(ApiError::Validation(String::from("Invalid action."))) };
 
-    let client: Account = match Account::get_account_by_sid(&client.session_id).await
-    {
-        Ok(Some(client)) => client,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return; }
-        Ok(None) => { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return; }
-    };
+    let Some(client): Option<Account> = state.db.get_account_by_id(&client.user_id).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
 
-    if x.iter().any(|user| !client.friends.contains(user) || user == &client.username)
-    { tx.send(utils::info_packet("You are not friends with all the users you are trying to create a conversation with.")).await.ok(); return; }
+    // members may be named by username or ID, but conversations refer to them by ID
+    let mut x: Vec<String> = Vec::new();
+    for handle in &handles
+    {
+        match state.db.find_account(handle).await?
+        {
+            Some(user) if client.friends.contains(&user.id) && user.id != client.id => x.push(user.id),
+            _ => return Err(ApiError::Validation(String::from("You are not friends with all the users you are trying to create a conversation with.")))
+        }
+    }
 
-    x.push(client.username.clone());
-    let Ok(convo) = make::create_conversation(x.iter().map(|x| x).collect()).await
-    else { tx.send(utils::info_packet("Failed to create conversation.")).await.ok(); return; };
+    x.push(client.id.clone());
+    let convo = make::create_conversation(state.db.as_ref(), x.iter().collect()).await?;
 
 
-    for user in x.iter()
+    // then, live-update the conversation list of every device members are online on, each with only its own keys
+    for client in store.values().filter(|c| x.contains(&c.user_id))
     {
-        // then, see if user is online to live-update their conversation list
-        let Some(client) = store.values().find(|c| &c.username == user)
-        else { continue };
-        let s_packet: WSPacket = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(serde_json::to_string(&convo).unwrap(), 2) };
+        let user = &client.username;
+        let for_device = convo.clone().for_device(&client.user_id, &client.device);
+        let s_packet: WSPacket = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(serde_json::to_string(&for_device).unwrap(), 2) };
         if client.socket.send(s_packet).await.is_ok() 
         { info!("Sent conversation to client {user} from {x}", x = client.username) } 
         else { error!("Failed to send conversation to client {user}. Did they abruptly disconnect?") }
@@ -51,4 +54,5 @@ pub async fn make_convo(packet: WSPacket, who: SocketAddr, State(store): State<C
 
     // tell the client that the message was sent (unnecessary in prod)
     tx.send(utils::info_packet("Conversation created.")).await.ok();
+    Ok(())
 }
@@ -0,0 +1,89 @@
+use std::net::SocketAddr;
+use axum::extract::State;
+use tracing::{error, info};
+use crate::generics::structs::{Account, WSAction};
+use crate::routes::message::make;
+use tokio::sync::mpsc::Sender;
+use crate::generics::{errors::ApiError, structs::WSPacket, utils};
+use super::generics::structs::AppState;
+
+/// Adds members to or removes members from a conversation, through [`WSAction::AddMembers`] and [`WSAction::RemoveMembers`].
+///
+/// Any member may add users they are friends with, and remove anyone, themselves included. Either way the conversation gets a new key
+/// under its next epoch (see [`make::change_members`]): removed members can't read anything sent afterwards, and added members can't read anything sent before.
+///
+/// Online members get the changed conversation with only the keys for their device (arbitrary info 2), and online removed members get its ID (arbitrary info 7).
+///
+/// ## Arguments
+/// * [`packet`][`WSPacket`] - The packet carrying the [`MembershipChange`][`crate::generics::structs::MembershipChange`].
+/// * [`who`][`SocketAddr`] - The address of the client.
+/// * [`State<AppState>`][`State`] - The global app state (client store and storage backend).
+/// * [`tx`][`Sender<WSPacket>`] - Transmitter, so we can relay info back to the client.
+///
+pub async fn change_members(packet: WSPacket, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
+{
+    let store = state.clients.lock().await;
+
+    let Some(client) = store.get(&who)
+    else { return Err(ApiError::Unauthorized(String::from("You are not registered with the server."))) };
+
+    if client.session_id != packet.sid || !client.is(&packet.sender)
+    { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
+
+    let (mut change, adding) = match packet.action
+    {
+        WSAction::AddMembers(change) => (change, true),
+        WSAction::RemoveMembers(change) => (change, false),
+        _ => return Err(ApiError::Validation(String::from("Invalid action.")))
+    };
+    if change.users.is_empty() { return Err(ApiError::Validation(String::from("No users given."))) }
+
+    let Some(account): Option<Account> = state.db.get_account_by_id(&client.user_id).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
+
+    // conversations the user isn't a part of are reported the same as missing ones, so their IDs can't be probed
+    let Some(convo) = state.db.get_conversation(&change.conversation_id).await?.filter(|c| c.users.contains(&account.id))
+    else { return Err(ApiError::NotFound(String::from("No such conversation."))) };
+
+    // users may be named by username or ID; those that can't be found are left as given, and so are neither anyone's friend nor a member
+    for user in change.users.iter_mut()
+    {
+        if let Some(found) = state.db.find_account(user).await? { *user = found.id }
+    }
+
+    let mut users = convo.users.clone();
+    if adding
+    {
+        if change.users.iter().any(|user| !account.friends.contains(user))
+        { return Err(ApiError::Validation(String::from("You are not friends with all the users you are trying to add."))) }
+        if change.users.iter().any(|user| users.contains(user))
+        { return Err(ApiError::Conflict(String::from("Some of those users are already a part of the conversation."))) }
+        for user in &change.users { if !users.contains(user) { users.push(user.clone()) } }
+    }
+    else
+    {
+        if change.users.iter().any(|user| !users.contains(user))
+        { return Err(ApiError::Validation(String::from("Some of those users are not a part of the conversation."))) }
+        users.retain(|user| !change.users.contains(user));
+        if users.is_empty() { return Err(ApiError::Validation(String::from("A conversation must keep at least one member."))) }
+    }
+
+    let convo = make::change_members(state.db.as_ref(), convo, users).await?;
+
+    for client in store.values()
+    {
+        let user = &client.username;
+        let action = match convo.users.contains(&client.user_id)
+        {
+            true => WSAction::ReceiveArbitraryInfo(serde_json::to_string(&convo.clone().for_device(&client.user_id, &client.device)).unwrap(), 2),
+            false if !adding && change.users.contains(&client.user_id) => WSAction::ReceiveArbitraryInfo(convo.id.clone(), 7),
+            false => continue
+        };
+        if client.socket.send(WSPacket { sender: String::from("API"), sid: String::from("0"), action }).await.is_ok()
+        { info!("Sent membership change of conversation {} to client {user}", convo.id) }
+        else { error!("Failed to send membership change to client {user}. Did they abruptly disconnect?") }
+    }
+
+    tx.send(utils::info_packet(match adding { true => "Members added.", false => "Members removed." })).await.ok();
+    Ok(())
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod ws;
 pub mod send_ws;
 pub mod register_ws;
@@ -5,4 +6,6 @@ pub mod recieve_ws;
 pub mod make_convo_ws;
 pub mod remove_friend_ws;
 pub mod add_friend_ws;
+pub mod history_ws;
+pub mod members_ws;
 use super::generics;
\ No newline at end of file
@@ -1,68 +1,89 @@
 use super::{generics::{
-    structs::{ClientStore, WSAction, WSPacket},
+    errors::ApiError,
+    rate_limit::{Key, Limit},
+    structs::{AppState, WSAction, WSPacket},
     utils,
-}, make_convo_ws, send_ws, register_ws, remove_friend_ws, add_friend_ws};
-use crate::tokio::sync::mpsc::Sender;
+}, make_convo_ws, send_ws, register_ws, remove_friend_ws, add_friend_ws, history_ws, members_ws};
+use tokio::sync::mpsc::Sender;
 use axum::extract::State;
 use std::net::SocketAddr;
 
-// Handles incoming websocket packets.
+// Handles incoming websocket packets. Any error a handler returns is sent back to the client as a [`WSAction::Error`] packet.
+//
+// Packets are rate limited per kind of action (see [`Limit::for_action`]), by IP and, once the client has registered, by account.
+// Packets over the limit are answered with a `rate_limited` error packet carrying `retry_after`, and dropped.
 //
 // ## Parameters:
 // * [`socket`][`axum::extract::ws::WebSocket`] - The websocket connection.
 // * [`who`][`std::net::SocketAddr`] - The address of the client.
-// * [`State<ClientStore>`][`axum::extract::State`] - The global client store.
+// * [`State<AppState>`][`axum::extract::State`] - The global app state (client store and storage backend).
 //
-pub async fn recieve_ws(packet: WSPacket, who: SocketAddr, State(store): State<ClientStore>, tx: Sender<WSPacket>) {
-    match packet.action {
+pub async fn recieve_ws(packet: WSPacket, who: SocketAddr, State(state): State<AppState>, tx: Sender<WSPacket>) {
+    let server_only = |name: &str| Err(ApiError::Validation(format!("Server does not accept {name} packets.")));
+
+    if let Some(limit) = Limit::for_action(&packet.action)
+    {
+        let mut keys = vec![Key::Ip(who.ip())];
+        if let Some(client) = state.clients.lock().await.get(&who) { keys.push(Key::Account(client.user_id.clone())) }
+        if let Err(e) = state.limiter.take(limit, &keys, utils::now())
+        {
+            tx.send(utils::error_packet(&e)).await.ok();
+            return;
+        }
+    }
+
+    let result = match packet.action {
         WSAction::Register() => 
         {
-            register_ws::register(&packet, who, State(store.clone()), &tx).await;
-            println!("Client {} registered", &packet.sender);
+            let result = register_ws::register(&packet, who, State(state.clone()), &tx).await;
+            if result.is_ok() { println!("Client {} registered", &packet.sender); }
+            result
         }
         WSAction::Disconnect() => 
         {
-            let mut store = store.lock().await;
+            let mut store = state.clients.lock().await;
             store.remove(&who);
+            Ok(())
         }
         WSAction::SendMessage(d) => 
         {
-            send_ws::send_msg(d, who, State(store.clone()), &tx).await;
+            send_ws::send_msg(d, who, State(state.clone()), &tx).await
+        }
+        WSAction::FetchHistory(_) => 
+        {
+            history_ws::fetch_history(packet, who, State(state.clone()), &tx).await
         }
         WSAction::AddFriend(_) => 
         {
-            add_friend_ws::add_friend(packet, who, State(store.clone()), &tx).await.ok();
+            add_friend_ws::add_friend(packet, who, State(state.clone()), &tx).await
         }
         WSAction::RemoveFriend(_) => 
         {
-            remove_friend_ws::remove_friend(packet, who, State(store.clone()), &tx).await.ok();
+            remove_friend_ws::remove_friend(packet, who, State(state.clone()), &tx).await
         }
         WSAction::CreateConversation(_) => 
         {
-            make_convo_ws::make_convo(packet, who, State(store.clone()), &tx).await;
+            make_convo_ws::make_convo(packet, who, State(state.clone()), &tx).await
         }
-        WSAction::DeleteConversation(_) => 
+        WSAction::AddMembers(_) | WSAction::RemoveMembers(_) => 
         {
-            tx.send(utils::info_packet("Not implemented."))
-                .await
-                .ok();
-        } // planned
-        WSAction::Info(_) => 
+            members_ws::change_members(packet, who, State(state.clone()), &tx).await
+        }
+        WSAction::DeleteConversation(_) => 
         {
-            tx.send(utils::info_packet("Server does not accept info packets."))
-                .await
-                .ok();
+            Err(ApiError::Validation(String::from("Not implemented.")))
         } // planned
-        WSAction::ReceiveMessage(_) => 
-        {
-            tx.send(utils::info_packet("Server does not accept recieve message packets."))
-                .await
-                .ok();
-        }
-        WSAction::ReceiveArbitraryInfo(_,_) => {
-            tx.send(utils::info_packet("Server does not accept arbitrary info packets."))
-                .await
-                .ok();
-        }
+        WSAction::Info(_) => server_only("info"), // planned
+        WSAction::Error(_) => server_only("error"),
+        WSAction::SessionEnded(_) => server_only("session ended"),
+        WSAction::ReceiveMessage(_) => server_only("recieve message"),
+        WSAction::MessageAck(_) => server_only("message acknowledgement"),
+        WSAction::History(_) => server_only("history"),
+        WSAction::ReceiveArbitraryInfo(_,_) => server_only("arbitrary info"),
+    };
+
+    if let Err(e) = result
+    {
+        tx.send(utils::error_packet(&e)).await.ok();
     }
 }
@@ -1,35 +1,40 @@
 use std::net::SocketAddr;
 use axum::extract::State;
-use crate::tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::Sender;
 
-use super::generics::{utils, structs::{ClientStore, WebsocketClient, WSPacket}};
+use super::generics::{errors::ApiError, utils, structs::{AppState, WebsocketClient, WSPacket}};
 
 /// Register a client into the ClientStore, so that they may recieve and send messages through WS.
+/// The packet's sender may be the account's username or its ID.
 /// 
 /// ## Arguments
 /// * [`account`][`ClientAccount`] - The account to register.
-/// * [`store`][`ClientStore`] - Our ClientStore state
+/// * [`state`][`AppState`] - Our app state, holding the ClientStore and the storage backend
 /// * [`tx`][`Sender<WSPacket>`] - Transmitter so we can send messages back to the client
 /// 
-pub async fn register(packet: &WSPacket, who: SocketAddr, State(store): State<ClientStore>, tx: &Sender<WSPacket>)
+pub async fn register(packet: &WSPacket, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
 {
 
-    let mut store = store.lock().await;
+    let mut store = state.clients.lock().await;
     if store.contains_key(&who)
-    {
-        tx.send(utils::info_packet("Client already registered.")).await.ok();
-        return;
-    }
+    { return Err(ApiError::Conflict(String::from("Client already registered."))) }
 
-    match utils::verify(&packet.sender, &packet.sid).await
-    {
-        Ok(true) => (),
-        Ok(false) => { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return; },
-        Err(e) => { tx.send(utils::info_packet(&utils::gen_err(&e))).await.ok(); return; }
-    }
+    let session = utils::authenticate(state.db.as_ref(), &packet.sid).await?;
+    let Some(account) = state.db.get_account_by_id(&session.user_id).await?.filter(|a| a.username == packet.sender || a.id == packet.sender)
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
+    state.db.touch_session(&session.token_hash, utils::now()).await?;
+    let device = account.device_for_session(&session.id).map(|d| d.id.clone()).unwrap_or_default();
 
     // make a new channel
-    store.insert(who, WebsocketClient { username: packet.sender.to_string(), session_id: packet.sid.to_string(), socket: tx.clone() });
+    store.insert(who, WebsocketClient {
+        username: account.username,
+        user_id: account.id,
+        session_id: packet.sid.to_string(),
+        session: session.id,
+        expires: session.expires,
+        device,
+        socket: tx.clone()
+    });
     tx.send(utils::info_packet("Registered")).await.ok();
-    return;
-}
\ No newline at end of file
+    Ok(())
+}
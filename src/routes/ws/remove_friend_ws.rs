@@ -1,73 +1,57 @@
 use std::net::SocketAddr;
 use axum::extract::State;
 use tracing::{error, info};
-use tracing_subscriber::field::debug;
-use super::generics::structs::{Account, WSAction, Conversation};
-use crate::tokio::sync::mpsc::Sender;
-use crate::generics::{structs::WSPacket, utils};
-use super::generics::structs::ClientStore;
+use super::generics::structs::{Account, WSAction};
+use tokio::sync::mpsc::Sender;
+use crate::generics::{errors::ApiError, structs::WSPacket, utils};
+use super::generics::structs::AppState;
 
 
-pub async fn remove_friend(packet: WSPacket, who: SocketAddr, State(store): State<ClientStore>, tx: &Sender<WSPacket>) -> Result<(), ()>
+pub async fn remove_friend(packet: WSPacket, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
 {
     info!("Recieved remove friend request from {who}: {:#?}", packet);
 
-    let store = store.lock().await;
+    let store = state.clients.lock().await;
 
     let Some(client) = store.get(&who)
-    else { tx.send(utils::info_packet("You are not registered with the server.")).await.ok(); return Ok(()); };
+    else { return Err(ApiError::Unauthorized(String::from("You are not registered with the server."))) };
 
-    if client.session_id != packet.sid || client.username != packet.sender
-    { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return Ok(()); }
+    if client.session_id != packet.sid || !client.is(&packet.sender)
+    { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
 
     let WSAction::RemoveFriend(x) = packet.action
-    else { tx.send(utils::info_packet("Invalid action.")).await.ok(); return Ok(()); };
+    else { return Err(ApiError::Validation(String::from("Invalid action."))) };
 
-    let mut client: Account = match Account::get_account_by_sid(&client.session_id).await
-    {
-        Ok(Some(client)) => client,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return Ok(()); }
-        Ok(None) => { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return Ok(()); }
-    };
+    let Some(mut client): Option<Account> = state.db.get_account_by_id(&client.user_id).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
 
-    let mut friend: Account = match Account::get_account(&x).await
-    {
-        Ok(Some(user)) => user,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return Ok(()); }
-        Ok(None) => { tx.send(utils::info_packet("Friend does not exist.")).await.ok(); return Ok(()); }
-    };
+    // the friend may be named by username or ID
+    let Some(mut friend): Option<Account> = state.db.find_account(&x).await?
+    else { return Err(ApiError::NotFound(String::from("Friend does not exist."))) };
 
 
 
-    if !client.friends.iter().any(|user| user == &x)
-    { tx.send(utils::info_packet("You are not friends with this user.")).await.ok(); return Ok(()); }
+    if !client.friends.iter().any(|user| user == &friend.id)
+    { return Err(ApiError::Validation(String::from("You are not friends with this user."))) }
 
 
-    friend.friends.retain(|u| u != &client.username);
-    client.friends.retain(|u| u != &x);
-    match Account::update_account(&friend).await
-    {
-        Ok(_) => (),
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return Ok(()); }
-    }
-    match Account::update_account(&client).await
-    {
-        Ok(_) => (),
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return Ok(()); }
-    }
+    friend.friends.retain(|u| u != &client.id);
+    client.friends.retain(|u| u != &friend.id);
+    state.db.update_account(&friend).await?;
+    state.db.update_account(&client).await?;
 
-    tx.send(utils::info_packet(&format!("Removed {x} from your friends list."))).await.ok();
+    tx.send(utils::info_packet(&format!("Removed {} from your friends list.", friend.username))).await.ok();
 
     // update this client side for all users, beginning with the client
-    let c_packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(x.clone(), 4) };
+    let c_packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(friend.id.clone(), 4) };
     if tx.send(c_packet).await.is_err() 
     { error!("Failed to send conversations to client {who}. Did they abruptly disconnect?") }
 
 
-    let Some(friend_client) = store.values().find(|c| &c.username == &x)
+    let Some(friend_client) = store.values().find(|c| c.user_id == friend.id)
     else { return Ok(()) }; // user is not online
 
-    let f_packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(client.username, 4) };
+    let f_packet = WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::ReceiveArbitraryInfo(client.id, 4) };
     if friend_client.socket.send(f_packet).await.is_err() 
     { error!("Failed to send conversations to client {x}. Did they abruptly disconnect?") }
     Ok(())
@@ -1,63 +1,64 @@
 use std::net::SocketAddr;
 use axum::extract::State;
-use tracing::{debug, error};
+use tracing::error;
 use crate::generics::structs::WSAction;
-use crate::tokio::sync::mpsc::Sender;
-use crate::generics::{structs::{Conversation, EncryptedMessage, WSPacket, Account}, utils};
+use tokio::sync::mpsc::Sender;
+use crate::generics::{errors::ApiError, structs::{EncryptedMessage, WSPacket}};
 use super::super::message::send;
-use super::generics::structs::ClientStore;
+use super::generics::structs::AppState;
 use tracing::info;
 
 /// Client interface for message sending through the websocket.
 /// 
+/// Once the message is stored, the sender gets a [`WSAction::MessageAck`] carrying the stored message (with its ID and sequence number),
+/// and every other online member of the conversation gets a [`WSAction::ReceiveMessage`].
+/// 
 /// ## Arguments
 /// * [`data`][`EncryptedMessage`] - The message to send.
 /// * [`who`][`SocketAddr`] - The address of the client.
-/// * [`State<ClientStore>`][`State`] - The global client store.
+/// * [`State<AppState>`][`State`] - The global app state (client store and storage backend).
 /// * [`tx`][`Sender<WSPacket>`] - Transmitter, so we can relay info back to the sender of this message if needed
 /// 
-pub async fn send_msg(data: EncryptedMessage, who: SocketAddr, State(store): State<ClientStore>, tx: &Sender<WSPacket>)
+pub async fn send_msg(data: EncryptedMessage, who: SocketAddr, State(state): State<AppState>, tx: &Sender<WSPacket>) -> Result<(), ApiError>
 {
 
-    let store = store.lock().await;
+    let store = state.clients.lock().await;
 
     let Some(client) = store.get(&who)
-    else { tx.send(utils::info_packet("You are not registered with the server.")).await.ok(); return; };
+    else { return Err(ApiError::Unauthorized(String::from("You are not registered with the server."))) };
 
-    if client.session_id != data.sender_sid || client.username != data.sender
-    { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return; }
+    if client.session_id != data.sender_sid || !client.is(&data.sender)
+    { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) }
 
-    let account = match Account::get_account_by_sid(&client.session_id).await
-    {
-        Ok(Some(account)) => account,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return; }
-        Ok(None) => { tx.send(utils::info_packet("Invalid session ID.")).await.ok(); return; }
-    };
-        
+    let Some(account) = state.db.get_account_by_id(&client.user_id).await?
+    else { return Err(ApiError::Unauthorized(String::from("Invalid session ID."))) };
 
-    let mut conversation = match Conversation::get_one(&data.dest_convo_id).await
-    {
-        Ok(Some(convo)) => convo,
-        Err(e) => { tx.send(utils::info_packet(&e)).await.ok(); return;}
-        Ok(None) => { tx.send(utils::info_packet("No such conversation.")).await.ok(); return; }
-    };
+    let Some(conversation) = state.db.get_conversation(&data.dest_convo_id).await?
+    else { return Err(ApiError::NotFound(String::from("No such conversation."))) };
 
     // ensure the sender is friends with all users in the conversation
-    if !conversation.users.iter().filter(|x| *x != &account.username).all(|user| account.friends.contains(user))
-    { info!("User is not friends with all users."); tx.send(utils::info_packet("You are not friends with all users in this conversation, so you may not send messages to it.")).await.ok(); return; }
+    if !conversation.users.iter().filter(|x| *x != &account.id).all(|user| account.friends.contains(user))
+    { info!("User is not friends with all users."); return Err(ApiError::Validation(String::from("You are not friends with all users in this conversation, so you may not send messages to it."))) }
 
-    // send message to db
-    if let Err(e) = send::send(data.clone()).await 
-    { tx.send(utils::info_packet(&e)).await.ok(); return; }
+    // the storage backend refuses it too, but this way the client learns why
+    if data.epoch != conversation.epoch
+    { return Err(ApiError::Validation(format!("The conversation's key has changed, the message must be encrypted with the key of epoch {}.", conversation.epoch))) }
 
-    
-    // forward message to all online recipients
-    for user in conversation.users {
-        let Some(client) = store.values().find(|c| c.username == user)
-        else { continue }; // user is not currently logged on
+    // send message to db, under the sender's ID whichever way the client named them
+    let data = EncryptedMessage { sender: client.user_id.clone(), ..data };
+    let message = send::send(state.db.as_ref(), &client.user_id, data).await?;
 
-        if client.socket.send(WSPacket { sender: data.clone().sender, sid: String::from("0"), action: WSAction::ReceiveMessage(data.clone())}).await.is_ok() 
-        { info!("Sent message to client {user} from {x}", x = data.sender) } 
+    // acknowledge the message to the sender, so they learn its ID and position in the conversation
+    if tx.send(WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::MessageAck(message.clone()) }).await.is_err()
+    { error!("Failed to acknowledge message to client {who}. Did they abruptly disconnect?") }
+
+    // forward message to all other online recipients
+    for (_, client) in store.iter().filter(|(addr, c)| **addr != who && conversation.users.contains(&c.user_id))
+    {
+        let user = &client.username;
+        if client.socket.send(WSPacket { sender: message.sender.clone(), sid: String::from("0"), action: WSAction::ReceiveMessage(message.clone())}).await.is_ok() 
+        { info!("Sent message to client {user} from {x}", x = message.sender) } 
         else { error!("Failed to send message to client {user}. Did they abruptly disconnect?") }
     }
+    Ok(())
 }
@@ -6,15 +6,15 @@ use axum::{
     response::IntoResponse,
 };
 use axum_extra::TypedHeader;
-use futures::{future, pin_mut, SinkExt, StreamExt};
-use tokio::{self, sync::mpsc};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
 use std::net::SocketAddr;
 use tracing::{error, info};
-use crate::{generics::{structs::{ClientStore, WSPacket}, utils}, routes::ws::recieve_ws};
+use crate::{generics::{errors::ApiError, structs::{AppState, ClientStore, Session, SessionPolicy, SessionTokens, WebsocketClient, WSAction, WSPacket}, utils}, routes::ws::recieve_ws};
 use axum::extract::connect_info::ConnectInfo;
 
 /// Handles incoming websocket connections.
-pub async fn ws_handler(ws: WebSocketUpgrade, user_agent: Option<TypedHeader<headers::UserAgent>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, State(store): State<ClientStore>) -> impl IntoResponse {
+pub async fn ws_handler(ws: WebSocketUpgrade, user_agent: Option<TypedHeader<headers::UserAgent>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>) -> impl IntoResponse {
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
@@ -22,19 +22,20 @@ pub async fn ws_handler(ws: WebSocketUpgrade, user_agent: Option<TypedHeader<hea
     };
     info!("`{user_agent}` at {addr} connected.");
 
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, State(store)))
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, State(state)))
 }
 
 /// Websocket Statemachine
-async fn handle_socket(socket: WebSocket, who: SocketAddr, State(store): State<ClientStore>) {
+async fn handle_socket(socket: WebSocket, who: SocketAddr, State(state): State<AppState>) {
     let (tx, mut rx) = mpsc::channel::<WSPacket>(100);
 
     let (mut write, mut read) = socket.split();
 
 
     // Ran whenever `rx` recieves a message from `tx` through a `tx.send()` call
-    let send_task = tokio::spawn(async move {
+    let mut send_task = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
+            let ended = matches!(msg.action, WSAction::SessionEnded(_));
             let msg = serde_json::to_string(&msg).unwrap();
             if write
                 .send(Message::Text(msg.clone()))
@@ -44,31 +45,108 @@ async fn handle_socket(socket: WebSocket, who: SocketAddr, State(store): State<C
                 error!("Failed to send message");
                 return;
             }
+            // the session behind this connection is gone, so hang up
+            if ended {
+                write.send(Message::Close(None)).await.ok();
+                return;
+            }
         }
     });
 
     // Ran whenever the client sends messages to the websocket
-    let recv_task = tokio::spawn
-    ({ let store = store.clone(); async move 
+    let mut recv_task = tokio::spawn
+    ({ let state = state.clone(); async move 
         {
             while let Some(Ok(msg)) = read.next().await 
             {
                 let Ok(message) = serde_json::from_str::<WSPacket>(msg.to_text().unwrap())
-                else { tx.send( utils::info_packet("Invalid WSPacket.")).await.ok(); continue; };
+                else { tx.send(utils::error_packet(&ApiError::Validation(String::from("Invalid WSPacket.")))).await.ok(); continue; };
                 info!("Recieved message from {who}: {:#?}", message);
-                recieve_ws::recieve_ws(message, who, State(store.clone()), tx.clone()).await;
+                recieve_ws::recieve_ws(message, who, State(state.clone()), tx.clone()).await;
             }
         }
     });
 
-    pin_mut!(send_task, recv_task);
-    future::select(send_task, recv_task).await;
+    // whichever side finishes first takes the other down with it
+    tokio::select! {
+        _ = &mut send_task => recv_task.abort(),
+        _ = &mut recv_task => send_task.abort(),
+    }
 
     // returning from the handler closes the websocket connection
     println!("Websocket context {who} destroyed");
 
     // remove the client from the store. This does the same thing as a Disconnect() packet, but is here in case the client disconnects without sending a Disconnect() packet.
-    let mut store = store.lock().await;
+    let mut store = state.clients.lock().await;
     store.remove(&who);
     
 }
+
+/// Closes every websocket connection authenticated with one of `sessions`, after telling the client why with a [`WSAction::SessionEnded`] packet.
+/// Used whenever sessions are revoked, so a revoked session can't keep using a connection it opened earlier.
+pub async fn end_sessions(clients: &ClientStore, sessions: &[Session], reason: &str)
+{
+    end_clients(clients, |c| sessions.iter().any(|s| s.id == c.session), reason).await;
+}
+
+/// Closes every websocket connection of an account, after telling the client why with a [`WSAction::SessionEnded`] packet.
+pub async fn end_user(clients: &ClientStore, user_id: &str, reason: &str)
+{
+    end_clients(clients, |c| c.user_id == user_id, reason).await;
+}
+
+/// Moves the websocket connections of the account with the ID `user_id`, which was just renamed, over to its new username.
+pub async fn rename_user(clients: &ClientStore, user_id: &str, new: &str)
+{
+    for client in clients.lock().await.values_mut().filter(|c| c.user_id == user_id)
+    {
+        client.username = new.to_string();
+    }
+}
+
+/// Moves websocket connections made with a session over to the access token it was just refreshed to, so they stay open past the old token's expiry.
+pub async fn refresh_sessions(clients: &ClientStore, rotated: &Session, tokens: &SessionTokens)
+{
+    for client in clients.lock().await.values_mut().filter(|c| c.session == rotated.id)
+    {
+        client.session_id = tokens.token.clone();
+        client.expires = tokens.expires;
+    }
+}
+
+/// Runs forever, every [`SessionPolicy::sweep_interval`]: deletes sessions that have lapsed, and closes websocket connections whose session lapsed
+/// or whose access token expired without being refreshed.
+pub async fn sweep_sessions(state: AppState)
+{
+    let policy = SessionPolicy::current();
+    let mut interval = tokio::time::interval(policy.sweep_interval);
+    loop
+    {
+        interval.tick().await;
+        let now = utils::now();
+        match state.db.delete_lapsed_sessions(now - policy.idle_timeout, now - policy.max_age).await
+        {
+            Ok(lapsed) => end_sessions(&state.clients, &lapsed, "Your session expired.").await,
+            Err(e) => error!("Failed to clean up lapsed sessions: {e}")
+        }
+        end_clients(&state.clients, |c| c.expires <= now, "Your access token expired.").await;
+    }
+}
+
+/// Closes every websocket connection matching `ended`, after telling the client why with a [`WSAction::SessionEnded`] packet.
+async fn end_clients(clients: &ClientStore, ended: impl Fn(&WebsocketClient) -> bool, reason: &str)
+{
+    let mut store = clients.lock().await;
+    let ended: Vec<SocketAddr> = store
+        .iter()
+        .filter(|(_, c)| ended(c))
+        .map(|(addr, _)| *addr)
+        .collect();
+
+    for addr in ended
+    {
+        let Some(client) = store.remove(&addr) else { continue };
+        info!("Closing websocket connection {addr} of {}: {reason}", client.username);
+        client.socket.send(WSPacket { sender: String::from("API"), sid: String::from("0"), action: WSAction::SessionEnded(reason.to_string()) }).await.ok();
+    }
+}